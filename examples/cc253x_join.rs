@@ -1,7 +1,7 @@
-use futures::executor::block_on;
+use futures::{executor::block_on, FutureExt, StreamExt};
 use log::info;
 use rusty_zigbee_dongle::{
-    coordinator::{Coordinator, CoordinatorError, ZigbeeEvent},
+    coordinator::{Coordinator, CoordinatorError, PermitJoin, ZigbeeEvent},
     utils::sleep::sleep_forever,
     zstack::cc253x::CC253X,
 };
@@ -13,39 +13,58 @@ fn main() {
         .init();
 
     let f = async {
-        let mut cc2531 = CC253X::from_simple_serial("/dev/ttyACM2", 115_200)
+        let cc2531 = CC253X::from_simple_serial("/dev/ttyACM2", 115_200)
             .await
             .unwrap();
-        cc2531
-            .set_on_event(Box::new(|event| {
-                match event {
-                    ZigbeeEvent::DeviceJoined {
-                        network_address,
-                        ieee_address,
-                    } => {
-                        info!("Device joined: {:?} {:?}", network_address, ieee_address);
-                    }
-                    ZigbeeEvent::DeviceAnnounce {
-                        network_address,
-                        ieee_address,
-                    } => {
-                        info!("Device announce: {:?} {:?}", network_address, ieee_address);
-                    }
-                    ZigbeeEvent::NetworkAddress {
-                        network_address,
-                        ieee_address,
-                    } => {
-                        info!("Network address: {:?} {:?}", network_address, ieee_address);
-                    }
-                    ZigbeeEvent::DeviceLeave(d) => {
-                        info!("Device leave: {:?}", d);
-                    }
+        let events = cc2531.subscribe_events().for_each(|event| async move {
+            match event {
+                ZigbeeEvent::DeviceJoined {
+                    network_address,
+                    ieee_address,
+                } => {
+                    info!("Device joined: {:?} {:?}", network_address, ieee_address);
                 }
-                #[allow(unreachable_code)]
-                Ok(())
-            }))
-            .await
-            .unwrap();
+                ZigbeeEvent::DeviceAnnounce {
+                    network_address,
+                    ieee_address,
+                } => {
+                    info!("Device announce: {:?} {:?}", network_address, ieee_address);
+                }
+                ZigbeeEvent::NetworkAddress {
+                    network_address,
+                    ieee_address,
+                } => {
+                    info!("Network address: {:?} {:?}", network_address, ieee_address);
+                }
+                ZigbeeEvent::DeviceRejected {
+                    network_address,
+                    ieee_address,
+                } => {
+                    info!("Device rejected: {:?} {:?}", network_address, ieee_address);
+                }
+                ZigbeeEvent::DeviceLeave(d) => {
+                    info!("Device leave: {:?}", d);
+                }
+                ZigbeeEvent::IncomingMessage(m) => {
+                    info!("Incoming message: {:?}", m);
+                }
+                ZigbeeEvent::NetworkState(state) => {
+                    info!("Network state: {:?}", state);
+                }
+                ZigbeeEvent::PermitJoin(permit) => {
+                    info!("Permit join: {:?}", permit);
+                }
+                ZigbeeEvent::SourceRoute {
+                    network_address,
+                    relays,
+                } => {
+                    info!("Source route: {:?} {:?}", network_address, relays);
+                }
+                ZigbeeEvent::Lagged(missed) => {
+                    info!("Missed {} events", missed);
+                }
+            }
+        });
 
         let b = async {
             info!("starting...");
@@ -58,14 +77,14 @@ fn main() {
             let device_info = cc2531.device_info().await.unwrap();
             info!("device_info: {:?}", device_info);
             cc2531
-                .permit_join(std::time::Duration::from_secs(100), None)
+                .permit_join(PermitJoin::For(std::time::Duration::from_secs(600)), None)
                 .await
                 .unwrap();
             info!("sleeping forever");
             sleep_forever().await.unwrap();
             Ok::<(), CoordinatorError>(())
         };
-        futures::try_join!(b)
+        futures::try_join!(b, events.map(Ok))
     };
 
     block_on(f).unwrap();
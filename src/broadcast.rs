@@ -0,0 +1,168 @@
+use futures::Stream;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+};
+
+/// Sends every value to all the receivers subscribed at that time. Sending never blocks: a
+/// receiver that falls behind loses its oldest values and is told how many with [`Lagged`].
+pub struct Broadcast<T> {
+    receivers: Mutex<Vec<Weak<Mutex<Queue<T>>>>>,
+}
+
+/// Values a receiver missed because its buffer was full
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+struct Queue<T> {
+    values: VecDeque<T>,
+    capacity: usize,
+    missed: u64,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// Stream of the values sent after [`Broadcast::subscribe`], ends once the broadcast is dropped
+pub struct BroadcastReceiver<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+}
+
+impl<T: Clone> Broadcast<T> {
+    pub fn new() -> Self {
+        Self {
+            receivers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Adds a receiver that buffers up to `capacity` values
+    pub fn subscribe(&self, capacity: usize) -> BroadcastReceiver<T> {
+        let queue = Arc::new(Mutex::new(Queue {
+            values: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            missed: 0,
+            closed: false,
+            waker: None,
+        }));
+        self.receivers.lock().unwrap().push(Arc::downgrade(&queue));
+        BroadcastReceiver { queue }
+    }
+
+    /// Number of receivers still listening
+    pub fn receiver_count(&self) -> usize {
+        let mut receivers = self.receivers.lock().unwrap();
+        receivers.retain(|receiver| receiver.strong_count() > 0);
+        receivers.len()
+    }
+
+    pub fn send(&self, value: T) {
+        let mut receivers = self.receivers.lock().unwrap();
+        // Dropped receivers are forgotten on the way
+        receivers.retain(|receiver| {
+            let Some(queue) = receiver.upgrade() else {
+                return false;
+            };
+            let mut queue = queue.lock().unwrap();
+            if queue.values.len() == queue.capacity {
+                queue.values.pop_front();
+                queue.missed += 1;
+            }
+            queue.values.push_back(value.clone());
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+            true
+        });
+    }
+}
+
+impl<T: Clone> Default for Broadcast<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Broadcast<T> {
+    fn drop(&mut self) {
+        for receiver in self.receivers.lock().unwrap().iter() {
+            if let Some(queue) = receiver.upgrade() {
+                let mut queue = queue.lock().unwrap();
+                queue.closed = true;
+                if let Some(waker) = queue.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+impl<T> Stream for BroadcastReceiver<T> {
+    type Item = Result<T, Lagged>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut queue = self.queue.lock().unwrap();
+        // The loss is reported before the values that survived it
+        if queue.missed > 0 {
+            let missed = std::mem::take(&mut queue.missed);
+            return Poll::Ready(Some(Err(Lagged(missed))));
+        }
+        if let Some(value) = queue.values.pop_front() {
+            return Poll::Ready(Some(Ok(value)));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, StreamExt};
+
+    #[test]
+    fn test_every_receiver_gets_every_value() {
+        let broadcast = Broadcast::new();
+        let first = broadcast.subscribe(4);
+        let second = broadcast.subscribe(4);
+        broadcast.send(1);
+        broadcast.send(2);
+        drop(broadcast);
+        let first: Vec<_> = block_on(first.collect());
+        let second: Vec<_> = block_on(second.collect());
+        assert_eq!(first, vec![Ok(1), Ok(2)]);
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn test_slow_receiver_lags() {
+        let broadcast = Broadcast::new();
+        let slow = broadcast.subscribe(2);
+        let fast = broadcast.subscribe(8);
+        for value in 0..5 {
+            broadcast.send(value);
+        }
+        assert_eq!(broadcast.receiver_count(), 2);
+        drop(fast);
+        assert_eq!(broadcast.receiver_count(), 1);
+        drop(broadcast);
+        let values: Vec<_> = block_on(slow.collect());
+        assert_eq!(values, vec![Err(Lagged(3)), Ok(3), Ok(4)]);
+    }
+
+    #[test]
+    fn test_receiver_wakes_up_on_send() {
+        let broadcast = Arc::new(Broadcast::new());
+        let mut receiver = broadcast.subscribe(1);
+        let sender = broadcast.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            sender.send("joined");
+        });
+        assert_eq!(block_on(receiver.next()), Some(Ok("joined")));
+        thread.join().unwrap();
+    }
+}
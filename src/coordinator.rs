@@ -1,19 +1,31 @@
+#[cfg(feature = "psila")]
+use crate::zstack::unpi::subsystems::zdo::NetworkAddressRspRequest;
 use crate::{
     serial::SerialThreadError,
     utils::map::MapError,
     zstack::{
         nv_memory::nv_item::NvMemoryAdapterError,
         unpi::{
-            constants::{CommandStatus, NoCommandStatusError},
+            constants::{
+                zdo::{AddressRequestType, DeviceState},
+                CommandStatus, NoCommandStatusError,
+            },
             serial::UnpiCommandError,
-            subsystems::{sys::VersionResponse, util::GetDeviceInfoResponse},
+            subsystems::{
+                af::IncomingMessage, sys::VersionResponse, util::GetDeviceInfoResponse,
+                zdo::IeeeAddressRspRequest,
+            },
         },
     },
 };
 use deku::{reader::Reader, writer::Writer, DekuError, DekuReader, DekuWriter};
+#[cfg(feature = "psila")]
+use ieee802154::mac::ExtendedAddress;
 use std::{
+    collections::HashSet,
     future::Future,
     io::{Read, Seek, Write},
+    ops::RangeInclusive,
 };
 
 pub type OnEvent = Box<dyn Fn(ZigbeeEvent) -> Result<(), CoordinatorError> + Send + Sync>;
@@ -26,9 +38,11 @@ pub trait Coordinator {
     fn start(&self) -> impl Future<Output = Result<(), CoordinatorError>>;
     fn stop(&self) -> impl Future<Output = Result<(), CoordinatorError>>;
     fn version(&self) -> impl Future<Output = Result<VersionResponse, CoordinatorError>>;
+    /// Opens or closes joining on the whole network, or only through the router at `address`.
+    /// A new call replaces the window opened by the previous one.
     fn permit_join(
         &self,
-        duration: std::time::Duration,
+        permit: PermitJoin,
         address: Option<u16>,
     ) -> impl Future<Output = Result<(), CoordinatorError>>;
     fn is_inter_pan_mode(&self) -> impl Future<Output = bool>;
@@ -41,7 +55,23 @@ pub trait Coordinator {
     ) -> impl Future<Output = Result<(), CoordinatorError>>;
     fn change_channel(&self, channel: u8) -> impl Future<Output = Result<(), CoordinatorError>>;
     fn set_transmit_power(&self, power: i8) -> impl Future<Output = Result<(), CoordinatorError>>;
-    fn request_network_address(addr: &str) -> impl Future<Output = Result<(), CoordinatorError>>;
+    /// Looks up the network address of a device, and with [`AddressRequestType::Extended`]
+    /// the devices that joined through it from `start_index` on
+    #[cfg(feature = "psila")]
+    fn request_network_address(
+        &self,
+        ieee_address: ExtendedAddress,
+        request_type: AddressRequestType,
+        start_index: u8,
+    ) -> impl Future<Output = Result<NetworkAddressRspRequest, CoordinatorError>>;
+    /// Asks a device for its IEEE address, and with [`AddressRequestType::Extended`] the
+    /// devices that joined through it from `start_index` on
+    fn request_ieee_address(
+        &self,
+        network_address: u16,
+        request_type: AddressRequestType,
+        start_index: u8,
+    ) -> impl Future<Output = Result<IeeeAddressRspRequest, CoordinatorError>>;
     #[allow(clippy::too_many_arguments)]
     fn send_zcl_frame(
         &self,
@@ -81,7 +111,87 @@ pub struct DeviceInfo {
     pub assoc_devices_list: [u16; 16],
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Channels of the 2.4 GHz band a Zigbee network can use
+pub const ZIGBEE_CHANNELS: RangeInclusive<u8> = 11..=26;
+
+/// Parameters of the network the coordinator forms
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkOptions {
+    pub pan_id: u16,
+    /// Least significant byte first, as the stack stores it
+    pub extended_pan_id: [u8; 8],
+    /// Channels the coordinator may form the network on, from 11 to 26
+    pub channel_list: Vec<u8>,
+    pub network_key: [u8; 16],
+    /// Whether the network key is sent in the clear to joining devices
+    pub network_key_distribute: bool,
+}
+
+impl NetworkOptions {
+    /// Channels outside [`ZIGBEE_CHANNELS`] are left out
+    pub fn channel_mask(&self) -> u32 {
+        self.channel_list
+            .iter()
+            .filter(|channel| ZIGBEE_CHANNELS.contains(channel))
+            .fold(0, |mask, channel| mask | (1 << channel))
+    }
+}
+
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        NetworkOptions {
+            pan_id: 0x1a62,
+            extended_pan_id: [0xdd; 8],
+            channel_list: vec![11],
+            network_key: [1, 3, 5, 7, 9, 11, 13, 15, 0, 2, 4, 6, 8, 10, 12, 13],
+            network_key_distribute: false,
+        }
+    }
+}
+
+/// What a device asked to leave the network does next
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct RemoveOptions {
+    /// Joins again right away, to move it to another parent or refresh its keys
+    pub rejoin: bool,
+    /// Makes the children of a router leave with it
+    pub remove_children: bool,
+}
+
+/// How long the network accepts new devices
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PermitJoin {
+    Close,
+    /// Stays open that long, however long it is. Windows the stack can't open in one go are
+    /// extended before they run out.
+    For(std::time::Duration),
+    /// Stays open until closed
+    Forever,
+}
+
+/// Which devices the trust center lets stay on the network. A refused device is asked to leave
+/// as soon as it joins. IEEE addresses are least significant byte first.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum JoinPolicy {
+    #[default]
+    AllowAll,
+    /// Only these devices may join
+    Allowlist(HashSet<[u8; 8]>),
+    /// Every device but these may join
+    Denylist(HashSet<[u8; 8]>),
+}
+
+impl JoinPolicy {
+    pub fn allows(&self, ieee_address: &[u8; 8]) -> bool {
+        match self {
+            JoinPolicy::AllowAll => true,
+            JoinPolicy::Allowlist(allowed) => allowed.contains(ieee_address),
+            JoinPolicy::Denylist(denied) => !denied.contains(ieee_address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 #[allow(clippy::type_complexity)]
 pub enum ZigbeeEvent {
     DeviceJoined {
@@ -97,14 +207,33 @@ pub enum ZigbeeEvent {
         ieee_address: [u8; 8],
     },
     DeviceLeave(Either<(Option<u16>, [u8; 8]), (u16, Option<[u8; 8]>)>),
+    IncomingMessage(IncomingMessage),
+    /// The coordinator moved to another network state
+    NetworkState(DeviceState),
+    /// Joining was opened, extended with the time it has left, or closed, either on request or
+    /// because it ran out. Once [`Coordinator::permit_join`] was called it's the only source.
+    PermitJoin(PermitJoin),
+    /// Relays the frames of a device went through to reach the coordinator
+    SourceRoute {
+        network_address: u16,
+        relays: Vec<u16>,
+    },
+    /// A device the join policy refuses joined and was asked to leave
+    DeviceRejected {
+        network_address: u16,
+        ieee_address: [u8; 8],
+    },
+    /// Events an event stream dropped because its consumer was too slow
+    Lagged(u64),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Either<A, B> {
     Left(A),
     Right(B),
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum AddressMode {
     AddrNotPresent = 0,
     AddrGroup = 1,
@@ -113,6 +242,108 @@ pub enum AddressMode {
     AddrBroadcast = 15,
 }
 
+impl AddressMode {
+    pub fn from_mode(mode: u8) -> Option<Self> {
+        match mode {
+            0 => Some(AddressMode::AddrNotPresent),
+            1 => Some(AddressMode::AddrGroup),
+            2 => Some(AddressMode::Addr16bit),
+            3 => Some(AddressMode::Addr64bit),
+            15 => Some(AddressMode::AddrBroadcast),
+            _ => None,
+        }
+    }
+}
+
+/// Binding of a cluster of a device endpoint: what the cluster emits is sent to the destination
+#[derive(Debug, Clone, PartialEq)]
+pub struct BindingEntry {
+    /// IEEE address of the device holding the binding, least significant byte first
+    pub source_address: [u8; 8],
+    pub source_endpoint: u8,
+    pub cluster_id: u16,
+    pub destination: BindingDestination,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindingDestination {
+    Device { ieee_address: [u8; 8], endpoint: u8 },
+    Group(u16),
+}
+
+impl BindingDestination {
+    pub fn address_mode(&self) -> AddressMode {
+        match self {
+            BindingDestination::Device { .. } => AddressMode::Addr64bit,
+            BindingDestination::Group(_) => AddressMode::AddrGroup,
+        }
+    }
+}
+
+// As listed by ZDO_MGMT_BIND_RSP: a group destination is only its two bytes, with no endpoint
+impl DekuWriter<()> for BindingEntry {
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        _ctx: (),
+    ) -> Result<(), DekuError> {
+        writer.write_bytes(&self.source_address)?;
+        writer.write_bytes(&[self.source_endpoint])?;
+        writer.write_bytes(&self.cluster_id.to_le_bytes())?;
+        writer.write_bytes(&[self.destination.address_mode() as u8])?;
+        match &self.destination {
+            BindingDestination::Device {
+                ieee_address,
+                endpoint,
+            } => {
+                writer.write_bytes(ieee_address)?;
+                writer.write_bytes(&[*endpoint])?;
+            }
+            BindingDestination::Group(group) => writer.write_bytes(&group.to_le_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+impl<'a> DekuReader<'a, ()> for BindingEntry {
+    fn from_reader_with_ctx<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        _ctx: (),
+    ) -> Result<Self, DekuError>
+    where
+        Self: Sized,
+    {
+        let mut source_address = [0u8; 8];
+        reader.read_bytes(8, &mut source_address)?;
+        let mut source = [0u8; 4];
+        reader.read_bytes(4, &mut source)?;
+        let destination = match AddressMode::from_mode(source[3]) {
+            Some(AddressMode::Addr64bit) => {
+                let mut ieee_address = [0u8; 8];
+                reader.read_bytes(8, &mut ieee_address)?;
+                let mut endpoint = [0u8; 1];
+                reader.read_bytes(1, &mut endpoint)?;
+                BindingDestination::Device {
+                    ieee_address,
+                    endpoint: endpoint[0],
+                }
+            }
+            Some(AddressMode::AddrGroup) => {
+                let mut group = [0u8; 2];
+                reader.read_bytes(2, &mut group)?;
+                BindingDestination::Group(u16::from_le_bytes(group))
+            }
+            _ => return Err(DekuError::Parse("Invalid binding address mode".into())),
+        };
+        Ok(BindingEntry {
+            source_address,
+            source_endpoint: source[0],
+            cluster_id: u16::from_le_bytes([source[1], source[2]]),
+            destination,
+        })
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum LedStatus {
     Disable,
@@ -187,6 +418,21 @@ pub enum CoordinatorError {
     CommandStatusFailure(CommandStatus),
     NoCommandStatus(NoCommandStatusError),
     Deku(deku::DekuError),
+    Timeout,
+    NetworkNotStarted,
+    Zcl(String),
+    InvalidEndpoint,
+    NetworkFormationFailed,
+    Backup(String),
+    UnsupportedFirmware,
+    DeviceStore(String),
+    UnknownDevice,
+    /// A device answered a ZDO request with this status
+    ZdoStatus(u8),
+    /// A device answered a ZCL command with this status
+    ZclStatus(u8),
+    /// An install code isn't 16 bytes followed by its CRC, or the CRC doesn't match
+    InvalidInstallCode,
 }
 
 impl From<std::io::Error> for CoordinatorError {
@@ -203,13 +449,26 @@ impl From<MapError> for CoordinatorError {
 
 impl From<NvMemoryAdapterError> for CoordinatorError {
     fn from(e: NvMemoryAdapterError) -> Self {
-        CoordinatorError::NvMemoryAdapter(e)
+        match e {
+            NvMemoryAdapterError::UnpiCommand(UnpiCommandError::Timeout) => {
+                CoordinatorError::Timeout
+            }
+            NvMemoryAdapterError::CommandStatus(status) => {
+                CoordinatorError::CommandStatusFailure(status)
+            }
+            NvMemoryAdapterError::Deku(e) => CoordinatorError::Deku(e),
+            NvMemoryAdapterError::UnsupportedFirmware => CoordinatorError::UnsupportedFirmware,
+            e => CoordinatorError::NvMemoryAdapter(e),
+        }
     }
 }
 
 impl From<UnpiCommandError> for CoordinatorError {
     fn from(e: UnpiCommandError) -> Self {
-        CoordinatorError::UnpiCommand(e)
+        match e {
+            UnpiCommandError::Timeout => CoordinatorError::Timeout,
+            e => CoordinatorError::UnpiCommand(e),
+        }
     }
 }
 
@@ -224,3 +483,66 @@ impl From<DekuError> for CoordinatorError {
         CoordinatorError::Deku(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zstack::unpi::commands::CountedList;
+    use deku::no_std_io::Cursor;
+
+    #[test]
+    fn test_join_policy() {
+        let listed = [1, 2, 3, 4, 5, 6, 7, 8];
+        let other = [8, 7, 6, 5, 4, 3, 2, 1];
+        assert!(JoinPolicy::default().allows(&listed));
+        let allowlist = JoinPolicy::Allowlist(HashSet::from([listed]));
+        assert!(allowlist.allows(&listed));
+        assert!(!allowlist.allows(&other));
+        let denylist = JoinPolicy::Denylist(HashSet::from([listed]));
+        assert!(!denylist.allows(&listed));
+        assert!(denylist.allows(&other));
+    }
+
+    #[test]
+    fn test_binding_entries() {
+        let entries = vec![
+            BindingEntry {
+                source_address: [1, 2, 3, 4, 5, 6, 7, 8],
+                source_endpoint: 1,
+                cluster_id: 0x0006,
+                destination: BindingDestination::Device {
+                    ieee_address: [9, 10, 11, 12, 13, 14, 15, 16],
+                    endpoint: 2,
+                },
+            },
+            BindingEntry {
+                source_address: [1, 2, 3, 4, 5, 6, 7, 8],
+                source_endpoint: 1,
+                cluster_id: 0x0008,
+                destination: BindingDestination::Group(0x1234),
+            },
+        ];
+        let list: CountedList<u8, BindingEntry> = entries.clone().into();
+        let mut bytes = Vec::new();
+        list.to_writer(&mut Writer::new(&mut Cursor::new(&mut bytes)), ())
+            .unwrap();
+        assert_eq!(
+            bytes,
+            [
+                2, 1, 2, 3, 4, 5, 6, 7, 8, 1, 0x06, 0x00, 3, 9, 10, 11, 12, 13, 14, 15, 16, 2, 1,
+                2, 3, 4, 5, 6, 7, 8, 1, 0x08, 0x00, 1, 0x34, 0x12
+            ]
+        );
+        let mut cursor = Cursor::new(&bytes);
+        let read = CountedList::<u8, BindingEntry>::from_reader_with_ctx(
+            &mut Reader::new(&mut cursor),
+            (),
+        )
+        .unwrap();
+        assert_eq!(read.items, entries);
+
+        bytes[12] = 2;
+        let mut cursor = Cursor::new(&bytes[1..]);
+        assert!(BindingEntry::from_reader_with_ctx(&mut Reader::new(&mut cursor), ()).is_err());
+    }
+}
@@ -0,0 +1,456 @@
+use crate::{
+    coordinator::{CoordinatorError, Either, ZigbeeEvent},
+    utils::{hex, warn},
+    zstack::unpi::constants::zdo::LogicalType,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Node that joined the network, as far as the coordinator knows it
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Device {
+    /// Least significant byte first, like in [`ZigbeeEvent`]. The JSON has it the other way
+    /// around.
+    #[serde(with = "hex::eui64")]
+    pub ieee_address: [u8; 8],
+    #[serde(with = "hex::u16")]
+    pub network_address: u16,
+    /// Empty until the device is interviewed
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manufacturer_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_id: Option<String>,
+    /// Seconds since the UNIX epoch
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+}
+
+/// Simple descriptor of an application endpoint
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Endpoint {
+    pub id: u8,
+    pub profile_id: u16,
+    pub device_id: u16,
+    pub input_clusters: Vec<u16>,
+    pub output_clusters: Vec<u16>,
+}
+
+/// What a device told about itself when interviewed
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeviceDescription {
+    pub network_address: u16,
+    pub logical_type: LogicalType,
+    pub manufacturer_code: u16,
+    pub power_source: PowerSource,
+    pub endpoints: Vec<Endpoint>,
+    /// From the Basic cluster, when the device has one and answered
+    pub manufacturer_name: Option<String>,
+    pub model_id: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PowerSource {
+    Mains,
+    Battery,
+}
+
+/// Where the registry keeps its devices between runs
+pub trait DeviceStore: Send {
+    fn load(&self) -> Result<Vec<Device>, CoordinatorError>;
+    fn save(&self, devices: &[Device]) -> Result<(), CoordinatorError>;
+    /// Waits until what was saved so far is stored, for stores that save in the background
+    fn flush(&self) -> Result<(), CoordinatorError> {
+        Ok(())
+    }
+}
+
+/// Keeps nothing, devices are forgotten on exit
+#[derive(Debug, Default)]
+pub struct MemoryStore;
+
+impl DeviceStore for MemoryStore {
+    fn load(&self) -> Result<Vec<Device>, CoordinatorError> {
+        Ok(Vec::new())
+    }
+
+    fn save(&self, _devices: &[Device]) -> Result<(), CoordinatorError> {
+        Ok(())
+    }
+}
+
+/// Devices as a JSON array in a file, which doesn't need to exist yet.
+///
+/// The file is written from a thread of its own, as saves come from the transport's read thread.
+/// Saves arriving faster than the file is written only leave the latest devices behind.
+#[derive(Debug)]
+pub struct JsonFileStore {
+    path: PathBuf,
+    writer: mpsc::Sender<StoreWrite>,
+}
+
+// What the writer thread of a `JsonFileStore` is asked for
+#[derive(Debug)]
+enum StoreWrite {
+    Save(String),
+    // Answered once what was saved before is written, with the latest failure if any
+    Flush(mpsc::Sender<Result<(), String>>),
+}
+
+impl JsonFileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let (writer, writes) = mpsc::channel();
+        let file = path.clone();
+        std::thread::spawn(move || write_devices(&file, writes));
+        Self { path, writer }
+    }
+}
+
+impl Drop for JsonFileStore {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl DeviceStore for JsonFileStore {
+    fn load(&self) -> Result<Vec<Device>, CoordinatorError> {
+        let json = match std::fs::read_to_string(&self.path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&json).map_err(|e| CoordinatorError::DeviceStore(e.to_string()))
+    }
+
+    fn save(&self, devices: &[Device]) -> Result<(), CoordinatorError> {
+        let json = serde_json::to_string_pretty(devices)
+            .map_err(|e| CoordinatorError::DeviceStore(e.to_string()))?;
+        self.writer
+            .send(StoreWrite::Save(json))
+            .map_err(|_| CoordinatorError::DeviceStore("the writer thread is gone".into()))
+    }
+
+    fn flush(&self) -> Result<(), CoordinatorError> {
+        let (done, written) = mpsc::channel();
+        self.writer
+            .send(StoreWrite::Flush(done))
+            .map_err(|_| CoordinatorError::DeviceStore("the writer thread is gone".into()))?;
+        match written.recv() {
+            Ok(result) => result.map_err(CoordinatorError::DeviceStore),
+            Err(_) => Err(CoordinatorError::DeviceStore(
+                "the writer thread is gone".into(),
+            )),
+        }
+    }
+}
+
+// Writes the latest devices saved until every `JsonFileStore` handle is dropped
+fn write_devices(path: &Path, writes: mpsc::Receiver<StoreWrite>) {
+    let mut failure = None;
+    while let Ok(write) = writes.recv() {
+        let mut latest = None;
+        let mut flushes = Vec::new();
+        for write in std::iter::once(write).chain(writes.try_iter()) {
+            match write {
+                StoreWrite::Save(json) => latest = Some(json),
+                StoreWrite::Flush(done) => flushes.push(done),
+            }
+        }
+        if let Some(json) = latest {
+            // Written aside first, so a crash never leaves half a file behind
+            let temporary = path.with_extension("tmp");
+            let written =
+                std::fs::write(&temporary, json).and_then(|_| std::fs::rename(&temporary, path));
+            failure = written.err().map(|e| {
+                warn!("could not save the devices to {:?}: {}", path, e);
+                e.to_string()
+            });
+        }
+        for done in flushes {
+            let _ = done.send(failure.clone().map_or(Ok(()), Err));
+        }
+    }
+}
+
+/// Devices of the network by IEEE address, kept up to date from the coordinator events.
+///
+/// Every change that matters is saved to the store right away. Only `last_seen` is not, as it
+/// changes with every message, it goes out with the next save or [`Self::flush`].
+pub struct DeviceRegistry {
+    devices: Mutex<HashMap<[u8; 8], Device>>,
+    store: Mutex<Box<dyn DeviceStore>>,
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        Self {
+            devices: Mutex::new(HashMap::new()),
+            store: Mutex::new(Box::new(MemoryStore)),
+        }
+    }
+}
+
+impl DeviceRegistry {
+    pub fn new(store: impl DeviceStore + 'static) -> Result<Self, CoordinatorError> {
+        let registry = Self::default();
+        registry.open(store)?;
+        Ok(registry)
+    }
+
+    /// Replaces the store and the devices with the ones it holds
+    pub fn open(&self, store: impl DeviceStore + 'static) -> Result<(), CoordinatorError> {
+        let devices = store
+            .load()?
+            .into_iter()
+            .map(|device| (device.ieee_address, device))
+            .collect();
+        *self.devices.lock().unwrap() = devices;
+        *self.store.lock().unwrap() = Box::new(store);
+        Ok(())
+    }
+
+    pub fn devices(&self) -> Vec<Device> {
+        let mut devices: Vec<_> = self.devices.lock().unwrap().values().cloned().collect();
+        devices.sort_by_key(|device| device.ieee_address);
+        devices
+    }
+
+    pub fn by_ieee_address(&self, ieee_address: &[u8; 8]) -> Option<Device> {
+        self.devices.lock().unwrap().get(ieee_address).cloned()
+    }
+
+    pub fn by_network_address(&self, network_address: u16) -> Option<Device> {
+        self.devices
+            .lock()
+            .unwrap()
+            .values()
+            .find(|device| device.network_address == network_address)
+            .cloned()
+    }
+
+    /// Records where a device is, adding it if it's new
+    pub fn update_address(
+        &self,
+        ieee_address: [u8; 8],
+        network_address: u16,
+    ) -> Result<(), CoordinatorError> {
+        let mut devices = self.devices.lock().unwrap();
+        let moved = devices
+            .get(&ieee_address)
+            .is_none_or(|device| device.network_address != network_address);
+        // Network addresses are reused once a device is gone
+        devices.retain(|ieee, device| {
+            *ieee == ieee_address || device.network_address != network_address
+        });
+        let device = devices.entry(ieee_address).or_insert_with(|| Device {
+            ieee_address,
+            network_address,
+            endpoints: Vec::new(),
+            manufacturer_name: None,
+            model_id: None,
+            last_seen: None,
+        });
+        device.network_address = network_address;
+        device.last_seen = Some(now());
+        // Seen again where it was, only `last_seen` changed
+        if !moved {
+            return Ok(());
+        }
+        self.save(&devices)
+    }
+
+    pub fn set_endpoints(
+        &self,
+        ieee_address: &[u8; 8],
+        endpoints: Vec<Endpoint>,
+    ) -> Result<(), CoordinatorError> {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(device) = devices.get_mut(ieee_address) else {
+            return Err(CoordinatorError::UnknownDevice);
+        };
+        device.endpoints = endpoints;
+        self.save(&devices)
+    }
+
+    /// Stores what the interview found out, for a device the registry knows
+    pub fn set_description(&self, description: &DeviceDescription) -> Result<(), CoordinatorError> {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(device) = devices
+            .values_mut()
+            .find(|device| device.network_address == description.network_address)
+        else {
+            return Err(CoordinatorError::UnknownDevice);
+        };
+        device.endpoints = description.endpoints.clone();
+        device.manufacturer_name = description.manufacturer_name.clone();
+        device.model_id = description.model_id.clone();
+        self.save(&devices)
+    }
+
+    pub fn remove(&self, ieee_address: &[u8; 8]) -> Result<Option<Device>, CoordinatorError> {
+        let mut devices = self.devices.lock().unwrap();
+        let removed = devices.remove(ieee_address);
+        if removed.is_some() {
+            self.save(&devices)?;
+        }
+        Ok(removed)
+    }
+
+    /// Saves the devices, with their latest `last_seen`, and waits until they are stored
+    pub fn flush(&self) -> Result<(), CoordinatorError> {
+        self.save(&self.devices.lock().unwrap())?;
+        self.store.lock().unwrap().flush()
+    }
+
+    /// Applies what an event tells about the network
+    pub fn handle_event(&self, event: &ZigbeeEvent) -> Result<(), CoordinatorError> {
+        match event {
+            ZigbeeEvent::DeviceJoined {
+                network_address,
+                ieee_address,
+            }
+            | ZigbeeEvent::DeviceAnnounce {
+                network_address,
+                ieee_address,
+            }
+            | ZigbeeEvent::NetworkAddress {
+                network_address,
+                ieee_address,
+            } => self.update_address(*ieee_address, *network_address),
+            ZigbeeEvent::DeviceLeave(Either::Left((_, ieee_address)))
+            | ZigbeeEvent::DeviceLeave(Either::Right((_, Some(ieee_address)))) => {
+                self.remove(ieee_address).map(|_| ())
+            }
+            ZigbeeEvent::DeviceLeave(Either::Right((network_address, None))) => {
+                match self.by_network_address(*network_address) {
+                    Some(device) => self.remove(&device.ieee_address).map(|_| ()),
+                    None => Ok(()),
+                }
+            }
+            ZigbeeEvent::IncomingMessage(message) => {
+                let mut devices = self.devices.lock().unwrap();
+                let device = match message.source_ieee_address {
+                    Some(ieee_address) => devices.get_mut(&ieee_address),
+                    None => devices
+                        .values_mut()
+                        .find(|device| device.network_address == message.source_address),
+                };
+                match device {
+                    Some(device) => device.last_seen = Some(now()),
+                    None => warn!(
+                        "message from unknown device {:#06x}",
+                        message.source_address
+                    ),
+                }
+                Ok(())
+            }
+            ZigbeeEvent::NetworkState(_)
+            | ZigbeeEvent::PermitJoin(_)
+            | ZigbeeEvent::DeviceRejected { .. }
+            | ZigbeeEvent::SourceRoute { .. }
+            | ZigbeeEvent::Lagged(_) => Ok(()),
+        }
+    }
+
+    fn save(&self, devices: &HashMap<[u8; 8], Device>) -> Result<(), CoordinatorError> {
+        let mut devices: Vec<_> = devices.values().cloned().collect();
+        devices.sort_by_key(|device| device.ieee_address);
+        self.store.lock().unwrap().save(&devices)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: [u8; 8] = [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8];
+    const OTHER_DEVICE: [u8; 8] = [0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8];
+
+    #[test]
+    fn test_registry_follows_events() {
+        let registry = DeviceRegistry::default();
+        registry
+            .handle_event(&ZigbeeEvent::DeviceJoined {
+                network_address: 0x1234,
+                ieee_address: DEVICE,
+            })
+            .unwrap();
+        assert_eq!(
+            registry.by_network_address(0x1234).unwrap().ieee_address,
+            DEVICE
+        );
+
+        // Rejoined at another address, then left silently and had it reused
+        registry
+            .handle_event(&ZigbeeEvent::DeviceAnnounce {
+                network_address: 0x5678,
+                ieee_address: DEVICE,
+            })
+            .unwrap();
+        registry
+            .handle_event(&ZigbeeEvent::NetworkAddress {
+                network_address: 0x5678,
+                ieee_address: OTHER_DEVICE,
+            })
+            .unwrap();
+        assert!(registry.by_ieee_address(&DEVICE).is_none());
+        assert_eq!(
+            registry.by_network_address(0x5678).unwrap().ieee_address,
+            OTHER_DEVICE
+        );
+        assert!(registry.by_network_address(0x1234).is_none());
+
+        registry
+            .handle_event(&ZigbeeEvent::DeviceLeave(Either::Right((0x5678, None))))
+            .unwrap();
+        assert!(registry.devices().is_empty());
+    }
+
+    #[test]
+    fn test_json_file_store() {
+        let path = std::env::temp_dir().join(format!("devices-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let registry = DeviceRegistry::new(JsonFileStore::new(&path)).unwrap();
+        registry.update_address(DEVICE, 0x1234).unwrap();
+        let endpoint = Endpoint {
+            id: 1,
+            profile_id: 0x0104,
+            device_id: 0x0100,
+            input_clusters: vec![0x0000, 0x0006],
+            output_clusters: vec![0x0019],
+        };
+        registry
+            .set_endpoints(&DEVICE, vec![endpoint.clone()])
+            .unwrap();
+        assert!(matches!(
+            registry.set_endpoints(&OTHER_DEVICE, Vec::new()),
+            Err(CoordinatorError::UnknownDevice)
+        ));
+
+        registry.flush().unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json[0]["ieee_address"], "a8a7a6a5a4a3a2a1");
+        assert_eq!(json[0]["network_address"], "1234");
+
+        let reopened = DeviceRegistry::new(JsonFileStore::new(&path)).unwrap();
+        let device = reopened.by_ieee_address(&DEVICE).unwrap();
+        assert_eq!(device.network_address, 0x1234);
+        assert_eq!(device.endpoints, vec![endpoint]);
+        assert!(device.last_seen.is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
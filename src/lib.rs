@@ -1,7 +1,12 @@
+pub mod broadcast;
 pub mod coordinator;
+pub mod devices;
 pub mod serial;
 pub mod subscription;
+pub mod topology;
 pub mod utils;
+#[cfg(feature = "psila")]
+pub mod zcl;
 pub mod zstack;
 
 #[cfg(test)]
@@ -1,6 +1,7 @@
 use std::future::Future;
 
 pub mod simple_serial_port;
+pub mod tcp_serial_port;
 
 pub trait SimpleSerial<P> {
     type Sender;
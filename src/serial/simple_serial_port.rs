@@ -25,16 +25,8 @@ pub struct SimpleSerialPort<P> {
     subscription_service: Arc<Mutex<SubscriptionService<P>>>,
 }
 
-impl<
-        P: for<'a> TryFrom<&'a [u8]>
-            + ToOwned<Owned = P>
-            + ToSerial
-            + PartialEq
-            + std::fmt::Debug
-            + Clone
-            + Send
-            + 'static,
-    > SimpleSerialPort<P>
+impl<P: FromSerial + ToSerial + PartialEq + std::fmt::Debug + Clone + Send + 'static>
+    SimpleSerialPort<P>
 {
     pub fn new(
         path: &str,
@@ -64,7 +56,7 @@ impl<
             .try_clone()
             .map_err(|e| CoordinatorError::SerialOpen(e.to_string()))?;
 
-        let subscription_service = self.subscription_service.clone();
+        let mut dispatcher = PacketDispatcher::new(self.subscription_service.clone());
         let mut receive_from_serial_send_to_channel = move || -> Result<(), SerialThreadError> {
             loop {
                 let mut buffer = [0u8; 256];
@@ -75,14 +67,7 @@ impl<
                     }
                     Err(e) => return Err(SerialThreadError::SerialRead(e.to_string())),
                 }?;
-                if let Some(start_of_frame_position) = buffer.iter().position(|&x| x == 0xfe) {
-                    let packet: P = P::try_from(&buffer[start_of_frame_position..len])
-                        .map_err(|_| SerialThreadError::PacketParse)?
-                        .to_owned();
-                    trace!("<<< {:?}", packet);
-                    let send = async { subscription_service.lock().await.notify(packet) };
-                    block_on(send).map_err(|_| SerialThreadError::SubscriptionWrite)?;
-                }
+                dispatcher.dispatch(&buffer[..len])?;
             }
         };
         let mut rx = self
@@ -133,3 +118,47 @@ impl<P: Clone> SimpleSerial<P> for SimpleSerialPort<P> {
 pub trait ToSerial {
     fn to_serial<W: std::io::Write + ?Sized>(&self, writer: &mut W) -> Result<(), std::io::Error>;
 }
+
+/// Turns raw bytes read from a transport into packets and hands them to the subscription service.
+/// Shared by every byte oriented transport so they all decode and dispatch the same way.
+pub struct PacketDispatcher<P: FromSerial> {
+    decoder: P::Decoder,
+    subscription_service: Arc<Mutex<SubscriptionService<P>>>,
+}
+
+impl<P: FromSerial + PartialEq + std::fmt::Debug + Clone> PacketDispatcher<P> {
+    pub fn new(subscription_service: Arc<Mutex<SubscriptionService<P>>>) -> Self {
+        Self {
+            decoder: P::Decoder::default(),
+            subscription_service,
+        }
+    }
+
+    /// Forgets any partial packet, used when the underlying link is reopened
+    pub fn reset(&mut self) {
+        self.decoder = P::Decoder::default();
+    }
+
+    pub fn dispatch(&mut self, data: &[u8]) -> Result<(), SerialThreadError> {
+        self.decoder.push(data);
+        while let Some(packet) = self.decoder.next_packet() {
+            trace!("<<< {:?}", packet);
+            let send = async { self.subscription_service.lock().await.notify(packet) };
+            block_on(send).map_err(|_| SerialThreadError::SubscriptionWrite)?;
+        }
+        Ok(())
+    }
+}
+
+/// Packets that can be decoded from a byte stream read from a serial port
+pub trait FromSerial: Sized {
+    type Decoder: SerialDecoder<Self>;
+}
+
+/// Stateful decoder that keeps partial packets between reads
+pub trait SerialDecoder<P>: Default + Send {
+    /// Appends bytes read from the serial port
+    fn push(&mut self, data: &[u8]);
+    /// Returns the next complete packet, if any
+    fn next_packet(&mut self) -> Option<P>;
+}
@@ -0,0 +1,275 @@
+use super::{
+    simple_serial_port::{FromSerial, PacketDispatcher, ToSerial},
+    SerialThreadError, SimpleSerial,
+};
+use crate::{
+    coordinator::CoordinatorError,
+    subscription::SubscriptionService,
+    utils::{error, info, trace, warn},
+};
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    executor::block_on,
+    lock::Mutex,
+    SinkExt, StreamExt,
+};
+use std::{
+    io::Read,
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+pub const TCP_PATH_PREFIX: &str = "tcp://";
+const DEFAULT_READ_TIMEOUT_MS: u64 = 10;
+const RECONNECT_DELAY_MS: u64 = 500;
+const CONNECT_TIMEOUT_MS: u64 = 5_000;
+
+/// Raw UNPI over a TCP socket, for dongles exposed by ser2net or an ESP32 serial bridge.
+///
+/// The link is re-established in the background whenever it drops. Packets written while
+/// disconnected are discarded, their replies simply time out.
+pub struct TcpSerialPort<P> {
+    address: String,
+    // from the coordinator to the socket
+    to_serial: (Option<Sender<P>>, Option<Receiver<P>>),
+    // current connection, shared by the read and write threads
+    stream: Arc<std::sync::Mutex<Option<TcpStream>>>,
+    closed: Arc<AtomicBool>,
+    read_thread: Option<JoinHandle<Result<(), SerialThreadError>>>,
+    write_thread: Option<JoinHandle<Result<(), SerialThreadError>>>,
+    subscription_service: Arc<Mutex<SubscriptionService<P>>>,
+}
+
+impl<P: FromSerial + ToSerial + PartialEq + std::fmt::Debug + Clone + Send + 'static>
+    TcpSerialPort<P>
+{
+    /// Connects to `tcp://host:port`
+    pub fn new(
+        path: &str,
+        subscription_service: Arc<Mutex<SubscriptionService<P>>>,
+    ) -> Result<Self, CoordinatorError> {
+        let address = path
+            .strip_prefix(TCP_PATH_PREFIX)
+            .ok_or_else(|| CoordinatorError::SerialOpen(format!("not a tcp path: {}", path)))?;
+        let to_serial = mpsc::channel(20);
+        let to_serial = (Some(to_serial.0), Some(to_serial.1));
+        let mut s = TcpSerialPort {
+            address: address.to_string(),
+            to_serial,
+            stream: Arc::new(std::sync::Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+            read_thread: None,
+            write_thread: None,
+            subscription_service,
+        };
+        s.start()?;
+        Ok(s)
+    }
+
+    fn start(&mut self) -> Result<(), CoordinatorError> {
+        // The first connection is made here so a wrong address is reported right away
+        let read =
+            connect(&self.address).map_err(|e| CoordinatorError::SerialOpen(e.to_string()))?;
+        self.stream.lock().unwrap().replace(
+            read.try_clone()
+                .map_err(|e| CoordinatorError::SerialOpen(e.to_string()))?,
+        );
+
+        let address = self.address.clone();
+        let stream = self.stream.clone();
+        let closed = self.closed.clone();
+        let mut dispatcher = PacketDispatcher::new(self.subscription_service.clone());
+        let receive_from_socket_send_to_channel = move || -> Result<(), SerialThreadError> {
+            let mut read = Some(read);
+            while !closed.load(Ordering::Relaxed) {
+                let Some(r) = read.as_mut() else {
+                    std::thread::sleep(std::time::Duration::from_millis(RECONNECT_DELAY_MS));
+                    match connect(&address).and_then(|r| Ok((r.try_clone()?, r))) {
+                        Ok((w, r)) => {
+                            info!("reconnected to {}", address);
+                            dispatcher.reset();
+                            stream.lock().unwrap().replace(w);
+                            read.replace(r);
+                        }
+                        Err(e) => warn!("reconnecting to {} failed: {}", address, e),
+                    }
+                    continue;
+                };
+                let mut buffer = [0u8; 256];
+                match r.read(&mut buffer) {
+                    Ok(0) => {
+                        warn!("connection to {} closed", address);
+                    }
+                    Ok(len) => {
+                        dispatcher.dispatch(&buffer[..len])?;
+                        continue;
+                    }
+                    Err(e)
+                        if e.kind() == std::io::ErrorKind::TimedOut
+                            || e.kind() == std::io::ErrorKind::WouldBlock =>
+                    {
+                        continue;
+                    }
+                    Err(e) => warn!("reading from {} failed: {}", address, e),
+                }
+                // Link dropped, stop writing to it and reconnect
+                stream.lock().unwrap().take();
+                read.take();
+            }
+            Ok(())
+        };
+        let mut rx = self
+            .to_serial
+            .1
+            .take()
+            .ok_or(CoordinatorError::SerialChannelMissing)?;
+        let stream = self.stream.clone();
+        let mut receive_from_channel_send_to_socket = move || -> Result<(), SerialThreadError> {
+            block_on(async {
+                while let Some(packet) = rx.next().await {
+                    trace!(">>> {:?}", packet);
+                    let mut stream = stream.lock().unwrap();
+                    let Some(write) = stream.as_mut() else {
+                        warn!("not connected, dropping {:?}", packet);
+                        continue;
+                    };
+                    if let Err(e) = packet.to_serial(write) {
+                        // The read thread notices the shutdown and reconnects
+                        warn!("writing to socket failed: {:?}", e);
+                        let _ = write.shutdown(Shutdown::Both);
+                        stream.take();
+                    }
+                }
+            });
+            Ok(())
+        };
+        self.read_thread.replace(std::thread::spawn(move || {
+            receive_from_socket_send_to_channel()
+                .inspect_err(|e| error!("receive_from_socket_send_to_channel: {:?}", e))
+        }));
+        self.write_thread.replace(std::thread::spawn(move || {
+            receive_from_channel_send_to_socket()
+                .inspect_err(|e| error!("receive_from_channel_send_to_socket: {:?}", e))
+        }));
+        Ok(())
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream, std::io::Error> {
+    let socket_address = std::net::ToSocketAddrs::to_socket_addrs(address)?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address"))?;
+    let stream = TcpStream::connect_timeout(
+        &socket_address,
+        std::time::Duration::from_millis(CONNECT_TIMEOUT_MS),
+    )?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(std::time::Duration::from_millis(
+        DEFAULT_READ_TIMEOUT_MS,
+    )))?;
+    Ok(stream)
+}
+
+impl<P> Drop for TcpSerialPort<P> {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl<P: Clone> SimpleSerial<P> for TcpSerialPort<P> {
+    type Sender = Sender<P>;
+    type Receiver = Receiver<P>;
+
+    async fn write(&mut self, packet: &P) -> Result<(), SerialThreadError> {
+        let tx = self
+            .to_serial
+            .0
+            .as_mut()
+            .ok_or(SerialThreadError::SerialChannelMissing)?;
+        tx.send(packet.clone())
+            .await
+            .map_err(|_e| SerialThreadError::SerialChannel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zstack::unpi::{
+        decoder::UnpiDecoder,
+        serial::request_with_reply,
+        subsystems::sys::{PingRequest, PingResponse},
+        LenTypeInfo, MessageType, SUnpiPacket, Subsystem,
+    };
+    use std::{io::Write, net::TcpListener};
+
+    const PING_RESPONSE: [u8; 7] = [0xfe, 0x02, 0x61, 0x01, 0x79, 0x01, 0x1a];
+
+    // Answers every ping on the connection until the host sends `pings` of them
+    fn answer_pings(stream: &mut TcpStream, pings: usize) {
+        let mut decoder = UnpiDecoder::default();
+        let mut answered = 0;
+        while answered < pings {
+            let mut buffer = [0u8; 256];
+            let len = stream.read(&mut buffer).unwrap();
+            decoder.push(&buffer[..len]);
+            while let Some(packet) = decoder.next_packet() {
+                assert_eq!(packet.type_subsystem, (MessageType::SREQ, Subsystem::Sys));
+                stream.write_all(&PING_RESPONSE).unwrap();
+                answered += 1;
+            }
+        }
+    }
+
+    fn ping(
+        port: &Arc<Mutex<TcpSerialPort<SUnpiPacket>>>,
+        subscriptions: &Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+    ) -> Result<PingResponse, crate::zstack::unpi::serial::UnpiCommandError> {
+        let packet =
+            SUnpiPacket::from_command_owned(LenTypeInfo::OneByte, &PingRequest {}).unwrap();
+        block_on(request_with_reply::<PingRequest, _, PingResponse>(
+            &packet,
+            port.clone(),
+            subscriptions.clone(),
+            Some(std::time::Duration::from_millis(300)),
+        ))
+    }
+
+    #[test]
+    fn test_tcp_rejects_non_tcp_path() {
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::<SUnpiPacket>::new()));
+        assert!(TcpSerialPort::new("/dev/ttyACM0", subscriptions).is_err());
+    }
+
+    #[test]
+    fn test_tcp_request_and_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = format!("tcp://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut first, _) = listener.accept().unwrap();
+            answer_pings(&mut first, 1);
+            // Drop the link, the port has to come back on its own
+            drop(first);
+            let (mut second, _) = listener.accept().unwrap();
+            answer_pings(&mut second, 1);
+        });
+
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
+        let port = Arc::new(Mutex::new(
+            TcpSerialPort::new(&path, subscriptions.clone()).unwrap(),
+        ));
+        assert_eq!(ping(&port, &subscriptions).unwrap().capabilities, 0x0179);
+
+        // Writes are dropped until the reconnection happens, so keep trying for a while
+        let reconnected = (0..20).any(|_| ping(&port, &subscriptions).is_ok());
+        assert!(reconnected);
+        server.join().unwrap();
+    }
+}
@@ -9,55 +9,78 @@ pub struct Event<T>(pub Box<dyn Fn(&T) + Send + Sync>);
 /// Subscribe to a single event or multiple events with a predicate that runs on each ocurrence.
 /// If the predicate returns true, then the closure on the second element is executed.
 pub struct SubscriptionService<T> {
-    subscriptions: VecDeque<Subscription<T>>,
+    subscriptions: VecDeque<(SubscriptionId, Subscription<T>)>,
+    next_id: u64,
 }
 
+/// Handle returned by [`SubscriptionService::subscribe`], used to remove a subscription that is no longer wanted
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
 impl<T: Clone + PartialEq + std::fmt::Debug> SubscriptionService<T> {
     pub fn new() -> Self {
         Self {
             subscriptions: VecDeque::new(),
+            next_id: 0,
         }
     }
 
-    pub fn subscribe(&mut self, subscription: Subscription<T>) {
+    pub fn subscribe(&mut self, subscription: Subscription<T>) -> SubscriptionId {
         trace!("adding subscription {:?}", subscription);
-        self.subscriptions.push_front(subscription);
+        let id = SubscriptionId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        self.subscriptions.push_front((id, subscription));
+        id
+    }
+
+    /// Removes a subscription, returns false if it was already gone (single shots are removed once they fire)
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        if let Some(position) = self.subscriptions.iter().position(|(i, _)| *i == id) {
+            trace!("removing subscription {:?}", id);
+            self.subscriptions.remove(position);
+            true
+        } else {
+            false
+        }
     }
 
+    pub fn len(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+    }
+
+    /// Hands `value` to every event subscription whose predicate accepts it, then to the first
+    /// single shot that does, which is removed. Events go first so that whoever waits on the
+    /// single shot sees their effects once it wakes up.
     pub fn notify(&mut self, value: T) -> Result<(), SubscriptionError> {
-        if let Some((position, is_single_shot)) = self
-            .subscriptions
-            .iter_mut()
-            .enumerate()
-            .find(|(_, s)| match s {
-                Subscription::SingleShot(predicate, _) => predicate.0(&value),
-                Subscription::Event(predicate, _) => predicate.0(&value),
-            })
-            .map(|x| (x.0, x.1.is_single_shot()))
-        {
-            if is_single_shot {
-                let subscription = self
-                    .subscriptions
-                    .remove(position)
-                    .ok_or(SubscriptionError::MissingSubscription)?;
-                let action = subscription
-                    .into_action()
-                    .ok_or(SubscriptionError::NotAction)?
-                    .1;
-                action.0(&value);
-            } else {
-                let subscription = self
-                    .subscriptions
-                    .get_mut(position)
-                    .ok_or(SubscriptionError::MissingSubscription)?;
-                match subscription {
-                    Subscription::SingleShot(_, _) => return Err(SubscriptionError::Unreachable),
-                    Subscription::Event(_, action) => {
-                        action.0(&value);
-                    }
+        let mut delivered = false;
+        for (_, subscription) in self.subscriptions.iter() {
+            if let Subscription::Event(predicate, action) = subscription {
+                if predicate.0(&value) {
+                    action.0(&value);
+                    delivered = true;
                 }
             }
-        } else {
+        }
+        if let Some(position) = self.subscriptions.iter().position(|(_, s)| match s {
+            Subscription::SingleShot(predicate, _) => predicate.0(&value),
+            Subscription::Event(_, _) => false,
+        }) {
+            let (_, subscription) = self
+                .subscriptions
+                .remove(position)
+                .ok_or(SubscriptionError::MissingSubscription)?;
+            let action = subscription
+                .into_action()
+                .ok_or(SubscriptionError::NotAction)?
+                .1;
+            action.0(&value);
+            delivered = true;
+        }
+        if !delivered {
             warn!("No subscription found for {:?}", value);
         }
         Ok(())
@@ -79,10 +102,6 @@ pub enum Subscription<T> {
 }
 
 impl<T> Subscription<T> {
-    fn is_single_shot(&self) -> bool {
-        matches!(self, Subscription::SingleShot(_, _))
-    }
-
     fn into_action(self) -> Option<(Predicate<T>, Action<T>)> {
         match self {
             Subscription::SingleShot(predicate, tx) => Some((predicate, tx)),
@@ -116,3 +135,36 @@ impl<T> std::fmt::Debug for Event<T> {
         write!(f, "Action")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_notify_reaches_every_event_and_one_single_shot() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut service = SubscriptionService::new();
+        for name in ["first event", "second event"] {
+            let seen = seen.clone();
+            service.subscribe(Subscription::Event(
+                Predicate(Box::new(|value: &u8| *value == 1)),
+                Event(Box::new(move |_| seen.lock().unwrap().push(name))),
+            ));
+        }
+        for name in ["older single shot", "newer single shot"] {
+            let seen = seen.clone();
+            service.subscribe(Subscription::SingleShot(
+                Predicate(Box::new(|value: &u8| *value == 1)),
+                Action(Box::new(move |_| seen.lock().unwrap().push(name))),
+            ));
+        }
+        service.notify(1).unwrap();
+        service.notify(2).unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["second event", "first event", "newer single shot"]
+        );
+        assert_eq!(service.len(), 3);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{
+    coordinator::CoordinatorError,
+    utils::hex,
+    zstack::unpi::{
+        constants::zdo::{LogicalType, Relationship, RouteStatus},
+        subsystems::zdo::{NeighborLqi, RoutingEntry},
+    },
+};
+use serde::Serialize;
+use std::fmt::Write;
+
+/// Mesh as its routers describe it: who hears whom and how well, and where frames are routed
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct Topology {
+    pub nodes: Vec<Node>,
+    pub links: Vec<Link>,
+    pub routes: Vec<Route>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Node {
+    #[serde(with = "hex::u16")]
+    pub network_address: u16,
+    /// Least significant byte first, unknown for a node only heard of by its network address
+    #[serde(with = "hex::optional_eui64")]
+    pub ieee_address: Option<[u8; 8]>,
+    pub logical_type: Option<LogicalType>,
+    /// Hops from the coordinator
+    pub depth: Option<u8>,
+    /// Whether the node gave its neighbor table, end devices and unreachable routers don't
+    pub scanned: bool,
+}
+
+/// A node hearing one of its neighbors
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Link {
+    /// Node whose neighbor table lists the link
+    #[serde(with = "hex::u16")]
+    pub source: u16,
+    #[serde(with = "hex::u16")]
+    pub neighbor: u16,
+    /// Quality of the frames `source` receives from `neighbor`
+    pub lqi: u8,
+    pub relationship: Relationship,
+}
+
+/// Entry of the routing table of a node
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Route {
+    /// Node whose routing table holds the entry
+    #[serde(with = "hex::u16")]
+    pub source: u16,
+    #[serde(with = "hex::u16")]
+    pub destination: u16,
+    #[serde(with = "hex::u16")]
+    pub next_hop: u16,
+    pub status: RouteStatus,
+}
+
+impl Topology {
+    /// The node with that network address, added when it isn't known yet
+    pub fn node(&mut self, network_address: u16) -> &mut Node {
+        let index = match self
+            .nodes
+            .iter()
+            .position(|node| node.network_address == network_address)
+        {
+            Some(index) => index,
+            None => {
+                self.nodes.push(Node {
+                    network_address,
+                    ieee_address: None,
+                    logical_type: None,
+                    depth: None,
+                    scanned: false,
+                });
+                self.nodes.len() - 1
+            }
+        };
+        &mut self.nodes[index]
+    }
+
+    /// Records the neighbor table of `source` and returns the routers listed in it, which have
+    /// neighbor tables of their own
+    pub fn add_neighbors(&mut self, source: u16, neighbors: &[NeighborLqi]) -> Vec<u16> {
+        let mut routers = Vec::new();
+        for neighbor in neighbors {
+            let logical_type = LogicalType::from_type(neighbor.device_type());
+            let node = self.node(neighbor.network_address);
+            node.ieee_address = Some(neighbor.ieee_address);
+            node.logical_type = logical_type.or(node.logical_type);
+            node.depth = Some(neighbor.depth);
+            if matches!(
+                logical_type,
+                Some(LogicalType::Coordinator | LogicalType::Router)
+            ) {
+                routers.push(neighbor.network_address);
+            }
+            self.links.push(Link {
+                source,
+                neighbor: neighbor.network_address,
+                lqi: neighbor.lqi,
+                relationship: Relationship::from_relationship(neighbor.relationship())
+                    .unwrap_or(Relationship::Other),
+            });
+        }
+        self.node(source).scanned = true;
+        routers
+    }
+
+    /// Records the routing table of `source`
+    pub fn add_routes(&mut self, source: u16, routes: &[RoutingEntry]) {
+        self.routes.extend(routes.iter().filter_map(|route| {
+            // Flags share the byte with the status
+            Some(Route {
+                source,
+                destination: route.destination_address,
+                next_hop: route.next_hop,
+                status: RouteStatus::from_status(route.status & 0x07)?,
+            })
+        }));
+    }
+
+    pub fn to_json(&self) -> Result<String, CoordinatorError> {
+        serde_json::to_string_pretty(self).map_err(|e| CoordinatorError::Io(e.to_string()))
+    }
+
+    /// Graphviz graph of the mesh. Links are solid edges labelled with their LQI, active routes
+    /// dashed edges to the next hop labelled with their destination.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph topology {\n");
+        for node in &self.nodes {
+            let mut label = format!("{:#06x}", node.network_address);
+            if let Some(logical_type) = node.logical_type {
+                let _ = write!(label, "\\n{:?}", logical_type);
+            }
+            if let Some(ieee_address) = node.ieee_address {
+                label.push_str("\\n");
+                label.extend(ieee_address.iter().rev().map(|b| format!("{:02x}", b)));
+            }
+            let shape = match node.logical_type {
+                Some(LogicalType::Coordinator | LogicalType::Router) => "box",
+                _ => "ellipse",
+            };
+            let style = if node.scanned { "solid" } else { "dashed" };
+            let _ = writeln!(
+                dot,
+                "    \"{:#06x}\" [label=\"{}\", shape={}, style={}];",
+                node.network_address, label, shape, style
+            );
+        }
+        for link in &self.links {
+            let _ = writeln!(
+                dot,
+                "    \"{:#06x}\" -> \"{:#06x}\" [label=\"{}\"];",
+                link.source, link.neighbor, link.lqi
+            );
+        }
+        for route in &self.routes {
+            if route.status != RouteStatus::Active {
+                continue;
+            }
+            let _ = writeln!(
+                dot,
+                "    \"{:#06x}\" -> \"{:#06x}\" [label=\"to {:#06x}\", style=dashed];",
+                route.source, route.next_hop, route.destination
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neighbor(network_address: u16, device_type: u8, relationship: u8, lqi: u8) -> NeighborLqi {
+        NeighborLqi {
+            extended_pan_id: [0xdd; 8],
+            ieee_address: [network_address as u8, 0, 0, 0, 0, 0, 0, 0x11],
+            network_address,
+            device_type_rx_on_when_idle_relationship: device_type | (relationship << 4),
+            permit_joining: 0,
+            depth: 1,
+            lqi,
+        }
+    }
+
+    #[test]
+    fn test_topology_export() {
+        let mut topology = Topology::default();
+        let coordinator = topology.node(0x0000);
+        coordinator.logical_type = Some(LogicalType::Coordinator);
+        coordinator.depth = Some(0);
+        let routers = topology.add_neighbors(
+            0x0000,
+            &[neighbor(0x1234, 1, 1, 180), neighbor(0x5678, 2, 1, 90)],
+        );
+        assert_eq!(routers, vec![0x1234]);
+        topology.add_routes(
+            0x0000,
+            &[
+                RoutingEntry {
+                    destination_address: 0x9abc,
+                    status: 0,
+                    next_hop: 0x1234,
+                },
+                RoutingEntry {
+                    destination_address: 0x5678,
+                    status: 3,
+                    next_hop: 0x5678,
+                },
+            ],
+        );
+        assert_eq!(topology.nodes.len(), 3);
+        assert!(topology.nodes[0].scanned);
+        assert!(!topology.nodes[1].scanned);
+        assert_eq!(topology.routes[1].status, RouteStatus::Inactive);
+
+        let dot = topology.to_dot();
+        assert!(dot.starts_with("digraph topology {\n"));
+        assert!(dot.contains(
+            "\"0x1234\" [label=\"0x1234\\nRouter\\n1100000000000034\", shape=box, style=dashed];"
+        ));
+        assert!(dot.contains("\"0x0000\" -> \"0x5678\" [label=\"90\"];"));
+        assert!(dot.contains("\"0x0000\" -> \"0x1234\" [label=\"to 0x9abc\", style=dashed];"));
+        // Inactive routes are left out of the graph
+        assert!(!dot.contains("to 0x5678"));
+
+        let json: serde_json::Value = serde_json::from_str(&topology.to_json().unwrap()).unwrap();
+        assert_eq!(json["nodes"][0]["ieee_address"], serde_json::Value::Null);
+        assert_eq!(json["nodes"][2]["ieee_address"], "1100000000000078");
+        assert_eq!(json["nodes"][2]["logical_type"], "EndDevice");
+        assert_eq!(json["links"][0]["neighbor"], "1234");
+        assert_eq!(json["links"][0]["relationship"], "Child");
+        assert_eq!(json["routes"][0]["next_hop"], "1234");
+    }
+}
@@ -0,0 +1,119 @@
+// Serde helpers for bytes written as hexadecimal strings, like the backup format does
+
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+fn encode(bytes: impl IntoIterator<Item = u8>) -> String {
+    bytes.into_iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode<const N: usize>(s: &str) -> Result<[u8; N], String> {
+    let s = s.replace(':', "");
+    // Digits are sliced two bytes at a time, which only lands on characters for ASCII
+    if !s.is_ascii() || s.len() != 2 * N {
+        return Err(format!("expected {} hex digits, got {:?}", 2 * N, s));
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|e| e.to_string())?;
+    }
+    Ok(bytes)
+}
+
+/// EUI64 written most significant byte first
+pub mod eui64 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &[u8; 8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&encode(value.iter().rev().copied()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 8], D::Error> {
+        let mut bytes: [u8; 8] = decode(&String::deserialize(d)?).map_err(D::Error::custom)?;
+        bytes.reverse();
+        Ok(bytes)
+    }
+}
+
+pub mod u16 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &u16, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&encode(value.to_be_bytes()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u16, D::Error> {
+        let bytes = decode(&String::deserialize(d)?).map_err(D::Error::custom)?;
+        Ok(u16::from_be_bytes(bytes))
+    }
+}
+
+pub mod optional_u16 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<u16>, s: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => super::u16::serialize(value, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u16>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| decode(&s).map(u16::from_be_bytes))
+            .transpose()
+            .map_err(D::Error::custom)
+    }
+}
+
+pub mod optional_eui64 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<[u8; 8]>, s: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => super::eui64::serialize(value, s),
+            None => s.serialize_none(),
+        }
+    }
+}
+
+pub mod key {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &[u8; 16], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&encode(value.iter().copied()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 16], D::Error> {
+        decode(&String::deserialize(d)?).map_err(D::Error::custom)
+    }
+}
+
+pub mod optional_key {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<[u8; 16]>, s: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => super::key::serialize(value, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<[u8; 16]>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| decode(&s))
+            .transpose()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode::<2>("12:ab"), Ok([0x12, 0xab]));
+        assert!(decode::<2>("12a").is_err());
+        assert!(decode::<2>("a\u{e9}1").is_err());
+    }
+}
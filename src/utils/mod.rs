@@ -1,3 +1,4 @@
+pub(crate) mod hex;
 pub mod map;
 pub mod sleep;
 pub mod slice_reader;
@@ -1,9 +1,17 @@
-use futures::channel::oneshot;
+use futures::future::{select, Either};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
 
 /// Debug-only delay function to avoid adding another dependency just for delay
 #[cfg(debug_assertions)]
 pub async fn async_delay(duration: std::time::Duration) -> Result<(), ()> {
-    let (tx, rx) = oneshot::channel();
+    let (tx, rx) = futures::channel::oneshot::channel();
 
     std::thread::spawn(move || {
         std::thread::sleep(duration);
@@ -21,3 +29,176 @@ pub async fn sleep_forever() -> Result<(), ()> {
     while (async_delay(std::time::Duration::from_secs(1)).await).is_ok() {}
     Err(())
 }
+
+/// Runtime agnostic delay. Every pending delay is served by one timer thread, started on first
+/// use, and dropping the future before it completes takes it off that thread's list.
+pub async fn delay(duration: std::time::Duration) {
+    Delay::new(duration).await
+}
+
+static TIMER: Timer = Timer {
+    state: Mutex::new(TimerState {
+        wakers: BTreeMap::new(),
+        next_id: 0,
+        running: false,
+    }),
+    changed: Condvar::new(),
+};
+
+struct Timer {
+    state: Mutex<TimerState>,
+    // Signalled when a delay due before all the others is added
+    changed: Condvar,
+}
+
+struct TimerState {
+    // By deadline, the id tells apart delays due at the same instant
+    wakers: BTreeMap<(Instant, u64), Waker>,
+    next_id: u64,
+    running: bool,
+}
+
+// Wakes the delays as they fall due, for the rest of the process
+fn run_timer() {
+    let mut state = TIMER.state.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while let Some(entry) = state.wakers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            due.push(entry.remove());
+        }
+        if !due.is_empty() {
+            // Wakers may poll right away, which needs the lock
+            drop(state);
+            due.into_iter().for_each(Waker::wake);
+            state = TIMER.state.lock().unwrap();
+            continue;
+        }
+        state = match state.wakers.keys().next() {
+            Some(&(deadline, _)) => TIMER.changed.wait_timeout(state, deadline - now).unwrap().0,
+            None => TIMER.changed.wait(state).unwrap(),
+        };
+    }
+}
+
+struct Delay {
+    // None when the duration is too long to be represented, the delay never completes then
+    deadline: Option<Instant>,
+    // Set once the delay is registered with the timer
+    id: Option<u64>,
+}
+
+impl Delay {
+    fn new(duration: Duration) -> Self {
+        Delay {
+            deadline: Instant::now().checked_add(duration),
+            id: None,
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(deadline) = self.deadline else {
+            return Poll::Pending;
+        };
+        if Instant::now() >= deadline {
+            return Poll::Ready(());
+        }
+        let mut state = TIMER.state.lock().unwrap();
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                state.next_id += 1;
+                self.id = Some(state.next_id);
+                state.next_id
+            }
+        };
+        let earliest = state.wakers.keys().next().copied();
+        state.wakers.insert((deadline, id), cx.waker().clone());
+        if !state.running {
+            state.running = true;
+            std::thread::spawn(run_timer);
+        } else if earliest.is_none_or(|earliest| (deadline, id) < earliest) {
+            TIMER.changed.notify_one();
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if let (Some(deadline), Some(id)) = (self.deadline, self.id) {
+            TIMER.state.lock().unwrap().wakers.remove(&(deadline, id));
+        }
+    }
+}
+
+/// Runs `future` to completion or fails with [`Elapsed`] once `duration` has passed
+pub async fn timeout<F: Future>(
+    duration: std::time::Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    let future = std::pin::pin!(future);
+    let delay = std::pin::pin!(delay(duration));
+    match select(future, delay).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Elapsed;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timeout_elapses() {
+        let never = futures::future::pending::<()>();
+        assert_eq!(
+            block_on(timeout(Duration::from_millis(10), never)),
+            Err(Elapsed)
+        );
+    }
+
+    #[test]
+    fn test_timeout_completes() {
+        let ready = futures::future::ready(42);
+        assert_eq!(block_on(timeout(Duration::from_secs(10), ready)), Ok(42));
+    }
+
+    #[test]
+    fn test_delays_complete_in_any_order() {
+        let started = Instant::now();
+        let durations = [30, 10, 20].map(Duration::from_millis);
+        let finished = block_on(futures::future::join_all(durations.map(
+            |duration| async move {
+                delay(duration).await;
+                started.elapsed()
+            },
+        )));
+        for (duration, finished) in durations.iter().zip(finished) {
+            assert!(finished >= *duration);
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_dropped_delay_leaves_the_timer() {
+        let mut delay = Delay::new(Duration::from_secs(3600));
+        assert!(futures::FutureExt::now_or_never(&mut delay).is_none());
+        let key = (delay.deadline.unwrap(), delay.id.unwrap());
+        assert!(TIMER.state.lock().unwrap().wakers.contains_key(&key));
+        drop(delay);
+        assert!(!TIMER.state.lock().unwrap().wakers.contains_key(&key));
+    }
+}
@@ -0,0 +1,435 @@
+use crate::coordinator::CoordinatorError;
+use psila_data::{
+    cluster_library::{ClusterLibraryHeader, Direction, FrameControl, FrameType},
+    pack::Pack,
+};
+
+// Frame control, manufacturer code, sequence number and command
+const MAXIMUM_HEADER_SIZE: usize = 5;
+
+/// Global commands, shared by every cluster
+pub const READ_ATTRIBUTES: u8 = 0x00;
+pub const READ_ATTRIBUTES_RESPONSE: u8 = 0x01;
+pub const DEFAULT_RESPONSE: u8 = 0x0b;
+
+pub const BASIC_CLUSTER: u16 = 0x0000;
+pub const BASIC_MANUFACTURER_NAME: u16 = 0x0004;
+pub const BASIC_MODEL_IDENTIFIER: u16 = 0x0005;
+
+/// Groups cluster, its commands and their responses share IDs
+pub const GROUPS_CLUSTER: u16 = 0x0004;
+pub const GROUPS_ADD: u8 = 0x00;
+pub const GROUPS_VIEW: u8 = 0x01;
+pub const GROUPS_GET_MEMBERSHIP: u8 = 0x02;
+pub const GROUPS_REMOVE: u8 = 0x03;
+
+/// Data types of attribute values
+pub const DATA_TYPE_OCTET_STRING: u8 = 0x41;
+pub const DATA_TYPE_CHARACTER_STRING: u8 = 0x42;
+
+/// ZCL frame exchanged with a cluster: the cluster library header followed by the command payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZclFrame {
+    pub cluster_id: u16,
+    pub header: ClusterLibraryHeader,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZclError {
+    Pack(psila_data::Error),
+    UnexpectedCommand(u8),
+    UnsupportedDataType(u8),
+    Truncated,
+}
+
+impl From<psila_data::Error> for ZclError {
+    fn from(e: psila_data::Error) -> Self {
+        ZclError::Pack(e)
+    }
+}
+
+impl From<ZclError> for CoordinatorError {
+    fn from(e: ZclError) -> Self {
+        CoordinatorError::Zcl(format!("{:?}", e))
+    }
+}
+
+/// Answer of a device to a Groups cluster command
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupResponse {
+    Add {
+        status: u8,
+        group_id: u16,
+    },
+    View {
+        status: u8,
+        group_id: u16,
+        name: String,
+    },
+    Membership {
+        /// Groups the device can still join, 0xfe for at least one and 0xff when unknown
+        capacity: u8,
+        groups: Vec<u16>,
+    },
+    Remove {
+        status: u8,
+        group_id: u16,
+    },
+}
+
+/// Attribute of a read attributes response, the value still encoded as its data type says
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeRecord {
+    pub id: u16,
+    /// ZCL status, the attribute has no value unless it's 0
+    pub status: u8,
+    pub data_type: u8,
+    pub value: Vec<u8>,
+}
+
+impl AttributeRecord {
+    /// Value of a string attribute, without its length prefix
+    pub fn as_string(&self) -> Option<String> {
+        match self.data_type {
+            DATA_TYPE_OCTET_STRING | DATA_TYPE_CHARACTER_STRING if self.status == 0 => {
+                Some(String::from_utf8_lossy(self.value.get(1..)?).into_owned())
+            }
+            _ => None,
+        }
+    }
+}
+
+// Encoded size of a value of the data type, `data` starting with the value
+fn value_size(data_type: u8, data: &[u8]) -> Result<usize, ZclError> {
+    match data_type {
+        // Data, boolean, bitmaps, unsigned and signed integers by size
+        0x08..=0x0f => Ok((data_type - 0x07) as usize),
+        0x10 => Ok(1),
+        0x18..=0x1f => Ok((data_type - 0x17) as usize),
+        0x20..=0x27 => Ok((data_type - 0x1f) as usize),
+        0x28..=0x2f => Ok((data_type - 0x27) as usize),
+        // Enumerations
+        0x30 => Ok(1),
+        0x31 => Ok(2),
+        DATA_TYPE_OCTET_STRING | DATA_TYPE_CHARACTER_STRING => {
+            let length = *data.first().ok_or(ZclError::Truncated)?;
+            // 0xff marks an invalid string, which has no characters
+            Ok(1 + if length == 0xff { 0 } else { length as usize })
+        }
+        data_type => Err(ZclError::UnsupportedDataType(data_type)),
+    }
+}
+
+impl ZclFrame {
+    /// Global read attributes command
+    pub fn read_attributes(cluster_id: u16, transaction_sequence: u8, attributes: &[u16]) -> Self {
+        ZclFrame::new(
+            cluster_id,
+            ClusterLibraryHeader {
+                control: FrameControl {
+                    frame_type: FrameType::Global,
+                    manufacturer_specific: false,
+                    direction: Direction::ToServer,
+                    disable_default_response: true,
+                },
+                manufacturer: None,
+                transaction_sequence,
+                command: READ_ATTRIBUTES,
+            },
+            attributes.iter().flat_map(|id| id.to_le_bytes()).collect(),
+        )
+    }
+
+    // Command of the Groups cluster, sent to a device's server
+    fn groups(transaction_sequence: u8, command: u8, payload: Vec<u8>) -> Self {
+        ZclFrame::new(
+            GROUPS_CLUSTER,
+            ClusterLibraryHeader {
+                control: FrameControl {
+                    frame_type: FrameType::Local,
+                    manufacturer_specific: false,
+                    direction: Direction::ToServer,
+                    disable_default_response: false,
+                },
+                manufacturer: None,
+                transaction_sequence,
+                command,
+            },
+            payload,
+        )
+    }
+
+    /// Adds the device to a group, the name is only kept by devices that support group names
+    pub fn add_group(transaction_sequence: u8, group_id: u16, name: &str) -> Self {
+        let mut payload = group_id.to_le_bytes().to_vec();
+        let name = &name.as_bytes()[..name.len().min(u8::MAX as usize - 1)];
+        payload.push(name.len() as u8);
+        payload.extend_from_slice(name);
+        ZclFrame::groups(transaction_sequence, GROUPS_ADD, payload)
+    }
+
+    pub fn view_group(transaction_sequence: u8, group_id: u16) -> Self {
+        ZclFrame::groups(
+            transaction_sequence,
+            GROUPS_VIEW,
+            group_id.to_le_bytes().to_vec(),
+        )
+    }
+
+    /// Asks which of `groups` the device is in, all of its groups when `groups` is empty
+    pub fn get_group_membership(transaction_sequence: u8, groups: &[u16]) -> Self {
+        let mut payload = vec![groups.len() as u8];
+        payload.extend(groups.iter().flat_map(|group| group.to_le_bytes()));
+        ZclFrame::groups(transaction_sequence, GROUPS_GET_MEMBERSHIP, payload)
+    }
+
+    pub fn remove_group(transaction_sequence: u8, group_id: u16) -> Self {
+        ZclFrame::groups(
+            transaction_sequence,
+            GROUPS_REMOVE,
+            group_id.to_le_bytes().to_vec(),
+        )
+    }
+
+    /// Status of a default response, which a device sends instead of the expected response
+    /// when it fails to handle a command
+    pub fn default_response_status(&self) -> Option<u8> {
+        if self.header.control.frame_type != FrameType::Global
+            || self.header.command != DEFAULT_RESPONSE
+        {
+            return None;
+        }
+        // The command it answers comes first
+        self.payload.get(1).copied()
+    }
+
+    /// Answer of a device to a Groups cluster command
+    pub fn group_response(&self) -> Result<GroupResponse, ZclError> {
+        if self.cluster_id != GROUPS_CLUSTER || self.header.control.frame_type != FrameType::Local {
+            return Err(ZclError::UnexpectedCommand(self.header.command));
+        }
+        let data = self.payload.as_slice();
+        let group = |data: &[u8]| match data {
+            [status, low, high, ..] => Ok((*status, u16::from_le_bytes([*low, *high]))),
+            _ => Err(ZclError::Truncated),
+        };
+        match self.header.command {
+            GROUPS_ADD => {
+                let (status, group_id) = group(data)?;
+                Ok(GroupResponse::Add { status, group_id })
+            }
+            GROUPS_VIEW => {
+                let (status, group_id) = group(data)?;
+                // A failed view has no name
+                let name = match data.get(3..) {
+                    Some(name) if !name.is_empty() => {
+                        let size = value_size(DATA_TYPE_CHARACTER_STRING, name)?;
+                        let name = name.get(1..size).ok_or(ZclError::Truncated)?;
+                        String::from_utf8_lossy(name).into_owned()
+                    }
+                    _ => String::new(),
+                };
+                Ok(GroupResponse::View {
+                    status,
+                    group_id,
+                    name,
+                })
+            }
+            GROUPS_GET_MEMBERSHIP => {
+                let [capacity, count, groups @ ..] = data else {
+                    return Err(ZclError::Truncated);
+                };
+                let groups = groups
+                    .get(..2 * *count as usize)
+                    .ok_or(ZclError::Truncated)?
+                    .chunks_exact(2)
+                    .map(|group| u16::from_le_bytes([group[0], group[1]]))
+                    .collect();
+                Ok(GroupResponse::Membership {
+                    capacity: *capacity,
+                    groups,
+                })
+            }
+            GROUPS_REMOVE => {
+                let (status, group_id) = group(data)?;
+                Ok(GroupResponse::Remove { status, group_id })
+            }
+            command => Err(ZclError::UnexpectedCommand(command)),
+        }
+    }
+
+    /// Records of a read attributes response
+    pub fn attribute_records(&self) -> Result<Vec<AttributeRecord>, ZclError> {
+        if self.header.control.frame_type != FrameType::Global
+            || self.header.command != READ_ATTRIBUTES_RESPONSE
+        {
+            return Err(ZclError::UnexpectedCommand(self.header.command));
+        }
+        let mut records = Vec::new();
+        let mut data = self.payload.as_slice();
+        while !data.is_empty() {
+            let [id_low, id_high, status, rest @ ..] = data else {
+                return Err(ZclError::Truncated);
+            };
+            let id = u16::from_le_bytes([*id_low, *id_high]);
+            // Failed reads carry no data type nor value
+            if *status != 0 {
+                records.push(AttributeRecord {
+                    id,
+                    status: *status,
+                    data_type: 0,
+                    value: Vec::new(),
+                });
+                data = rest;
+                continue;
+            }
+            let [data_type, rest @ ..] = rest else {
+                return Err(ZclError::Truncated);
+            };
+            let size = value_size(*data_type, rest)?;
+            let value = rest.get(..size).ok_or(ZclError::Truncated)?;
+            records.push(AttributeRecord {
+                id,
+                status: 0,
+                data_type: *data_type,
+                value: value.to_vec(),
+            });
+            data = &rest[size..];
+        }
+        Ok(records)
+    }
+
+    pub fn new(cluster_id: u16, header: ClusterLibraryHeader, payload: Vec<u8>) -> Self {
+        ZclFrame {
+            cluster_id,
+            header,
+            payload,
+        }
+    }
+
+    /// Header and payload as sent over the air
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ZclError> {
+        let mut header = [0u8; MAXIMUM_HEADER_SIZE];
+        let used = self.header.pack(&mut header)?;
+        let mut bytes = header[..used].to_vec();
+        bytes.extend_from_slice(&self.payload);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(cluster_id: u16, data: &[u8]) -> Result<Self, ZclError> {
+        let (header, used) = ClusterLibraryHeader::unpack(data)?;
+        Ok(ZclFrame {
+            cluster_id,
+            header,
+            payload: data[used..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zcl_frame_round_trip() {
+        // On/Off cluster, toggle
+        let frame = ZclFrame::new(
+            0x0006,
+            ClusterLibraryHeader {
+                control: FrameControl {
+                    frame_type: FrameType::Local,
+                    manufacturer_specific: false,
+                    direction: Direction::ToServer,
+                    disable_default_response: false,
+                },
+                manufacturer: None,
+                transaction_sequence: 42,
+                command: 0x02,
+            },
+            vec![],
+        );
+        let bytes = frame.to_bytes().unwrap();
+        assert_eq!(bytes, vec![0x01, 42, 0x02]);
+        assert_eq!(ZclFrame::from_bytes(0x0006, &bytes).unwrap(), frame);
+    }
+
+    #[test]
+    fn test_read_attributes() {
+        let request = ZclFrame::read_attributes(BASIC_CLUSTER, 7, &[0x0004, 0x0005]);
+        assert_eq!(
+            request.to_bytes().unwrap(),
+            vec![0x10, 7, 0x00, 0x04, 0x00, 0x05, 0x00]
+        );
+
+        let response = ZclFrame::from_bytes(
+            BASIC_CLUSTER,
+            &[
+                0x18, 7, 0x01, // header
+                0x04, 0x00, 0x00, 0x42, 4, b'I', b'K', b'E', b'A', // manufacturer name
+                0x05, 0x00, 0x86, // model identifier unsupported
+                0x00, 0x00, 0x00, 0x20, 0x03, // ZCL version
+            ],
+        )
+        .unwrap();
+        let records = response.attribute_records().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].as_string(), Some("IKEA".to_string()));
+        assert_eq!(records[1].status, 0x86);
+        assert_eq!(records[1].as_string(), None);
+        assert_eq!(records[2].value, vec![0x03]);
+        assert_eq!(
+            request.attribute_records(),
+            Err(ZclError::UnexpectedCommand(READ_ATTRIBUTES))
+        );
+    }
+
+    #[test]
+    fn test_groups() {
+        assert_eq!(
+            ZclFrame::add_group(3, 0x0102, "Kitchen")
+                .to_bytes()
+                .unwrap(),
+            vec![0x01, 3, 0x00, 0x02, 0x01, 7, b'K', b'i', b't', b'c', b'h', b'e', b'n']
+        );
+        assert_eq!(
+            ZclFrame::get_group_membership(4, &[0x0102, 0x0304])
+                .to_bytes()
+                .unwrap(),
+            vec![0x01, 4, 0x02, 2, 0x02, 0x01, 0x04, 0x03]
+        );
+
+        let response = |data: &[u8]| ZclFrame::from_bytes(GROUPS_CLUSTER, data).unwrap();
+        assert_eq!(
+            response(&[0x19, 5, 0x01, 0x00, 0x02, 0x01, 2, b'H', b'i']).group_response(),
+            Ok(GroupResponse::View {
+                status: 0,
+                group_id: 0x0102,
+                name: "Hi".to_string(),
+            })
+        );
+        assert_eq!(
+            response(&[0x19, 5, 0x01, 0x8b, 0x02, 0x01]).group_response(),
+            Ok(GroupResponse::View {
+                status: 0x8b,
+                group_id: 0x0102,
+                name: String::new(),
+            })
+        );
+        assert_eq!(
+            response(&[0x19, 6, 0x02, 0x0a, 1, 0x02, 0x01]).group_response(),
+            Ok(GroupResponse::Membership {
+                capacity: 0x0a,
+                groups: vec![0x0102],
+            })
+        );
+        assert_eq!(
+            response(&[0x19, 6, 0x02, 0x0a, 2, 0x02, 0x01]).group_response(),
+            Err(ZclError::Truncated)
+        );
+
+        let failed = response(&[0x18, 7, DEFAULT_RESPONSE, GROUPS_ADD, 0x89]);
+        assert_eq!(failed.default_response_status(), Some(0x89));
+        assert!(failed.group_response().is_err());
+    }
+}
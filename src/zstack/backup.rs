@@ -0,0 +1,646 @@
+use super::{
+    nv_memory::{
+        entries::{
+            addr_mgr::{AddrMgrEntry, ADDR_MGR_USER_ASSOCIATE, ADDR_MGR_USER_SECURITY},
+            encode_entry,
+            nib::{Nib, NwkKeyDescriptor},
+            parse_table,
+            security::{
+                hashed_link_key, ApsKeyDataEntry, ApsLinkKeyTableEntry, NwkSecMaterialDesc,
+                TclkDevEntry, AUTHENTICATED_CBCK, KEY_ATTRIBUTES_VERIFIED,
+            },
+            ExtAddr, NvLayout, NwkActiveKeyInfo,
+        },
+        nv_item::{NvMemoryAdapter, NvMemoryAdapterError},
+        NvItemId,
+    },
+    unpi::{constants::sys::ZnpVersion, constants::CommandStatus, SUnpiPacket},
+};
+use crate::{
+    coordinator::{CoordinatorError, NetworkOptions, ZIGBEE_CHANNELS},
+    serial::SimpleSerial,
+    utils::hex,
+};
+use deku::{no_std_io::Cursor, reader::Reader, DekuContainerWrite, DekuReader};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// `metadata.format` of an Open Coordinator Backup
+pub const BACKUP_FORMAT: &str = "zigpy/open-coordinator-backup";
+pub const BACKUP_VERSION: u32 = 1;
+// Added to the restored frame counters, covering what was sent since the backup was made
+const FRAME_COUNTER_MARGIN: u32 = 2500;
+
+/// Network state in the Open ZigBee Coordinator Backup Format shared by zigpy and
+/// zigbee-herdsman, so a network can move between dongles and between projects.
+///
+/// Addresses and extended PAN IDs are kept least significant byte first, as the stack stores
+/// them. The JSON has them the other way around.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct NetworkBackup {
+    pub metadata: BackupMetadata,
+    #[serde(default)]
+    pub stack_specific: StackSpecific,
+    #[serde(with = "hex::eui64")]
+    pub coordinator_ieee: [u8; 8],
+    #[serde(with = "hex::u16")]
+    pub pan_id: u16,
+    #[serde(with = "hex::eui64")]
+    pub extended_pan_id: [u8; 8],
+    pub nwk_update_id: u8,
+    pub security_level: u8,
+    pub channel: u8,
+    pub channel_mask: Vec<u8>,
+    pub network_key: NetworkKeyBackup,
+    pub devices: Vec<DeviceBackup>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BackupMetadata {
+    pub format: String,
+    pub version: u32,
+    pub source: String,
+    #[serde(default)]
+    pub internal: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct StackSpecific {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zstack: Option<ZStackSpecific>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ZStackSpecific {
+    /// Seed the unique link key of each device is derived from, Z-Stack 3 only
+    #[serde(default, with = "hex::optional_key")]
+    pub tclk_seed: Option<[u8; 16]>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct NetworkKeyBackup {
+    #[serde(with = "hex::key")]
+    pub key: [u8; 16],
+    pub sequence_number: u8,
+    pub frame_counter: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DeviceBackup {
+    /// Unknown for devices only met through a link key
+    #[serde(with = "hex::optional_u16")]
+    pub nwk_address: Option<u16>,
+    #[serde(with = "hex::eui64")]
+    pub ieee_address: [u8; 8],
+    #[serde(default)]
+    pub is_child: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_key: Option<LinkKeyBackup>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct LinkKeyBackup {
+    #[serde(with = "hex::key")]
+    pub key: [u8; 16],
+    pub rx_counter: u32,
+    pub tx_counter: u32,
+}
+
+impl NetworkBackup {
+    pub fn to_json(&self) -> Result<String, CoordinatorError> {
+        serde_json::to_string_pretty(self).map_err(|e| CoordinatorError::Backup(e.to_string()))
+    }
+
+    /// Parses a backup made by this crate, zigpy or zigbee-herdsman
+    pub fn from_json(json: &str) -> Result<Self, CoordinatorError> {
+        let backup: NetworkBackup =
+            serde_json::from_str(json).map_err(|e| CoordinatorError::Backup(e.to_string()))?;
+        if backup.metadata.format != BACKUP_FORMAT || backup.metadata.version != BACKUP_VERSION {
+            return Err(CoordinatorError::Backup(format!(
+                "unsupported backup format {} version {}",
+                backup.metadata.format, backup.metadata.version
+            )));
+        }
+        let outside = backup
+            .channel_mask
+            .iter()
+            .chain([&backup.channel])
+            .find(|channel| !ZIGBEE_CHANNELS.contains(channel))
+            .copied();
+        if let Some(channel) = outside {
+            return Err(CoordinatorError::Backup(format!(
+                "channel {} is outside the Zigbee band",
+                channel
+            )));
+        }
+        Ok(backup)
+    }
+
+    /// Parameters to form the network with before the rest of the backup is written
+    pub fn network_options(&self) -> NetworkOptions {
+        NetworkOptions {
+            pan_id: self.pan_id,
+            extended_pan_id: self.extended_pan_id,
+            channel_list: vec![self.channel],
+            network_key: self.network_key.key,
+            network_key_distribute: false,
+        }
+    }
+
+    // Backups built by hand skip the checks of `from_json`, so the channels are checked again
+    fn channel_list(&self) -> Result<u32, CoordinatorError> {
+        self.channel_mask
+            .iter()
+            .chain([&self.channel])
+            .try_fold(0, |mask, channel| {
+                if !ZIGBEE_CHANNELS.contains(channel) {
+                    return Err(CoordinatorError::InvalidChannel);
+                }
+                Ok(mask | (1 << channel))
+            })
+    }
+}
+
+/// Reads everything the backup needs from NV memory. The network has to be formed.
+pub async fn read_backup<S: SimpleSerial<SUnpiPacket>>(
+    nv: &NvMemoryAdapter<S>,
+) -> Result<NetworkBackup, CoordinatorError> {
+    let version = nv.znp_version().await?;
+    let nib: Nib = nv.read().await?;
+    // Every table is laid out like the NIB, which tells the platform apart
+    let layout = nib.layout;
+    let ExtAddr(coordinator_ieee) = nv.read().await?;
+    let NwkActiveKeyInfo(active_key) = nv.read().await?;
+
+    let materials: Vec<NwkSecMaterialDesc> = parse_table(
+        &nv.read_table(NvItemId::ExNwkSecMaterialTable).await?,
+        layout,
+    )?;
+    let frame_counter = materials
+        .iter()
+        .find(|m| m.extended_pan_id == nib.extended_panid)
+        .or_else(|| materials.iter().find(|m| m.extended_pan_id == [0xff; 8]))
+        .map_or(0, |m| m.frame_counter);
+
+    let addr_mgr: Vec<AddrMgrEntry> =
+        parse_table(&nv.read_table(NvItemId::ZcdNvExAddrmgr).await?, layout)?;
+
+    let mut link_keys = HashMap::new();
+    let tclk_seed = if version == ZnpVersion::ZStack12 {
+        None
+    } else {
+        let seed: [u8; 16] = nv
+            .read_raw(NvItemId::TclkSeed)
+            .await?
+            .try_into()
+            .map_err(|_| CoordinatorError::Backup("invalid TCLK seed".to_string()))?;
+        let devices: Vec<TclkDevEntry> =
+            parse_table(&nv.read_table(NvItemId::ExTclkTable).await?, layout)?;
+        for device in devices {
+            if device.ext_addr == [0x00; 8] || device.ext_addr == [0xff; 8] {
+                continue;
+            }
+            link_keys.insert(
+                device.ext_addr,
+                LinkKeyBackup {
+                    key: hashed_link_key(&seed, &device.ext_addr, device.seed_shift_ic_index),
+                    rx_counter: device.rx_frame_counter,
+                    tx_counter: device.tx_frame_counter,
+                },
+            );
+        }
+        Some(seed)
+    };
+    for (ieee_address, key) in read_unhashed_link_keys(nv, &addr_mgr, layout).await? {
+        link_keys.entry(ieee_address).or_insert(key);
+    }
+
+    let mut devices: Vec<DeviceBackup> = addr_mgr
+        .iter()
+        .filter(|entry| entry.is_used())
+        .map(|entry| DeviceBackup {
+            nwk_address: (entry.nwk_addr != 0xfffe).then_some(entry.nwk_addr),
+            ieee_address: entry.ext_addr,
+            is_child: entry.is_child(),
+            link_key: link_keys.remove(&entry.ext_addr),
+        })
+        .collect();
+    // Keys of devices the address manager forgot are still worth keeping
+    devices.extend(
+        link_keys
+            .into_iter()
+            .map(|(ieee_address, key)| DeviceBackup {
+                nwk_address: None,
+                ieee_address,
+                is_child: false,
+                link_key: Some(key),
+            }),
+    );
+    devices.sort_by_key(|d| d.ieee_address);
+
+    Ok(NetworkBackup {
+        metadata: BackupMetadata {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            source: format!("{}@{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            internal: serde_json::Map::from_iter([(
+                "znp_version".to_string(),
+                serde_json::Value::from(version as u8),
+            )]),
+        },
+        stack_specific: StackSpecific {
+            zstack: Some(ZStackSpecific { tclk_seed }),
+        },
+        coordinator_ieee,
+        pan_id: nib.pan_id(),
+        extended_pan_id: nib.extended_pan_id(),
+        nwk_update_id: nib.nwk_update_id(),
+        security_level: nib.security_level,
+        channel: nib.channel(),
+        channel_mask: ZIGBEE_CHANNELS
+            .filter(|channel| nib.channel_list & (1 << channel) != 0)
+            .collect(),
+        network_key: NetworkKeyBackup {
+            key: active_key.key,
+            sequence_number: active_key.key_seq_num,
+            frame_counter,
+        },
+        devices,
+    })
+}
+
+/// Writes the network state of `backup` over the one of a freshly formed network with the same
+/// parameters. The stack has to be reset for it to be picked up.
+pub async fn write_backup<S: SimpleSerial<SUnpiPacket>>(
+    nv: &NvMemoryAdapter<S>,
+    backup: &NetworkBackup,
+) -> Result<(), CoordinatorError> {
+    let channel_list = backup.channel_list()?;
+    let version = nv.znp_version().await?;
+    if version == ZnpVersion::ZStack12 {
+        return Err(CoordinatorError::UnsupportedFirmware);
+    }
+    let mut nib: Nib = match nv.read().await {
+        Ok(nib) => nib,
+        // The NIB length doesn't match any layout this crate knows
+        Err(NvMemoryAdapterError::Deku(_)) => return Err(CoordinatorError::UnsupportedFirmware),
+        Err(e) => return Err(e.into()),
+    };
+    let layout = nib.layout;
+
+    nib.set_pan_id(backup.pan_id);
+    nib.set_extended_pan_id(backup.extended_pan_id);
+    nib.set_channel(backup.channel)?;
+    nib.channel_list = channel_list;
+    nib.set_nwk_update_id(backup.nwk_update_id);
+    nib.security_level = backup.security_level;
+    nv.write(&nib).await?;
+    nv.write(&ExtAddr(backup.coordinator_ieee)).await?;
+
+    let key = NwkKeyDescriptor {
+        key_seq_num: backup.network_key.sequence_number,
+        key: backup.network_key.key,
+    };
+    nv.write(&NwkActiveKeyInfo(key.clone())).await?;
+    nv.write_raw(NvItemId::NwkAlternKeyInfo, &key.to_bytes()?)
+        .await?;
+    // Devices drop frames whose counter went backwards, so start well past the backed up one
+    let material = NwkSecMaterialDesc {
+        frame_counter: backup
+            .network_key
+            .frame_counter
+            .saturating_add(FRAME_COUNTER_MARGIN),
+        extended_pan_id: backup.extended_pan_id,
+    };
+    nv.write_table_entry(
+        NvItemId::ExNwkSecMaterialTable,
+        0,
+        &encode_entry(&material, layout)?,
+    )
+    .await?;
+
+    let seed = backup
+        .stack_specific
+        .zstack
+        .as_ref()
+        .and_then(|zstack| zstack.tclk_seed);
+    if let Some(seed) = seed {
+        nv.write_raw(NvItemId::TclkSeed, &seed).await?;
+    }
+
+    let mut addr_mgr = Vec::new();
+    let mut tclk_devices = Vec::new();
+    let mut unhashed_keys = Vec::new();
+    for device in &backup.devices {
+        let mut user = ADDR_MGR_USER_SECURITY;
+        if device.is_child {
+            user |= ADDR_MGR_USER_ASSOCIATE;
+        }
+        let index = addr_mgr.len() as u16;
+        addr_mgr.push(AddrMgrEntry {
+            user,
+            nwk_addr: device.nwk_address.unwrap_or(0xfffe),
+            ext_addr: device.ieee_address,
+        });
+        let Some(link_key) = &device.link_key else {
+            continue;
+        };
+        let shift = seed.and_then(|seed| {
+            (0..16)
+                .find(|&shift| hashed_link_key(&seed, &device.ieee_address, shift) == link_key.key)
+        });
+        match shift {
+            Some(shift) => tclk_devices.push(TclkDevEntry {
+                tx_frame_counter: link_key.tx_counter.saturating_add(FRAME_COUNTER_MARGIN),
+                rx_frame_counter: link_key.rx_counter,
+                ext_addr: device.ieee_address,
+                key_attributes: KEY_ATTRIBUTES_VERIFIED,
+                key_type: 0,
+                seed_shift_ic_index: shift,
+            }),
+            None => unhashed_keys.push((index, link_key)),
+        }
+    }
+
+    write_addr_mgr(nv, version, layout, &addr_mgr).await?;
+    for (index, device) in tclk_devices.iter().enumerate() {
+        nv.write_table_entry(
+            NvItemId::ExTclkTable,
+            index as u16,
+            &encode_entry(device, layout)?,
+        )
+        .await?;
+    }
+    if !unhashed_keys.is_empty() {
+        write_unhashed_link_keys(nv, layout, &unhashed_keys).await?;
+    }
+    Ok(())
+}
+
+async fn write_addr_mgr<S: SimpleSerial<SUnpiPacket>>(
+    nv: &NvMemoryAdapter<S>,
+    version: ZnpVersion,
+    layout: NvLayout,
+    entries: &[AddrMgrEntry],
+) -> Result<(), CoordinatorError> {
+    let id = NvItemId::ZcdNvExAddrmgr;
+    if id.max_entries(version) > 1 {
+        for (index, entry) in entries.iter().enumerate() {
+            nv.write_table_entry(id, index as u16, &encode_entry(entry, layout)?)
+                .await?;
+        }
+        return Ok(());
+    }
+    // The legacy table is a single item of fixed size
+    let length = nv.item_length(id).await?;
+    let mut table = Vec::new();
+    for entry in entries {
+        table.extend(encode_entry(entry, layout)?);
+    }
+    let empty = encode_entry(&AddrMgrEntry::empty(), layout)?;
+    while table.len() + empty.len() <= length {
+        table.extend_from_slice(&empty);
+    }
+    if table.len() != length {
+        return Err(CoordinatorError::Backup(format!(
+            "{} devices don't fit in the address manager table",
+            entries.len()
+        )));
+    }
+    Ok(nv.write_raw(id, &table).await?)
+}
+
+async fn write_unhashed_link_keys<S: SimpleSerial<SUnpiPacket>>(
+    nv: &NvMemoryAdapter<S>,
+    layout: NvLayout,
+    keys: &[(u16, &LinkKeyBackup)],
+) -> Result<(), CoordinatorError> {
+    let mut table = (keys.len() as u16).to_le_bytes().to_vec();
+    let first_key_id = u16::from(NvItemId::ApsLinkKeyDataStart);
+    for (i, (addr_mgr_index, key)) in keys.iter().enumerate() {
+        let data = ApsKeyDataEntry {
+            key: key.key,
+            tx_frame_counter: key.tx_counter.saturating_add(FRAME_COUNTER_MARGIN),
+            rx_frame_counter: key.rx_counter,
+        };
+        nv.write_table_entry(
+            NvItemId::ZcdNvExApsKeyDataTable,
+            i as u16,
+            &encode_entry(&data, layout)?,
+        )
+        .await?;
+        let entry = ApsLinkKeyTableEntry {
+            addr_mgr_index: *addr_mgr_index,
+            link_key_nv_id: first_key_id + i as u16,
+            authentication_state: AUTHENTICATED_CBCK,
+        };
+        table.extend(encode_entry(&entry, layout)?);
+    }
+    let length = nv.item_length(NvItemId::ApsLinkKeyTable).await?;
+    if length == 0 {
+        nv.init_item(NvItemId::ApsLinkKeyTable, table.len(), &table)
+            .await?;
+        return Ok(());
+    }
+    if table.len() > length {
+        return Err(CoordinatorError::Backup(format!(
+            "{} link keys don't fit in the link key table",
+            keys.len()
+        )));
+    }
+    table.resize(length, 0);
+    Ok(nv.write_raw(NvItemId::ApsLinkKeyTable, &table).await?)
+}
+
+// Keys of devices that were given their own link key, which `ApsLinkKeyTable` maps to an
+// address manager entry
+async fn read_unhashed_link_keys<S: SimpleSerial<SUnpiPacket>>(
+    nv: &NvMemoryAdapter<S>,
+    addr_mgr: &[AddrMgrEntry],
+    layout: NvLayout,
+) -> Result<Vec<([u8; 8], LinkKeyBackup)>, CoordinatorError> {
+    let table = match nv.read_raw(NvItemId::ApsLinkKeyTable).await {
+        Ok(table) => table,
+        Err(NvMemoryAdapterError::CommandStatus(CommandStatus::NvOperFailed)) => {
+            return Ok(Vec::new())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // The table starts with its number of entries
+    let mut cursor = Cursor::new(&table);
+    let mut reader = Reader::new(&mut cursor);
+    let count = u16::from_reader_with_ctx(&mut reader, deku::ctx::Endian::Little)?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        entries.push(ApsLinkKeyTableEntry::from_reader_with_ctx(
+            &mut reader,
+            layout,
+        )?);
+    }
+
+    let key_data: Vec<ApsKeyDataEntry> = parse_table(
+        &nv.read_table(NvItemId::ZcdNvExApsKeyDataTable).await?,
+        layout,
+    )?;
+    let first_key_id = u16::from(NvItemId::ApsLinkKeyDataStart);
+    Ok(entries
+        .iter()
+        .filter(|entry| entry.authentication_state == AUTHENTICATED_CBCK)
+        .filter_map(|entry| {
+            let device = addr_mgr.get(entry.addr_mgr_index as usize)?;
+            let key = key_data.get(entry.link_key_nv_id.checked_sub(first_key_id)? as usize)?;
+            Some((
+                device.ext_addr,
+                LinkKeyBackup {
+                    key: key.key,
+                    rx_counter: key.rx_frame_counter,
+                    tx_counter: key.tx_frame_counter,
+                },
+            ))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        subscription::SubscriptionService,
+        zstack::{nv_memory::entries::nib::NIB_PACKED_LEN, simulator::ZnpSimulator},
+    };
+    use futures::{executor::block_on, lock::Mutex};
+    use std::sync::Arc;
+
+    const DEVICE_A: [u8; 8] = [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8];
+    const DEVICE_B: [u8; 8] = [0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8];
+    const EXTENDED_PAN_ID: [u8; 8] = [8, 7, 6, 5, 4, 3, 2, 1];
+
+    // CC2531 on Z-Stack 3.0.x, every table in its legacy items
+    fn formed_network(simulator: &ZnpSimulator) {
+        let mut nib = Nib::from_nv_bytes(&[0; NIB_PACKED_LEN]).unwrap();
+        nib.set_pan_id(0x1a62);
+        nib.set_extended_pan_id(EXTENDED_PAN_ID);
+        nib.set_channel(15).unwrap();
+        nib.set_nwk_update_id(2);
+        nib.security_level = 5;
+        let set = |id: NvItemId, value: &[u8]| simulator.set_nv_item(id.into(), value);
+        set(NvItemId::NIB, &nib.to_nv_bytes().unwrap());
+        set(NvItemId::ExtAddr, &[4, 3, 2, 1, 0, 0x4b, 0x12, 0]);
+        set(
+            NvItemId::NwkActiveKeyInfo,
+            &[[1].as_slice(), &[0x11; 16]].concat(),
+        );
+
+        let material = |counter: u32, extended_pan_id: [u8; 8]| {
+            [counter.to_le_bytes().as_slice(), &extended_pan_id].concat()
+        };
+        set(
+            NvItemId::LegacyNwkSecMaterialTableStart,
+            &material(5, [0xff; 8]),
+        );
+        simulator.set_nv_item(0x0076, &material(1000, EXTENDED_PAN_ID));
+
+        set(NvItemId::TclkSeed, &[0x5a; 16]);
+        let tclk = [
+            10u32.to_le_bytes().as_slice(),
+            &20u32.to_le_bytes(),
+            &DEVICE_A,
+            &[2, 0, 3],
+        ]
+        .concat();
+        set(NvItemId::LegacyTclkTableStart, &tclk);
+        simulator.set_nv_item(0x0112, &[0; 19]);
+
+        let addr_mgr = [
+            [0x03, 0x34, 0x12].as_slice(),
+            &DEVICE_A,
+            &[0x02, 0x78, 0x56],
+            &DEVICE_B,
+            &[0x00, 0xfe, 0xff],
+            &[0xff; 8],
+        ]
+        .concat();
+        set(NvItemId::AddrMgr, &addr_mgr);
+        set(NvItemId::ApsLinkKeyTable, &[1, 0, 1, 0, 0x01, 0x02, 0x01]);
+        let key_data = [
+            [0x22; 16].as_slice(),
+            &7u32.to_le_bytes(),
+            &8u32.to_le_bytes(),
+        ]
+        .concat();
+        set(NvItemId::ApsLinkKeyDataStart, &key_data);
+    }
+
+    #[test]
+    fn test_backup_of_legacy_tables() {
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
+        let simulator = ZnpSimulator::new(subscriptions.clone());
+        formed_network(&simulator);
+        let nv = NvMemoryAdapter::new(Arc::new(Mutex::new(simulator)), subscriptions).unwrap();
+
+        let backup = block_on(read_backup(&nv)).unwrap();
+        assert_eq!(backup.network_key.frame_counter, 1000);
+        assert_eq!(backup.devices.len(), 2);
+        assert_eq!(
+            backup.devices[0].link_key,
+            Some(LinkKeyBackup {
+                key: hashed_link_key(&[0x5a; 16], &DEVICE_A, 3),
+                rx_counter: 20,
+                tx_counter: 10,
+            })
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&backup.to_json().unwrap()).unwrap();
+        assert_eq!(json["metadata"]["format"], BACKUP_FORMAT);
+        assert_eq!(json["metadata"]["version"], 1);
+        assert_eq!(json["coordinator_ieee"], "00124b0001020304");
+        assert_eq!(json["pan_id"], "1a62");
+        assert_eq!(json["extended_pan_id"], "0102030405060708");
+        assert_eq!(json["channel"], 15);
+        assert_eq!(json["channel_mask"], serde_json::json!([15]));
+        assert_eq!(json["nwk_update_id"], 2);
+        assert_eq!(json["security_level"], 5);
+        assert_eq!(json["network_key"]["key"], "11".repeat(16));
+        assert_eq!(json["network_key"]["sequence_number"], 1);
+        assert_eq!(
+            json["stack_specific"]["zstack"]["tclk_seed"],
+            "5a".repeat(16)
+        );
+        let devices = json["devices"].as_array().unwrap();
+        assert_eq!(devices[0]["ieee_address"], "a8a7a6a5a4a3a2a1");
+        assert_eq!(devices[0]["nwk_address"], "1234");
+        assert_eq!(devices[0]["is_child"], true);
+        assert_eq!(devices[1]["ieee_address"], "b8b7b6b5b4b3b2b1");
+        assert_eq!(devices[1]["is_child"], false);
+        assert_eq!(devices[1]["link_key"]["key"], "22".repeat(16));
+        assert_eq!(devices[1]["link_key"]["tx_counter"], 7);
+        assert_eq!(devices[1]["link_key"]["rx_counter"], 8);
+    }
+
+    #[test]
+    fn test_backup_with_channel_outside_the_band() {
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
+        let simulator = ZnpSimulator::new(subscriptions.clone());
+        formed_network(&simulator);
+        let nv = NvMemoryAdapter::new(Arc::new(Mutex::new(simulator)), subscriptions).unwrap();
+        let json = block_on(read_backup(&nv)).unwrap().to_json().unwrap();
+        assert!(NetworkBackup::from_json(&json).is_ok());
+
+        let mut json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        json["channel_mask"] = serde_json::json!([15, 40]);
+        assert!(matches!(
+            NetworkBackup::from_json(&json.to_string()),
+            Err(CoordinatorError::Backup(_))
+        ));
+
+        // Built by hand, the backup only fails when written, before anything is touched
+        let mut backup = block_on(read_backup(&nv)).unwrap();
+        backup.channel_mask = vec![15, 40];
+        let nib: Nib = block_on(nv.read()).unwrap();
+        assert!(matches!(
+            block_on(write_backup(&nv, &backup)),
+            Err(CoordinatorError::InvalidChannel)
+        ));
+        assert_eq!(block_on(nv.read::<Nib>()).unwrap(), nib);
+    }
+}
@@ -1,24 +1,74 @@
 use super::{
-    nv_memory::nv_item::NvMemoryAdapter,
+    backup::{read_backup, write_backup, NetworkBackup},
+    nv_memory::{
+        entries::{nib::Nib, ChanList, ExtendedPanId, PanId, PreCfgKey},
+        nv_item::{NvMemoryAdapter, NvMemoryAdapterError},
+        NvItemId,
+    },
     unpi::{
-        commands::{CommandRequest, CommandResponse},
+        buffer::Buffer,
+        commands::{CommandIeeeAddress, CommandRequest, CommandResponse},
         serial::{request, request_with_reply},
         subsystems::{
-            sys::{VersionRequest, VersionResponse},
-            zdo::TcDeviceIndexRequest,
+            af::{
+                DataConfirmRequest, DataRequestExtRequest, DataRequestExtResponse,
+                DataRequestRequest, DataRequestResponse, IncomingMessage, IncomingMsgExtRequest,
+                IncomingMsgRequest, RegisterRequest, RegisterResponse, TransactionIdGenerator,
+            },
+            app_cnf::{
+                BdbAddInstallCodeRequest, BdbAddInstallCodeResponse, BdbSetChannelRequest,
+                BdbSetChannelResponse, BdbStartCommissioningRequest, BdbStartCommissioningResponse,
+            },
+            sys::{ResetIndRequest, VersionRequest, VersionResponse},
+            zdo::{
+                ActiveEndpointsRequest, ActiveEndpointsRspRequest, BindRequest, BindRspRequest,
+                EndDeviceAnnounceIndRequest, ExtAddGroupRequest, ExtAddGroupResponse,
+                ExtFindAllGroupsEndpointRequest, ExtFindAllGroupsEndpointResponse,
+                ExtRemoveGroupRequest, ExtRemoveGroupResponse, IeeeAddressRequest,
+                IeeeAddressRspRequest, LeaveIndRequest, ManagementBindRequest,
+                ManagementBindRspRequest, ManagementLeaveRequest, ManagementLeaveRspRequest,
+                ManagementLqiRequest, ManagementLqiRspRequest, ManagementRoutingRequest,
+                ManagementRoutingRspRequest, NeighborLqi, NetworkAddressRequest,
+                NetworkAddressRspRequest, NodeDescriptorRequest, NodeDescriptorRspRequest,
+                PermitJoinIndRequest, RoutingEntry, SecDeviceRemoveRequest,
+                SecDeviceRemoveResponse, SimpleDescriptorRequest, SimpleDescriptorRspRequest,
+                SourceRouteIndRequest, TcDeviceIndexRequest, UnbindRequest, UnbindRspRequest,
+            },
         },
     },
 };
 use crate::{
+    broadcast::{Broadcast, Lagged},
     coordinator::{
-        AddressMode, Coordinator, CoordinatorError, LedStatus, OnEvent, ResetType, ZigbeeEvent,
+        AddressMode, BindingEntry, Coordinator, CoordinatorError, Either, JoinPolicy, LedStatus,
+        NetworkOptions, OnEvent, PermitJoin, RemoveOptions, ResetType, ZigbeeEvent,
+        ZIGBEE_CHANNELS,
     },
-    serial::{simple_serial_port::SimpleSerialPort, SimpleSerial},
+    devices::{DeviceDescription, DeviceRegistry, Endpoint, PowerSource},
+    serial::{simple_serial_port::SimpleSerialPort, tcp_serial_port::TcpSerialPort, SimpleSerial},
     subscription::{Event, Predicate, Subscription, SubscriptionService},
+    topology::Topology,
     utils::{info, trace, warn},
+    zcl::{
+        AttributeRecord, GroupResponse, ZclFrame, BASIC_CLUSTER, BASIC_MANUFACTURER_NAME,
+        BASIC_MODEL_IDENTIFIER,
+    },
     zstack::unpi::{
-        constants::{af, CommandStatus},
-        serial::wait_for,
+        constants::{
+            af,
+            app_cnf::{CommissioningMode, InstallCodeFormat},
+            sys::ZnpVersion,
+            zdo::{
+                AddressRequestType, DeviceState, LogicalType, StartupStatus, LEAVE_OPTION_REJOIN,
+                LEAVE_OPTION_REMOVE_CHILDREN, MAC_CAPABILITY_MAINS_POWERED,
+                STARTUP_OPTION_CLEAR_CONFIG, STARTUP_OPTION_CLEAR_STATE,
+            },
+            CommandStatus, NoCommandStatusError,
+        },
+        serial::{
+            subscribe_for, subscribe_for_any_matching, subscribe_for_matching, wait_for,
+            UnpiCommandError,
+        },
         subsystems::{
             sys::{PingRequest, PingResponse, ResetRequest, StackTuneRequest},
             util::{GetDeviceInfoRequest, GetDeviceInfoResponse, LedControlRequest},
@@ -31,11 +81,38 @@ use crate::{
     },
 };
 use deku::{DekuContainerRead, DekuReader, DekuWriter};
-use futures::{executor::block_on, lock::Mutex};
-use std::{ops::Deref, sync::Arc};
+use futures::{executor::block_on, lock::Mutex, Stream, StreamExt};
+#[cfg(feature = "psila")]
+use ieee802154::mac::ExtendedAddress;
+use std::{
+    collections::{HashSet, VecDeque},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-//TODO: fix this
-const MAXIMUM_ZIGBEE_PAYLOAD_SIZE: usize = 255;
+// Endpoint ZCL frames are sent from when the caller doesn't pick one
+const DEFAULT_SOURCE_ENDPOINT: u32 = 1;
+// Route discoveries attempted before giving up on a frame
+const ZCL_SEND_RETRIES: usize = 2;
+// Time the stack takes to reboot after a reset
+const RESET_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+// Forming or joining a network can take a while before the state changes
+const STARTUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+// Attempts at a request to a device before giving up, sleepy end devices only hear it when
+// they poll their parent
+const DEVICE_REQUEST_ATTEMPTS: usize = 3;
+const DEFAULT_DEVICE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+// Events each stream of `subscribe_events` holds before dropping the oldest
+const EVENT_STREAM_CAPACITY: usize = 64;
+// Longest window a single permit join request opens, the stack reads 255 as no end at all
+const PERMIT_JOIN_MAX_SECONDS: u8 = 254;
+// How long before a window runs out it is extended, so joining never closes in between
+const PERMIT_JOIN_REISSUE_MARGIN: Duration = Duration::from_secs(5);
 
 pub struct CC253X<S: SimpleSerial<SUnpiPacket>> {
     _supports_led: Option<bool>,
@@ -43,48 +120,166 @@ pub struct CC253X<S: SimpleSerial<SUnpiPacket>> {
     subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
     // Send data directly to serial here, but for reading we use the subscription service above
     serial: Arc<Mutex<S>>,
-    on_zigbee_event: Arc<Mutex<Option<OnEvent>>>,
+    on_zigbee_event: Arc<std::sync::Mutex<Option<OnEvent>>>,
+    // Updated from every event before it reaches `on_zigbee_event`
+    devices: Arc<DeviceRegistry>,
+    events: Arc<Broadcast<ZigbeeEvent>>,
+    // Correlates AF data requests with their AF_DATA_CONFIRM
+    transaction_ids: TransactionIdGenerator,
+    // Sequence numbers of the ZCL frames we build ourselves
+    zcl_sequence: TransactionIdGenerator,
+    // How long a device gets to answer each attempt of a ZDO request or attribute read
+    device_timeout: Arc<std::sync::Mutex<std::time::Duration>>,
+    // Checked on every join, from the transport's read thread
+    join_policy: Arc<std::sync::Mutex<JoinPolicy>>,
+    // Dropping the sender stops the thread extending the current join window
+    join_window: std::sync::Mutex<Option<mpsc::Sender<()>>>,
+    // Set once `permit_join` reports the join windows, the stack's indications only echo it then
+    join_window_reported: Arc<AtomicBool>,
     pub nv_adapter: NvMemoryAdapter<S>,
 }
 
 impl CC253X<SimpleSerialPort<SUnpiPacket>> {
     pub async fn from_simple_serial(path: &str, baud_rate: u32) -> Result<Self, CoordinatorError> {
-        let on_zigbee_event = Arc::new(Mutex::new(Option::<OnEvent>::None));
         let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
         let serial = SimpleSerialPort::new(path, baud_rate, subscriptions.clone())?;
+        Self::new(serial, subscriptions).await
+    }
+}
+
+impl CC253X<TcpSerialPort<SUnpiPacket>> {
+    /// Connects to a dongle shared over the network, `path` is `tcp://host:port`
+    pub async fn from_tcp(path: &str) -> Result<Self, CoordinatorError> {
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
+        let serial = TcpSerialPort::new(path, subscriptions.clone())?;
+        Self::new(serial, subscriptions).await
+    }
+}
+
+impl<S: SimpleSerial<SUnpiPacket> + Send + 'static> CC253X<S> {
+    /// Builds a coordinator on top of any transport. The transport must dispatch every packet it
+    /// reads to `subscriptions`, which is where the coordinator listens for replies and callbacks.
+    pub async fn new(
+        serial: S,
+        subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+    ) -> Result<Self, CoordinatorError> {
+        let serial = Arc::new(Mutex::new(serial));
+        let on_zigbee_event = Arc::new(std::sync::Mutex::new(Option::<OnEvent>::None));
+        let devices = Arc::new(DeviceRegistry::default());
+        let events = Arc::new(Broadcast::new());
+        let join_policy = Arc::new(std::sync::Mutex::new(JoinPolicy::default()));
+        let device_timeout = Arc::new(std::sync::Mutex::new(DEFAULT_DEVICE_TIMEOUT));
+        let join_window_reported = Arc::new(AtomicBool::new(false));
 
         let on_zigbee_event_clone = on_zigbee_event.clone();
+        let devices_clone = devices.clone();
+        let events_clone = events.clone();
+        let join_policy_clone = join_policy.clone();
+        let join_window_reported_clone = join_window_reported.clone();
+        // The transport owns the subscriptions, strong references would keep it alive forever
+        let serial_weak = Arc::downgrade(&serial);
+        let subscriptions_weak = Arc::downgrade(&subscriptions);
+        let devices_link = devices.clone();
+        let events_link = events.clone();
+        let on_zigbee_event_link = on_zigbee_event.clone();
+        let device_timeout_link = device_timeout.clone();
+        let link = move || {
+            Some(DeviceLink {
+                serial: serial_weak.upgrade()?,
+                subscriptions: subscriptions_weak.upgrade()?,
+                devices: devices_link.clone(),
+                events: events_link.clone(),
+                on_zigbee_event: on_zigbee_event_link.clone(),
+                device_timeout: device_timeout_link.clone(),
+            })
+        };
         subscriptions.lock().await.subscribe(Subscription::Event(
             Predicate(Box::new(|packet: &SUnpiPacket| {
                 packet.type_subsystem == (MessageType::AREQ, Subsystem::Zdo)
-                    && packet.command == TcDeviceIndexRequest::id()
             })),
             Event(Box::new(move |packet: &SUnpiPacket| {
-                let a = async {
-                    if let Some(on_zigbee_event) = on_zigbee_event_clone.lock().await.deref() {
-                        (on_zigbee_event)(ZigbeeEvent::DeviceAnnounce {
-                            network_address: packet.payload[0] as u16,
-                            ieee_address: packet.payload[1..9].try_into().unwrap(),
-                        })
-                        .unwrap();
+                // Runs on the transport's read thread, so no blocking on async locks here
+                match zdo_event(packet) {
+                    Ok(Some(ZigbeeEvent::PermitJoin(permit)))
+                        if join_window_reported_clone.load(Ordering::Relaxed) =>
+                    {
+                        trace!("join window already reported, ignoring {:?}", permit)
+                    }
+                    Ok(Some(event)) => {
+                        let Some(event) = enforce_join_policy(&join_policy_clone, &link, event)
+                        else {
+                            return;
+                        };
+                        dispatch(&devices_clone, &events_clone, &on_zigbee_event_clone, event)
+                    }
+                    Ok(None) => trace!("ignoring zdo callback {}", packet.command),
+                    Err(e) => warn!("discarding malformed zdo callback: {:?}", e),
+                }
+            })),
+        ));
+
+        let on_zigbee_event_clone = on_zigbee_event.clone();
+        let devices_clone = devices.clone();
+        let events_clone = events.clone();
+        subscriptions.lock().await.subscribe(Subscription::Event(
+            Predicate(Box::new(|packet: &SUnpiPacket| {
+                packet.type_subsystem == (MessageType::AREQ, Subsystem::Af)
+                    && (packet.command == IncomingMsgRequest::id()
+                        || packet.command == IncomingMsgExtRequest::id())
+            })),
+            Event(Box::new(move |packet: &SUnpiPacket| {
+                let message = match incoming_message(packet) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("discarding malformed incoming message: {:?}", e);
+                        return;
                     }
                 };
-                block_on(a);
+                dispatch(
+                    &devices_clone,
+                    &events_clone,
+                    &on_zigbee_event_clone,
+                    ZigbeeEvent::IncomingMessage(message),
+                );
             })),
         ));
 
-        let serial = Arc::new(Mutex::new(serial));
         Ok(Self {
             serial: serial.clone(),
             _supports_led: None,
             subscriptions: subscriptions.clone(),
             on_zigbee_event,
+            devices,
+            events,
+            transaction_ids: TransactionIdGenerator::new(),
+            zcl_sequence: TransactionIdGenerator::new(),
+            device_timeout,
+            join_policy,
+            join_window: std::sync::Mutex::new(None),
+            join_window_reported,
             nv_adapter: NvMemoryAdapter::new(serial, subscriptions)?,
         })
     }
-}
 
-impl<S: SimpleSerial<SUnpiPacket>> CC253X<S> {
+    pub fn subscriptions(&self) -> Arc<Mutex<SubscriptionService<SUnpiPacket>>> {
+        self.subscriptions.clone()
+    }
+
+    /// Devices seen on the network. They are only kept in memory until a store is opened with
+    /// [`DeviceRegistry::open`].
+    pub fn devices(&self) -> Arc<DeviceRegistry> {
+        self.devices.clone()
+    }
+
+    /// Every event from now on, for as many listeners as needed. A listener that doesn't keep
+    /// up loses the oldest events and gets a [`ZigbeeEvent::Lagged`] with how many instead.
+    pub fn subscribe_events(&self) -> impl Stream<Item = ZigbeeEvent> {
+        self.events
+            .subscribe(EVENT_STREAM_CAPACITY)
+            .map(|event| event.unwrap_or_else(|Lagged(missed)| ZigbeeEvent::Lagged(missed)))
+    }
+
     // helper proxy function
     pub async fn request<R: CommandRequest + DekuWriter>(
         &self,
@@ -95,7 +290,7 @@ impl<S: SimpleSerial<SUnpiPacket>> CC253X<S> {
     }
 
     // helper proxy function
-    async fn wait_for(
+    pub async fn wait_for(
         &self,
         command_id: u8,
         message_type: MessageType,
@@ -130,183 +325,1274 @@ impl<S: SimpleSerial<SUnpiPacket>> CC253X<S> {
         .await?)
     }
 
-    pub async fn begin_startup(&self) -> Result<StartupFromAppResponse, CoordinatorError> {
-        info!("beginning startup...");
-        let command = StateChangedIndRequest { state: 0 };
+    /// Registers an application endpoint, needed before sending or receiving data on it
+    pub async fn register_endpoint(
+        &self,
+        request: &RegisterRequest,
+    ) -> Result<(), CoordinatorError> {
+        info!("registering endpoint {}", request.endpoint);
+        let response: RegisterResponse = self.request_with_reply(request, None).await?;
+        ensure_success(response.try_into()?)
+    }
 
-        let wait = self.wait_for(command.self_id(), MessageType::AREQ, Subsystem::Zdo, None);
-        let send = self.request(&StartupFromAppRequest {
-            start_delay: 100,
-            status: 0,
-        });
-        let r: StartupFromAppResponse = futures::try_join!(send, wait).map(|(_, packet)| {
-            info!("reading filling command: {:?}", command);
-            //command.read_and_fill(packet.payload.as_slice());
-            packet.to_command_response()
-        })??;
-        let c = TryInto::<CommandStatus>::try_into(r.clone())?;
-        if c != CommandStatus::Success {
-            return Err(CoordinatorError::CommandStatusFailure(c));
+    pub fn next_transaction_id(&self) -> u8 {
+        self.transaction_ids.next_id()
+    }
+
+    /// Time a device gets to answer a request before it's sent again. Sleepy end devices with
+    /// a long poll period need more than the default 10 seconds.
+    pub fn set_device_timeout(&self, timeout: std::time::Duration) {
+        *self.device_timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn device_timeout(&self) -> std::time::Duration {
+        *self.device_timeout.lock().unwrap()
+    }
+
+    /// Devices joining from now on are checked against `policy`. A refused device gets a
+    /// [`ZigbeeEvent::DeviceRejected`] instead of [`ZigbeeEvent::DeviceJoined`] and is asked to
+    /// leave right away. Devices already on the network are left alone.
+    pub fn set_join_policy(&self, policy: JoinPolicy) {
+        *self.join_policy.lock().unwrap() = policy;
+    }
+
+    pub fn join_policy(&self) -> JoinPolicy {
+        self.join_policy.lock().unwrap().clone()
+    }
+
+    /// Lets a device join with the link key derived from its install code, which is what is
+    /// printed on the device: 16 bytes followed by their CRC, least significant byte first
+    pub async fn add_install_code(
+        &self,
+        ieee_address: [u8; 8],
+        install_code: &[u8],
+    ) -> Result<(), CoordinatorError> {
+        let [code @ .., crc_low, crc_high] = install_code else {
+            return Err(CoordinatorError::InvalidInstallCode);
+        };
+        if code.len() != 16 || install_code_crc(code) != u16::from_le_bytes([*crc_low, *crc_high]) {
+            return Err(CoordinatorError::InvalidInstallCode);
         }
-        Ok(r)
+        self.send_install_code(
+            InstallCodeFormat::InstallCodeAndCrc,
+            ieee_address,
+            install_code,
+        )
+        .await
+    }
+
+    /// Lets a device join with a link key already derived from its install code
+    pub async fn add_install_code_key(
+        &self,
+        ieee_address: [u8; 8],
+        key: [u8; 16],
+    ) -> Result<(), CoordinatorError> {
+        self.send_install_code(InstallCodeFormat::DerivedKey, ieee_address, &key)
+            .await
     }
-}
 
-impl<S: SimpleSerial<SUnpiPacket>> Coordinator for CC253X<S> {
-    type ZclFrame = psila_data::cluster_library::ClusterLibraryHeader;
+    async fn send_install_code(
+        &self,
+        format: InstallCodeFormat,
+        ieee_address: [u8; 8],
+        install_code: &[u8],
+    ) -> Result<(), CoordinatorError> {
+        // Install codes came with the base device behavior of Z-Stack 3
+        if self.nv_adapter.znp_version().await? == ZnpVersion::ZStack12 {
+            return Err(CoordinatorError::UnsupportedFirmware);
+        }
+        let response: BdbAddInstallCodeResponse = self
+            .request_with_reply(
+                &BdbAddInstallCodeRequest {
+                    install_code_format: format as u8,
+                    ieee_address: CommandIeeeAddress { ieee_address },
+                    install_code: Buffer::try_from(install_code)?,
+                },
+                None,
+            )
+            .await?;
+        ensure_success(response.try_into()?)
+    }
 
-    type ZclPayload<'a> = ([u8; MAXIMUM_ZIGBEE_PAYLOAD_SIZE], usize);
+    /// Asks a device to leave the network, waits until it's gone and forgets it, link key
+    /// included. A device told to rejoin keeps its link key and stays in the registry. When the
+    /// device can't be told, asleep, already gone or refusing, it's removed on our side only.
+    pub async fn remove_device(
+        &self,
+        ieee_address: [u8; 8],
+        network_address: u16,
+        options: RemoveOptions,
+    ) -> Result<(), CoordinatorError> {
+        self.link()
+            .remove_device(ieee_address, network_address, options)
+            .await
+    }
 
-    type IeeAddress = ieee802154::mac::Address;
+    // Extends a join window that outlasts a single permit join request from a thread of its
+    // own, then reports it closed once it ran out. Stops as soon as `cancelled` is dropped.
+    fn keep_join_window(
+        &self,
+        duration: Duration,
+        network_address: Option<u16>,
+        cancelled: mpsc::Receiver<()>,
+    ) {
+        let serial = self.serial.clone();
+        let devices = self.devices.clone();
+        let events = self.events.clone();
+        let on_zigbee_event = self.on_zigbee_event.clone();
+        std::thread::spawn(move || {
+            let opened = Instant::now();
+            let wait_until = |offset: Duration| {
+                let timeout = opened
+                    .checked_add(offset)
+                    .map_or(Duration::MAX, |deadline| {
+                        deadline.saturating_duration_since(Instant::now())
+                    });
+                matches!(
+                    cancelled.recv_timeout(timeout),
+                    Err(RecvTimeoutError::Timeout)
+                )
+            };
+            let window = |permit| {
+                dispatch(
+                    &devices,
+                    &events,
+                    &on_zigbee_event,
+                    ZigbeeEvent::PermitJoin(permit),
+                )
+            };
+            for (offset, seconds) in permit_join_schedule(duration).skip(1) {
+                if !wait_until(offset) {
+                    return;
+                }
+                trace!("extending the join window by {} seconds", seconds);
+                let command = permit_join_request(network_address, seconds);
+                let sent =
+                    SUnpiPacket::from_command_owned(super::unpi::LenTypeInfo::OneByte, &command)
+                        .and_then(|packet| {
+                            block_on(request::<ManagementPermitJoinRequest, S>(
+                                &packet,
+                                serial.clone(),
+                            ))
+                        });
+                if let Err(e) = sent {
+                    warn!("could not extend the join window: {:?}", e);
+                }
+                window(PermitJoin::For(duration - offset));
+            }
+            if wait_until(duration) {
+                window(PermitJoin::Close);
+            }
+        });
+    }
 
-    async fn start(&self) -> Result<(), CoordinatorError> {
-        if let Some(attempt) = (0..3).next() {
-            trace!("pinging coordinator attempt number {:?}", attempt);
-            let _ping: PingResponse = self.request_with_reply(&PingRequest {}, None).await?;
-            trace!("ping successful");
-            let version = self.version().await?;
-            info!("coordinator version: {:?}", version);
-            return Ok(());
+    /// Sends application data and waits until the stack confirms it was delivered to the next
+    /// hop. The request's transaction ID is what matches it with its AF_DATA_CONFIRM, so take it
+    /// from [`Self::next_transaction_id`].
+    pub async fn data_request(
+        &self,
+        request: &DataRequestRequest,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<DataConfirmRequest, CoordinatorError> {
+        self.request_with_confirm::<_, DataRequestResponse>(
+            request,
+            request.transaction_id,
+            timeout,
+        )
+        .await
+    }
+
+    /// Same as [`Self::data_request`] but with 64 bit, group or inter-PAN addressing
+    pub async fn data_request_ext(
+        &self,
+        request: &DataRequestExtRequest,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<DataConfirmRequest, CoordinatorError> {
+        self.request_with_confirm::<_, DataRequestExtResponse>(
+            request,
+            request.transaction_id,
+            timeout,
+        )
+        .await
+    }
+
+    async fn request_with_confirm<
+        R: CommandRequest + DekuWriter,
+        Res: CommandResponse
+            + for<'de> DekuReader<'de>
+            + for<'de> DekuContainerRead<'de>
+            + TryInto<CommandStatus, Error = NoCommandStatusError>,
+    >(
+        &self,
+        command: &R,
+        transaction_id: u8,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<DataConfirmRequest, CoordinatorError> {
+        // Subscribe before sending, the confirm can arrive right after the SRSP
+        let confirm = subscribe_for_matching(
+            DataConfirmRequest::id(),
+            MessageType::AREQ,
+            Subsystem::Af,
+            move |packet| packet.payload.get(2) == Some(&transaction_id),
+            self.subscriptions.clone(),
+        )
+        .await;
+        let accepted = match self.request_with_reply::<_, Res>(command, None).await {
+            Ok(response) => response.try_into().map_err(CoordinatorError::from),
+            Err(e) => Err(e),
+        }
+        .and_then(ensure_success);
+        if let Err(e) = accepted {
+            self.subscriptions.lock().await.unsubscribe(confirm.id);
+            return Err(e);
         }
-        Err(CoordinatorError::CoordinatorOpen)
+        let confirm: DataConfirmRequest = confirm
+            .wait(self.subscriptions.clone(), timeout)
+            .await?
+            .to_command_request()?;
+        ensure_success(confirm.status.try_into()?)?;
+        Ok(confirm)
     }
 
-    async fn stop(&self) -> Result<(), CoordinatorError> {
+    /// Brings up a network with the given parameters. A network that already matches them is
+    /// restored as is, anything else is wiped and formed again through BDB commissioning.
+    pub async fn form_network(&self, options: &NetworkOptions) -> Result<(), CoordinatorError> {
+        let channels = &options.channel_list;
+        if channels.is_empty() || !channels.iter().all(|c| ZIGBEE_CHANNELS.contains(c)) {
+            return Err(CoordinatorError::InvalidChannel);
+        }
+        if self.network_matches(options).await? {
+            info!("network already matches the requested configuration, restoring it");
+            self.begin_startup().await?;
+            return Ok(());
+        }
+        // BDB commissioning came with Z-Stack 3
+        if self.nv_adapter.znp_version().await? == ZnpVersion::ZStack12 {
+            return Err(CoordinatorError::UnsupportedFirmware);
+        }
+
+        info!("forming network with pan id {:#06x}", options.pan_id);
+        // Back to the factory configuration so nothing from the previous network survives
+        self.nv_adapter
+            .write_raw(
+                NvItemId::StartupOption,
+                &[STARTUP_OPTION_CLEAR_CONFIG | STARTUP_OPTION_CLEAR_STATE],
+            )
+            .await?;
+        self.reset_and_wait(ResetType::Soft).await?;
+
+        let nv = &self.nv_adapter;
+        nv.write_raw(NvItemId::LogicalType, &[LogicalType::Coordinator as u8])
+            .await?;
+        nv.write(&PanId(options.pan_id)).await?;
+        nv.write(&ExtendedPanId(options.extended_pan_id)).await?;
+        nv.write(&ChanList(options.channel_mask())).await?;
+        nv.write(&PreCfgKey(options.network_key)).await?;
+        nv.write_raw(
+            NvItemId::PrecfgkeysEnable,
+            &[options.network_key_distribute as u8],
+        )
+        .await?;
+
+        for (is_primary, channel) in [(1, options.channel_mask()), (0, 0)] {
+            let r: BdbSetChannelResponse = self
+                .request_with_reply(
+                    &BdbSetChannelRequest {
+                        is_primary,
+                        channel,
+                    },
+                    None,
+                )
+                .await?;
+            ensure_success(r.try_into()?)?;
+        }
+
+        let formed = subscribe_for_matching(
+            StateChangedIndRequest::id(),
+            MessageType::AREQ,
+            Subsystem::Zdo,
+            |packet| packet.payload.first() == Some(&(DeviceState::Coordinator as u8)),
+            self.subscriptions.clone(),
+        )
+        .await;
+        let started = self
+            .start_commissioning(CommissioningMode::NetworkFormation)
+            .await;
+        if let Err(e) = started {
+            self.subscriptions.lock().await.unsubscribe(formed.id);
+            return Err(e);
+        }
+        formed
+            .wait(self.subscriptions.clone(), Some(STARTUP_TIMEOUT))
+            .await?;
+        self.start_commissioning(CommissioningMode::NetworkSteering)
+            .await?;
+
+        if !self.network_matches(options).await? {
+            return Err(CoordinatorError::NetworkFormationFailed);
+        }
+        info!("network formed");
         Ok(())
     }
 
-    async fn is_inter_pan_mode(&self) -> bool {
-        warn!("is_inter_pan_mode not implemented, assuming false");
-        false
+    /// Exports the network in the Open Coordinator Backup format, see
+    /// [`NetworkBackup::to_json`]
+    pub async fn backup(&self) -> Result<NetworkBackup, CoordinatorError> {
+        read_backup(&self.nv_adapter).await
     }
 
-    async fn version(&self) -> Result<VersionResponse, CoordinatorError> {
-        let version: VersionResponse = self.request_with_reply(&VersionRequest {}, None).await?;
-        Ok(version)
+    /// Moves the network of `backup` onto this coordinator: a network with the same parameters
+    /// is formed, its NV state replaced by the backed up one and the stack restarted. Devices
+    /// keep working without being paired again.
+    pub async fn restore(&self, backup: &NetworkBackup) -> Result<(), CoordinatorError> {
+        // Z-Stack 1.2 has neither the TCLK seed nor the tables the backup is written to
+        if self.nv_adapter.znp_version().await? == ZnpVersion::ZStack12 {
+            return Err(CoordinatorError::UnsupportedFirmware);
+        }
+        let options = backup.network_options();
+        self.form_network(&options).await?;
+        write_backup(&self.nv_adapter, backup).await?;
+        self.reset_and_wait(ResetType::Soft).await?;
+        self.begin_startup().await?;
+        if !self.network_matches(&options).await? {
+            return Err(CoordinatorError::NetworkFormationFailed);
+        }
+        info!("network restored");
+        Ok(())
     }
 
-    async fn reset(&self, reset_type: ResetType) -> Result<(), CoordinatorError> {
-        trace!("reset with reset type {:?}", reset_type);
-        self.request(&ResetRequest { reset_type }).await?;
-        Ok::<(), CoordinatorError>(())
-    }
+    /// Asks a device what it is: its node descriptor, its endpoints and their simple
+    /// descriptors, then its manufacturer and model from the Basic cluster. The device registry
+    /// keeps the result when it knows the device.
+    pub async fn interview(
+        &self,
+        network_address: u16,
+    ) -> Result<DeviceDescription, CoordinatorError> {
+        info!("interviewing {:#06x}", network_address);
+        let node: NodeDescriptorRspRequest = self
+            .zdo_request(
+                &NodeDescriptorRequest {
+                    destination_address: network_address,
+                    network_address_of_interest: network_address,
+                },
+                network_address,
+            )
+            .await?;
+        // The logical type shares its byte with the complex and user descriptor flags
+        let logical_type = LogicalType::from_type(node.logical_type_complex_user_descriptor & 0x07)
+            .ok_or(CoordinatorError::InvalidResponse)?;
+        let power_source = if node.mac_capabilities & MAC_CAPABILITY_MAINS_POWERED != 0 {
+            PowerSource::Mains
+        } else {
+            PowerSource::Battery
+        };
 
-    async fn set_led(&self, led_status: LedStatus) -> Result<(), CoordinatorError> {
-        trace!("setting LED to {:?}", led_status);
-        //TODO: const firmwareControlsLed = parseInt(this.version.revision) >= 20211029;
-        let firmware_controls_led = true;
+        let active: ActiveEndpointsRspRequest = self
+            .zdo_request(
+                &ActiveEndpointsRequest {
+                    destination_address: network_address,
+                    network_address_of_interest: network_address,
+                },
+                network_address,
+            )
+            .await?;
+        let mut endpoints = Vec::new();
+        for endpoint in active.endpoints.items {
+            let simple: SimpleDescriptorRspRequest = self
+                .zdo_request(
+                    &SimpleDescriptorRequest {
+                        destination_address: network_address,
+                        network_address_of_interest: network_address,
+                        endpoint,
+                    },
+                    network_address,
+                )
+                .await?;
+            endpoints.push(Endpoint {
+                id: simple.endpoint,
+                profile_id: simple.profile_id,
+                device_id: simple.device_id,
+                input_clusters: simple.input_clusters.items,
+                output_clusters: simple.output_clusters.items,
+            });
+        }
 
-        let command = match led_status {
-            LedStatus::Disable => {
-                if firmware_controls_led {
-                    LedControlRequest {
-                        led_id: 0xff,
-                        mode: 0,
-                    }
-                } else {
-                    LedControlRequest { led_id: 3, mode: 0 }
+        // A device that doesn't tell its name can still be used
+        let basic = endpoints
+            .iter()
+            .find(|endpoint| endpoint.input_clusters.contains(&BASIC_CLUSTER));
+        let (manufacturer_name, model_id) = match basic {
+            Some(endpoint) => match self
+                .read_attributes(
+                    network_address,
+                    endpoint.id,
+                    BASIC_CLUSTER,
+                    &[BASIC_MANUFACTURER_NAME, BASIC_MODEL_IDENTIFIER],
+                )
+                .await
+            {
+                Ok(records) => {
+                    let value = |id| {
+                        records
+                            .iter()
+                            .find(|record| record.id == id)
+                            .and_then(AttributeRecord::as_string)
+                    };
+                    (
+                        value(BASIC_MANUFACTURER_NAME),
+                        value(BASIC_MODEL_IDENTIFIER),
+                    )
                 }
-            }
-            LedStatus::On => LedControlRequest { led_id: 3, mode: 1 },
-            LedStatus::Off => LedControlRequest { led_id: 3, mode: 0 },
+                Err(e) => {
+                    warn!(
+                        "could not read the name of {:#06x}: {:?}",
+                        network_address, e
+                    );
+                    (None, None)
+                }
+            },
+            None => (None, None),
         };
 
-        self.request(&command).await
+        let description = DeviceDescription {
+            network_address,
+            logical_type,
+            manufacturer_code: node.manufacturer_code,
+            power_source,
+            endpoints,
+            manufacturer_name,
+            model_id,
+        };
+        match self.devices.set_description(&description) {
+            Ok(()) | Err(CoordinatorError::UnknownDevice) => Ok(description),
+            Err(e) => Err(e),
+        }
     }
 
-    async fn change_channel(&self, channel: u8) -> Result<(), CoordinatorError> {
-        info!("changing channel to {}", channel);
+    /// Asks the device at `network_address`, the source of the binding, to add it to its
+    /// binding table
+    pub async fn bind(
+        &self,
+        network_address: u16,
+        binding: &BindingEntry,
+    ) -> Result<(), CoordinatorError> {
+        let _: BindRspRequest = self
+            .zdo_request(&BindRequest::new(network_address, binding), network_address)
+            .await?;
+        Ok(())
+    }
 
-        let command = ManagementNetworkUpdateRequest {
-            destination_address: 0xffff,
-            destination_address_mode: AddressMode::AddrBroadcast as u16,
-            channel_mask: [channel]
-                .into_iter()
-                .reduce(|a, c| a + (1 << c))
-                .ok_or(CoordinatorError::InvalidChannel)? as u32, //TODO: very likely wrong, check this
-            scan_duration: 0xfe,
-            scan_count: 0,
-            network_manager_address: 0,
-        };
+    /// Asks the device at `network_address` to remove a binding from its binding table
+    pub async fn unbind(
+        &self,
+        network_address: u16,
+        binding: &BindingEntry,
+    ) -> Result<(), CoordinatorError> {
+        let _: UnbindRspRequest = self
+            .zdo_request(
+                &UnbindRequest::new(network_address, binding),
+                network_address,
+            )
+            .await?;
+        Ok(())
+    }
 
-        self.request(&command).await
+    /// Reads the whole binding table of a device with ZDO_MGMT_BIND_REQ
+    pub async fn binding_table(
+        &self,
+        network_address: u16,
+    ) -> Result<Vec<BindingEntry>, CoordinatorError> {
+        let mut bindings = Vec::new();
+        loop {
+            let page: ManagementBindRspRequest = self
+                .zdo_request(
+                    &ManagementBindRequest {
+                        destination_address: network_address,
+                        start_index: bindings.len() as u8,
+                    },
+                    network_address,
+                )
+                .await?;
+            let last = page.bindings.items.is_empty();
+            bindings.extend(page.bindings.items);
+            if last || bindings.len() >= page.binding_table_entries.into() {
+                return Ok(bindings);
+            }
+        }
     }
 
-    async fn set_transmit_power(&self, power: i8) -> Result<(), CoordinatorError> {
-        info!("setting transmit power to {}", power);
-        let command = StackTuneRequest {
-            operation: 0,
-            value: power,
-        };
+    /// Walks the mesh from the coordinator, reading the neighbor and routing tables of every
+    /// router it finds. Routers that don't answer are left out with a warning.
+    pub async fn scan_topology(&self) -> Result<Topology, CoordinatorError> {
+        let info = self.device_info().await?;
+        let mut topology = Topology::default();
+        let coordinator = topology.node(info.short_addr);
+        coordinator.ieee_address = Some(info.ieee_addr);
+        coordinator.logical_type = Some(LogicalType::Coordinator);
+        coordinator.depth = Some(0);
+        // Direct children, in case the neighbor table of the coordinator leaves some out
+        for &child in &info.assoc_devices_list.list[..info.assoc_devices_list.len] {
+            let node = topology.node(child);
+            node.ieee_address = self
+                .devices
+                .by_network_address(child)
+                .map(|device| device.ieee_address);
+            node.depth = Some(1);
+        }
 
-        self.request(&command).await
+        let mut pending = VecDeque::from([info.short_addr]);
+        let mut scanned = HashSet::new();
+        while let Some(network_address) = pending.pop_front() {
+            if !scanned.insert(network_address) {
+                continue;
+            }
+            match self.neighbor_table(network_address).await {
+                Ok(neighbors) => {
+                    pending.extend(topology.add_neighbors(network_address, &neighbors));
+                }
+                Err(e) => {
+                    warn!(
+                        "could not read the neighbors of {:#06x}: {:?}",
+                        network_address, e
+                    );
+                    continue;
+                }
+            }
+            match self.routing_table(network_address).await {
+                Ok(routes) => topology.add_routes(network_address, &routes),
+                // Not every router shares its routing table
+                Err(e) => warn!(
+                    "could not read the routes of {:#06x}: {:?}",
+                    network_address, e
+                ),
+            }
+        }
+        Ok(topology)
     }
 
-    async fn request_network_address(_addr: &str) -> Result<(), CoordinatorError> {
-        todo!()
+    // Pages through the neighbor table of a router with ZDO_MGMT_LQI_REQ
+    async fn neighbor_table(
+        &self,
+        network_address: u16,
+    ) -> Result<Vec<NeighborLqi>, CoordinatorError> {
+        let mut neighbors = Vec::new();
+        loop {
+            let page: ManagementLqiRspRequest = self
+                .zdo_request(
+                    &ManagementLqiRequest {
+                        destination_address: network_address,
+                        start_index: neighbors.len() as u8,
+                    },
+                    network_address,
+                )
+                .await?;
+            let last = page.neighbors.items.is_empty();
+            neighbors.extend(page.neighbors.items);
+            if last || neighbors.len() >= page.neighbor_table_entries.into() {
+                return Ok(neighbors);
+            }
+        }
     }
 
-    async fn send_zcl_frame(
+    // Pages through the routing table of a router with ZDO_MGMT_RTG_REQ
+    async fn routing_table(
         &self,
-        _iee_addr: &Self::IeeAddress,
-        _network_address: u16,
-        _endpoint: u16,
-        _zcl_frame: &Self::ZclFrame,
-        _timeout: std::time::Duration,
-        _disable_response: bool,
-        _disable_recovery: bool,
-        _source_endpoint: Option<u32>,
-    ) -> Result<Option<Self::ZclPayload<'static>>, CoordinatorError> {
-        Ok(None)
+        network_address: u16,
+    ) -> Result<Vec<RoutingEntry>, CoordinatorError> {
+        let mut routes = Vec::new();
+        loop {
+            let page: ManagementRoutingRspRequest = self
+                .zdo_request(
+                    &ManagementRoutingRequest {
+                        destination_address: network_address,
+                        start_index: routes.len() as u8,
+                    },
+                    network_address,
+                )
+                .await?;
+            let last = page.routes.items.is_empty();
+            routes.extend(page.routes.items);
+            if last || routes.len() >= page.routing_table_entries.into() {
+                return Ok(routes);
+            }
+        }
     }
 
-    async fn permit_join(
+    /// Reads attributes of a device's cluster, asking again when the device doesn't answer
+    pub async fn read_attributes(
         &self,
-        seconds: std::time::Duration,
-        network_address: Option<u16>,
-    ) -> Result<(), CoordinatorError> {
-        info!("permitting join for {} seconds", seconds.as_secs());
-        self.error_if_interpan_mode().await?;
-        let address_mode =
-            network_address.map_or(AddressMode::AddrBroadcast, |_| AddressMode::Addr16bit);
-        let destination_address = network_address.unwrap_or(0xfffc);
-        let command = ManagementPermitJoinRequest {
-            address_mode: address_mode as u16,
-            destination_address,
-            duration: seconds
-                .as_secs()
-                .try_into()
-                .map_err(|_| CoordinatorError::DurationTooLong)?,
-            tc_significance: 0,
-        };
+        network_address: u16,
+        endpoint: u8,
+        cluster_id: u16,
+        attributes: &[u16],
+    ) -> Result<Vec<AttributeRecord>, CoordinatorError> {
+        let response = self
+            .zcl_request(network_address, endpoint, |sequence| {
+                ZclFrame::read_attributes(cluster_id, sequence, attributes)
+            })
+            .await?;
+        Ok(response.attribute_records()?)
+    }
 
-        self.request(&command).await
+    /// Adds an endpoint of the coordinator to a group, so it receives what is sent to the group
+    pub async fn add_to_group(
+        &self,
+        endpoint: u8,
+        group_id: u16,
+        name: &str,
+    ) -> Result<(), CoordinatorError> {
+        // The stack keeps at most 16 bytes of a group name
+        let name = &name.as_bytes()[..name.len().min(16)];
+        let response: ExtAddGroupResponse = self
+            .request_with_reply(
+                &ExtAddGroupRequest {
+                    endpoint,
+                    group_id,
+                    name: name.to_vec().into(),
+                },
+                None,
+            )
+            .await?;
+        ensure_success(response.try_into()?)
     }
 
-    async fn discover_route(
+    pub async fn remove_from_group(
         &self,
-        address: Option<u16>,
-        wait: Option<bool>,
+        endpoint: u8,
+        group_id: u16,
     ) -> Result<(), CoordinatorError> {
-        trace!(
-            "discovering route at address: {:?}, wait: {:?}",
-            address,
-            wait
-        );
+        let response: ExtRemoveGroupResponse = self
+            .request_with_reply(&ExtRemoveGroupRequest { endpoint, group_id }, None)
+            .await?;
+        ensure_success(response.try_into()?)
+    }
 
-        let command = ExitRouteDiscRequest {
-            destination_address: address.unwrap_or(0),
+    /// Groups an endpoint of the coordinator is in
+    pub async fn groups(&self, endpoint: u8) -> Result<Vec<u16>, CoordinatorError> {
+        let response: ExtFindAllGroupsEndpointResponse = self
+            .request_with_reply(&ExtFindAllGroupsEndpointRequest { endpoint }, None)
+            .await?;
+        Ok(response.groups.items)
+    }
+
+    /// Sends a ZCL frame to every device of a group at once. Devices don't answer frames sent to
+    /// a group.
+    pub async fn send_zcl_frame_to_group(
+        &self,
+        group_id: u16,
+        zcl_frame: &ZclFrame,
+        source_endpoint: Option<u8>,
+    ) -> Result<(), CoordinatorError> {
+        let mut destination_address = [0u8; 8];
+        destination_address[..2].copy_from_slice(&group_id.to_le_bytes());
+        let request = DataRequestExtRequest {
+            destination_address_mode: AddressMode::AddrGroup as u8,
+            destination_address,
+            // Every endpoint of the group members
+            destination_endpoint: 0xff,
+            // Our own network
+            destination_pan_id: 0,
+            source_endpoint: source_endpoint.unwrap_or(DEFAULT_SOURCE_ENDPOINT as u8),
+            cluster_id: zcl_frame.cluster_id,
+            transaction_id: self.next_transaction_id(),
             options: 0,
             radius: af::DEFAULT_RADIUS,
+            data: zcl_frame.to_bytes()?.into(),
         };
+        trace!(
+            "sending zcl frame {:?} to group {:#06x}",
+            zcl_frame,
+            group_id
+        );
+        self.data_request_ext(&request, None).await?;
+        Ok(())
+    }
 
-        self.request(&command).await
+    /// Adds an endpoint of a device to a group with the Groups cluster
+    pub async fn add_device_to_group(
+        &self,
+        network_address: u16,
+        endpoint: u8,
+        group_id: u16,
+        name: &str,
+    ) -> Result<(), CoordinatorError> {
+        match self
+            .group_command(network_address, endpoint, |sequence| {
+                ZclFrame::add_group(sequence, group_id, name)
+            })
+            .await?
+        {
+            GroupResponse::Add { status: 0, .. } => Ok(()),
+            GroupResponse::Add { status, .. } => Err(CoordinatorError::ZclStatus(status)),
+            _ => Err(CoordinatorError::InvalidResponse),
+        }
     }
 
-    async fn set_on_event(&mut self, on_zigbee_event: OnEvent) -> Result<(), CoordinatorError> {
-        self.on_zigbee_event
-            .lock()
+    /// Name a device has for one of its groups, empty when it doesn't keep names
+    pub async fn view_device_group(
+        &self,
+        network_address: u16,
+        endpoint: u8,
+        group_id: u16,
+    ) -> Result<String, CoordinatorError> {
+        match self
+            .group_command(network_address, endpoint, |sequence| {
+                ZclFrame::view_group(sequence, group_id)
+            })
+            .await?
+        {
+            GroupResponse::View {
+                status: 0, name, ..
+            } => Ok(name),
+            GroupResponse::View { status, .. } => Err(CoordinatorError::ZclStatus(status)),
+            _ => Err(CoordinatorError::InvalidResponse),
+        }
+    }
+
+    /// Groups an endpoint of a device is in
+    pub async fn device_groups(
+        &self,
+        network_address: u16,
+        endpoint: u8,
+    ) -> Result<Vec<u16>, CoordinatorError> {
+        match self
+            .group_command(network_address, endpoint, |sequence| {
+                ZclFrame::get_group_membership(sequence, &[])
+            })
+            .await?
+        {
+            GroupResponse::Membership { groups, .. } => Ok(groups),
+            _ => Err(CoordinatorError::InvalidResponse),
+        }
+    }
+
+    pub async fn remove_device_from_group(
+        &self,
+        network_address: u16,
+        endpoint: u8,
+        group_id: u16,
+    ) -> Result<(), CoordinatorError> {
+        match self
+            .group_command(network_address, endpoint, |sequence| {
+                ZclFrame::remove_group(sequence, group_id)
+            })
+            .await?
+        {
+            GroupResponse::Remove { status: 0, .. } => Ok(()),
+            GroupResponse::Remove { status, .. } => Err(CoordinatorError::ZclStatus(status)),
+            _ => Err(CoordinatorError::InvalidResponse),
+        }
+    }
+
+    // Sends a Groups cluster command, a device failing it may answer with a default response
+    async fn group_command(
+        &self,
+        network_address: u16,
+        endpoint: u8,
+        request: impl Fn(u8) -> ZclFrame,
+    ) -> Result<GroupResponse, CoordinatorError> {
+        let response = self.zcl_request(network_address, endpoint, request).await?;
+        if let Some(status) = response.default_response_status() {
+            return Err(CoordinatorError::ZclStatus(status));
+        }
+        Ok(response.group_response()?)
+    }
+
+    // Sends a ZCL frame built for a fresh sequence number and waits for the answer, asking again
+    // when the device doesn't answer
+    async fn zcl_request(
+        &self,
+        network_address: u16,
+        endpoint: u8,
+        request: impl Fn(u8) -> ZclFrame,
+    ) -> Result<ZclFrame, CoordinatorError> {
+        let address = ieee802154::mac::Address::Short(
+            ieee802154::mac::PanId(0xffff),
+            ieee802154::mac::ShortAddress(network_address),
+        );
+        let mut attempt = 1;
+        loop {
+            let request = request(self.zcl_sequence.next_id());
+            let response = self
+                .send_zcl_frame(
+                    &address,
+                    network_address,
+                    endpoint.into(),
+                    &request,
+                    self.device_timeout(),
+                    false,
+                    false,
+                    None,
+                )
+                .await;
+            match response {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => return Err(CoordinatorError::NoResponse),
+                Err(CoordinatorError::Timeout) if attempt < DEVICE_REQUEST_ATTEMPTS => {
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn zdo_request<R, Rsp>(
+        &self,
+        request: &R,
+        network_address: u16,
+    ) -> Result<Rsp, CoordinatorError>
+    where
+        R: CommandRequest + DekuWriter,
+        R::Response: for<'de> DekuReader<'de>
+            + for<'de> DekuContainerRead<'de>
+            + TryInto<CommandStatus, Error = NoCommandStatusError>,
+        Rsp: CommandRequest + for<'de> DekuReader<'de> + for<'de> DekuContainerRead<'de>,
+    {
+        self.link().zdo_request(request, network_address).await
+    }
+
+    async fn zdo_exchange<R, Rsp>(
+        &self,
+        request: &R,
+        answers: impl Fn(&[u8]) -> bool + Clone + Send + Sync + 'static,
+        status_index: usize,
+    ) -> Result<Rsp, CoordinatorError>
+    where
+        R: CommandRequest + DekuWriter,
+        R::Response: for<'de> DekuReader<'de>
+            + for<'de> DekuContainerRead<'de>
+            + TryInto<CommandStatus, Error = NoCommandStatusError>,
+        Rsp: CommandRequest + for<'de> DekuReader<'de> + for<'de> DekuContainerRead<'de>,
+    {
+        self.link()
+            .zdo_exchange(request, answers, status_index)
             .await
+    }
+
+    // Shares what the requests to remote devices need, the device timeout included
+    fn link(&self) -> DeviceLink<S> {
+        DeviceLink {
+            serial: self.serial.clone(),
+            subscriptions: self.subscriptions.clone(),
+            devices: self.devices.clone(),
+            events: self.events.clone(),
+            on_zigbee_event: self.on_zigbee_event.clone(),
+            device_timeout: self.device_timeout.clone(),
+        }
+    }
+
+    // Compares what the NIB and the NV items describe with the requested network
+    async fn network_matches(&self, options: &NetworkOptions) -> Result<bool, CoordinatorError> {
+        let nib = match self.nv_adapter.read::<Nib>().await {
+            Ok(nib) => nib,
+            // No NIB yet, the stack never formed or joined anything
+            Err(NvMemoryAdapterError::CommandStatus(_)) => return Ok(false),
+            Err(NvMemoryAdapterError::Deku(e)) => {
+                warn!("could not decode the NIB: {:?}", e);
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let network_key = match self.nv_adapter.read::<PreCfgKey>().await {
+            Ok(PreCfgKey(network_key)) => network_key,
+            Err(NvMemoryAdapterError::CommandStatus(_)) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(nib.nwk_pan_id == options.pan_id
+            && nib.extended_panid == options.extended_pan_id
+            && options.channel_list.contains(&nib.nwk_logical_channel)
+            && network_key == options.network_key)
+    }
+
+    async fn start_commissioning(&self, mode: CommissioningMode) -> Result<(), CoordinatorError> {
+        trace!("starting commissioning in mode {:?}", mode);
+        let r: BdbStartCommissioningResponse = self
+            .request_with_reply(&BdbStartCommissioningRequest { mode: mode as u8 }, None)
+            .await?;
+        ensure_success(r.try_into()?)
+    }
+
+    /// Resets the stack and waits until it's back up
+    pub async fn reset_and_wait(
+        &self,
+        reset_type: ResetType,
+    ) -> Result<ResetIndRequest, CoordinatorError> {
+        let reset = subscribe_for(
+            ResetIndRequest::id(),
+            MessageType::AREQ,
+            Subsystem::Sys,
+            self.subscriptions.clone(),
+        )
+        .await;
+        if let Err(e) = self.reset(reset_type).await {
+            self.subscriptions.lock().await.unsubscribe(reset.id);
+            return Err(e);
+        }
+        Ok(reset
+            .wait(self.subscriptions.clone(), Some(RESET_TIMEOUT))
+            .await?
+            .to_command_request()?)
+    }
+
+    pub async fn begin_startup(&self) -> Result<StartupFromAppResponse, CoordinatorError> {
+        info!("beginning startup...");
+        // The stack goes through a few states before settling, we only care about the final one
+        let started = subscribe_for_matching(
+            StateChangedIndRequest::id(),
+            MessageType::AREQ,
+            Subsystem::Zdo,
+            |packet| packet.payload.first() == Some(&(DeviceState::Coordinator as u8)),
+            self.subscriptions.clone(),
+        )
+        .await;
+        let r = self
+            .request_with_reply::<_, StartupFromAppResponse>(
+                &StartupFromAppRequest {
+                    start_delay: 100,
+                    status: 0,
+                },
+                None,
+            )
+            .await;
+        match r {
+            Ok(r) if r.status != StartupStatus::LeaveAndNotStarted as u8 => {
+                started
+                    .wait(self.subscriptions.clone(), Some(STARTUP_TIMEOUT))
+                    .await?;
+                info!("coordinator started");
+                Ok(r)
+            }
+            r => {
+                self.subscriptions.lock().await.unsubscribe(started.id);
+                r.and(Err(CoordinatorError::NetworkNotStarted))
+            }
+        }
+    }
+}
+
+impl<S: SimpleSerial<SUnpiPacket> + Send + 'static> Coordinator for CC253X<S> {
+    type ZclFrame = ZclFrame;
+
+    type ZclPayload<'a> = ZclFrame;
+
+    type IeeAddress = ieee802154::mac::Address;
+
+    async fn start(&self) -> Result<(), CoordinatorError> {
+        let mut attempt = 1;
+        loop {
+            trace!("pinging coordinator attempt number {:?}", attempt);
+            match self
+                .request_with_reply::<_, PingResponse>(&PingRequest {}, None)
+                .await
+            {
+                Ok(_) => break,
+                Err(CoordinatorError::Timeout) if attempt < 3 => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        trace!("ping successful");
+        let version = self.version().await?;
+        info!("coordinator version: {:?}", version);
+        if let Some(znp_version) = ZnpVersion::from_product(version.product) {
+            self.nv_adapter.set_znp_version(znp_version);
+        }
+        Ok(())
+    }
+
+    async fn stop(&self) -> Result<(), CoordinatorError> {
+        Ok(())
+    }
+
+    async fn is_inter_pan_mode(&self) -> bool {
+        warn!("is_inter_pan_mode not implemented, assuming false");
+        false
+    }
+
+    async fn version(&self) -> Result<VersionResponse, CoordinatorError> {
+        let version: VersionResponse = self.request_with_reply(&VersionRequest {}, None).await?;
+        Ok(version)
+    }
+
+    async fn reset(&self, reset_type: ResetType) -> Result<(), CoordinatorError> {
+        trace!("reset with reset type {:?}", reset_type);
+        self.request(&ResetRequest { reset_type }).await?;
+        Ok::<(), CoordinatorError>(())
+    }
+
+    async fn set_led(&self, led_status: LedStatus) -> Result<(), CoordinatorError> {
+        trace!("setting LED to {:?}", led_status);
+        //TODO: const firmwareControlsLed = parseInt(this.version.revision) >= 20211029;
+        let firmware_controls_led = true;
+
+        let command = match led_status {
+            LedStatus::Disable => {
+                if firmware_controls_led {
+                    LedControlRequest {
+                        led_id: 0xff,
+                        mode: 0,
+                    }
+                } else {
+                    LedControlRequest { led_id: 3, mode: 0 }
+                }
+            }
+            LedStatus::On => LedControlRequest { led_id: 3, mode: 1 },
+            LedStatus::Off => LedControlRequest { led_id: 3, mode: 0 },
+        };
+
+        self.request(&command).await
+    }
+
+    async fn change_channel(&self, channel: u8) -> Result<(), CoordinatorError> {
+        info!("changing channel to {}", channel);
+
+        let command = ManagementNetworkUpdateRequest {
+            destination_address: 0xffff,
+            destination_address_mode: AddressMode::AddrBroadcast as u16,
+            channel_mask: [channel]
+                .into_iter()
+                .reduce(|a, c| a + (1 << c))
+                .ok_or(CoordinatorError::InvalidChannel)? as u32, //TODO: very likely wrong, check this
+            scan_duration: 0xfe,
+            scan_count: 0,
+            network_manager_address: 0,
+        };
+
+        self.request(&command).await
+    }
+
+    async fn set_transmit_power(&self, power: i8) -> Result<(), CoordinatorError> {
+        info!("setting transmit power to {}", power);
+        let command = StackTuneRequest {
+            operation: 0,
+            value: power,
+        };
+
+        self.request(&command).await
+    }
+
+    #[cfg(feature = "psila")]
+    async fn request_network_address(
+        &self,
+        ieee_address: ExtendedAddress,
+        request_type: AddressRequestType,
+        start_index: u8,
+    ) -> Result<NetworkAddressRspRequest, CoordinatorError> {
+        let ieee_address = ieee_address.0.to_le_bytes();
+        // The request is broadcast, the answer is told apart by the address it is about
+        self.zdo_exchange(
+            &NetworkAddressRequest {
+                ieee_address: CommandIeeeAddress { ieee_address },
+                request_type: request_type as u8,
+                start_index,
+            },
+            move |payload| payload.get(1..9) == Some(&ieee_address[..]),
+            0,
+        )
+        .await
+    }
+
+    async fn request_ieee_address(
+        &self,
+        network_address: u16,
+        request_type: AddressRequestType,
+        start_index: u8,
+    ) -> Result<IeeeAddressRspRequest, CoordinatorError> {
+        let source = network_address.to_le_bytes();
+        self.zdo_exchange(
+            &IeeeAddressRequest {
+                short_address: network_address,
+                request_type: request_type as u8,
+                start_index,
+            },
+            move |payload| payload.get(9..11) == Some(&source[..]),
+            0,
+        )
+        .await
+    }
+
+    async fn send_zcl_frame(
+        &self,
+        iee_addr: &Self::IeeAddress,
+        network_address: u16,
+        endpoint: u16,
+        zcl_frame: &Self::ZclFrame,
+        timeout: std::time::Duration,
+        disable_response: bool,
+        disable_recovery: bool,
+        source_endpoint: Option<u32>,
+    ) -> Result<Option<Self::ZclPayload<'static>>, CoordinatorError> {
+        let destination_endpoint: u8 = endpoint
+            .try_into()
+            .map_err(|_| CoordinatorError::InvalidEndpoint)?;
+        let source_endpoint: u8 = source_endpoint
+            .unwrap_or(DEFAULT_SOURCE_ENDPOINT)
+            .try_into()
+            .map_err(|_| CoordinatorError::InvalidEndpoint)?;
+        let ieee_address = match iee_addr {
+            ieee802154::mac::Address::Extended(_, ieee802154::mac::ExtendedAddress(ieee)) => {
+                Some(ieee.to_le_bytes())
+            }
+            _ => None,
+        };
+        // The device may have moved since the caller learned its network address
+        let network_address = ieee_address
+            .and_then(|ieee| self.devices.by_ieee_address(&ieee))
+            .map_or(network_address, |device| device.network_address);
+        let cluster_id = zcl_frame.cluster_id;
+        let data = zcl_frame.to_bytes()?;
+        trace!(
+            "sending zcl frame {:?} to {:#06x}/{}",
+            zcl_frame,
+            network_address,
+            destination_endpoint
+        );
+
+        // Replies carry our transaction sequence number, listen before sending so none is missed
+        let reply = if disable_response {
+            None
+        } else {
+            let transaction_sequence = zcl_frame.header.transaction_sequence;
+            Some(
+                subscribe_for_any_matching(
+                    MessageType::AREQ,
+                    Subsystem::Af,
+                    move |packet| {
+                        // The extended message names the source by its IEEE address at times
+                        incoming_message(packet)
+                            .ok()
+                            .flatten()
+                            .is_some_and(|message| {
+                                (message.source_address == network_address
+                                    || message.source_ieee_address.is_some()
+                                        && message.source_ieee_address == ieee_address)
+                                    && message.source_endpoint == destination_endpoint
+                                    && message.cluster_id == cluster_id
+                                    && ZclFrame::from_bytes(cluster_id, &message.data).is_ok_and(
+                                        |reply| {
+                                            reply.header.transaction_sequence
+                                                == transaction_sequence
+                                        },
+                                    )
+                            })
+                    },
+                    self.subscriptions.clone(),
+                )
+                .await,
+            )
+        };
+
+        let mut attempt = 0;
+        let sent = loop {
+            let request = DataRequestRequest::new(
+                network_address,
+                destination_endpoint,
+                source_endpoint,
+                cluster_id,
+                self.next_transaction_id(),
+                0,
+                af::DEFAULT_RADIUS,
+                data.clone(),
+            );
+            let e = match self.data_request(&request, None).await {
+                Ok(confirm) => break Ok(confirm),
+                Err(e) => e,
+            };
+            let recoverable = match &e {
+                CoordinatorError::CommandStatusFailure(status) => is_recoverable(status),
+                CoordinatorError::Timeout => true,
+                _ => false,
+            };
+            if disable_recovery || !recoverable || attempt == ZCL_SEND_RETRIES {
+                break Err(e);
+            }
+            attempt += 1;
+            warn!(
+                "sending to {:#06x} failed with {:?}, discovering route and retrying",
+                network_address, e
+            );
+            if let Err(e) = self.discover_route(Some(network_address), Some(true)).await {
+                break Err(e);
+            }
+        };
+
+        match (sent, reply) {
+            (Err(e), Some(reply)) => {
+                self.subscriptions.lock().await.unsubscribe(reply.id);
+                Err(e)
+            }
+            (Err(e), None) => Err(e),
+            (Ok(_), None) => Ok(None),
+            (Ok(_), Some(reply)) => {
+                let packet = reply
+                    .wait(self.subscriptions.clone(), Some(timeout))
+                    .await?;
+                let message =
+                    incoming_message(&packet)?.ok_or(CoordinatorError::InvalidResponse)?;
+                Ok(Some(ZclFrame::from_bytes(cluster_id, &message.data)?))
+            }
+        }
+    }
+
+    async fn permit_join(
+        &self,
+        permit: PermitJoin,
+        network_address: Option<u16>,
+    ) -> Result<(), CoordinatorError> {
+        let permit = match permit {
+            PermitJoin::For(duration) if duration.is_zero() => PermitJoin::Close,
+            permit => permit,
+        };
+        info!("permitting join: {:?}", permit);
+        self.error_if_interpan_mode().await?;
+        // Whatever the previous call opened is taken over from here
+        self.join_window.lock().unwrap().take();
+        let seconds = match permit {
+            PermitJoin::Close => 0,
+            PermitJoin::For(duration) => window_seconds(duration),
+            PermitJoin::Forever => 255,
+        };
+        // Before the request, so its echo from the stack can't get through
+        self.join_window_reported.store(true, Ordering::Relaxed);
+        self.request(&permit_join_request(network_address, seconds))
+            .await?;
+        dispatch(
+            &self.devices,
+            &self.events,
+            &self.on_zigbee_event,
+            ZigbeeEvent::PermitJoin(permit),
+        );
+        if let PermitJoin::For(duration) = permit {
+            let (cancel, cancelled) = mpsc::channel();
+            *self.join_window.lock().unwrap() = Some(cancel);
+            self.keep_join_window(duration, network_address, cancelled);
+        }
+        Ok(())
+    }
+
+    async fn discover_route(
+        &self,
+        address: Option<u16>,
+        wait: Option<bool>,
+    ) -> Result<(), CoordinatorError> {
+        trace!(
+            "discovering route at address: {:?}, wait: {:?}",
+            address,
+            wait
+        );
+
+        let command = ExitRouteDiscRequest {
+            destination_address: address.unwrap_or(0),
+            options: 0,
+            radius: af::DEFAULT_RADIUS,
+        };
+
+        self.request(&command).await
+    }
+
+    async fn set_on_event(&mut self, on_zigbee_event: OnEvent) -> Result<(), CoordinatorError> {
+        self.on_zigbee_event
+            .lock()
+            .unwrap()
             .replace(Box::new(on_zigbee_event));
         Ok(())
     }
@@ -319,3 +1605,1727 @@ impl<S: SimpleSerial<SUnpiPacket>> Coordinator for CC253X<S> {
         Ok(device_info)
     }
 }
+
+// Decodes both flavours of AF incoming message, `None` for any other AF callback
+fn incoming_message(packet: &SUnpiPacket) -> Result<Option<IncomingMessage>, UnpiCommandError> {
+    let message = if packet.command == IncomingMsgRequest::id() {
+        packet.to_command_request::<IncomingMsgRequest>()?.into()
+    } else if packet.command == IncomingMsgExtRequest::id() {
+        packet.to_command_request::<IncomingMsgExtRequest>()?.into()
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(message))
+}
+
+// Translates the ZDO callbacks that tell something about the network
+fn zdo_event(packet: &SUnpiPacket) -> Result<Option<ZigbeeEvent>, UnpiCommandError> {
+    let command = packet.command;
+    let event = if command == EndDeviceAnnounceIndRequest::id() {
+        let r: EndDeviceAnnounceIndRequest = packet.to_command_request()?;
+        Some(ZigbeeEvent::DeviceAnnounce {
+            network_address: r.network_address,
+            ieee_address: r.ieee_address.ieee_address,
+        })
+    } else if command == TcDeviceIndexRequest::id() {
+        let r: TcDeviceIndexRequest = packet.to_command_request()?;
+        Some(ZigbeeEvent::DeviceJoined {
+            network_address: r.network_address,
+            ieee_address: r.extended_address.ieee_address,
+        })
+    } else if command == LeaveIndRequest::id() {
+        let r: LeaveIndRequest = packet.to_command_request()?;
+        // A device rejoining right away is still part of the network
+        (r.rejoin == 0).then_some(ZigbeeEvent::DeviceLeave(Either::Left((
+            Some(r.source_address),
+            r.extended_address.ieee_address,
+        ))))
+    } else if command == NetworkAddressRspRequest::id() {
+        let r: NetworkAddressRspRequest = packet.to_command_request()?;
+        (r.status == CommandStatus::Success as u8).then_some(ZigbeeEvent::NetworkAddress {
+            network_address: r.network_address,
+            ieee_address: r.ieee_address.ieee_address,
+        })
+    } else if command == IeeeAddressRspRequest::id() {
+        let r: IeeeAddressRspRequest = packet.to_command_request()?;
+        (r.status == CommandStatus::Success as u8).then_some(ZigbeeEvent::NetworkAddress {
+            network_address: r.network_address,
+            ieee_address: r.ieee_address.ieee_address,
+        })
+    } else if command == StateChangedIndRequest::id() {
+        let r: StateChangedIndRequest = packet.to_command_request()?;
+        DeviceState::from_state(r.state).map(ZigbeeEvent::NetworkState)
+    } else if command == PermitJoinIndRequest::id() {
+        let r: PermitJoinIndRequest = packet.to_command_request()?;
+        Some(ZigbeeEvent::PermitJoin(match r.duration {
+            0 => PermitJoin::Close,
+            255 => PermitJoin::Forever,
+            seconds => PermitJoin::For(Duration::from_secs(seconds.into())),
+        }))
+    } else if command == SourceRouteIndRequest::id() {
+        let r: SourceRouteIndRequest = packet.to_command_request()?;
+        Some(ZigbeeEvent::SourceRoute {
+            network_address: r.destination_address,
+            relays: r.relays.items,
+        })
+    } else {
+        None
+    };
+    Ok(event)
+}
+
+// Records what the event tells about the network, then hands it to the event streams and the
+// user callback
+fn dispatch(
+    devices: &DeviceRegistry,
+    events: &Broadcast<ZigbeeEvent>,
+    on_zigbee_event: &std::sync::Mutex<Option<OnEvent>>,
+    event: ZigbeeEvent,
+) {
+    if let Err(e) = devices.handle_event(&event) {
+        warn!("could not update the device registry: {:?}", e);
+    }
+    events.send(event.clone());
+    if let Some(on_zigbee_event) = on_zigbee_event.lock().unwrap().deref() {
+        if let Err(e) = (on_zigbee_event)(event) {
+            warn!("event handler failed: {:?}", e);
+        }
+    }
+}
+
+// Seconds a permit join request opens for what is left of a window, a started second counts
+fn window_seconds(duration: Duration) -> u8 {
+    let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    seconds.clamp(1, PERMIT_JOIN_MAX_SECONDS.into()) as u8
+}
+
+// When each permit join request of a window is sent after it opened and for how many seconds:
+// the first right away, every next one a little before the previous runs out
+fn permit_join_schedule(duration: Duration) -> impl Iterator<Item = (Duration, u8)> {
+    let issue = move |offset: Duration| (offset, window_seconds(duration.saturating_sub(offset)));
+    std::iter::successors(Some(issue(Duration::ZERO)), move |(offset, seconds)| {
+        let covered = *offset + Duration::from_secs((*seconds).into());
+        (covered < duration).then(|| issue(covered - PERMIT_JOIN_REISSUE_MARGIN))
+    })
+}
+
+fn permit_join_request(network_address: Option<u16>, seconds: u8) -> ManagementPermitJoinRequest {
+    let address_mode =
+        network_address.map_or(AddressMode::AddrBroadcast, |_| AddressMode::Addr16bit);
+    ManagementPermitJoinRequest {
+        address_mode: address_mode as u16,
+        destination_address: network_address.unwrap_or(0xfffc),
+        duration: seconds,
+        tc_significance: 0,
+    }
+}
+
+// What requests to remote devices need, apart from the coordinator so that a thread of its own
+// can make them
+struct DeviceLink<S: SimpleSerial<SUnpiPacket>> {
+    serial: Arc<Mutex<S>>,
+    subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+    devices: Arc<DeviceRegistry>,
+    events: Arc<Broadcast<ZigbeeEvent>>,
+    on_zigbee_event: Arc<std::sync::Mutex<Option<OnEvent>>>,
+    device_timeout: Arc<std::sync::Mutex<std::time::Duration>>,
+}
+
+impl<S: SimpleSerial<SUnpiPacket> + Send + 'static> DeviceLink<S> {
+    fn device_timeout(&self) -> std::time::Duration {
+        *self.device_timeout.lock().unwrap()
+    }
+
+    async fn remove_device(
+        &self,
+        ieee_address: [u8; 8],
+        network_address: u16,
+        options: RemoveOptions,
+    ) -> Result<(), CoordinatorError> {
+        info!("removing {:#06x} {:?}", network_address, options);
+        let mut flags = 0;
+        if options.rejoin {
+            flags |= LEAVE_OPTION_REJOIN;
+        }
+        if options.remove_children {
+            flags |= LEAVE_OPTION_REMOVE_CHILDREN;
+        }
+        let request = ManagementLeaveRequest {
+            destination_address: network_address,
+            device_address: CommandIeeeAddress { ieee_address },
+            remove_children_rejoin: flags,
+        };
+        // Its parent reports the device gone after it answered, we listen from now on so the
+        // report can't slip through
+        let source = network_address.to_le_bytes();
+        let leave = subscribe_for_matching(
+            LeaveIndRequest::id(),
+            MessageType::AREQ,
+            Subsystem::Zdo,
+            move |packet| packet.payload.starts_with(&source),
+            self.subscriptions.clone(),
+        )
+        .await;
+        let answered = self
+            .zdo_request::<_, ManagementLeaveRspRequest>(&request, network_address)
+            .await;
+        let (forced, reported) = match answered {
+            Ok(_) => {
+                let timeout = Some(self.device_timeout());
+                let reported = leave
+                    .wait(self.subscriptions.clone(), timeout)
+                    .await
+                    .is_ok();
+                if !reported {
+                    trace!("{:#06x} left without its parent telling", network_address);
+                }
+                (false, reported)
+            }
+            // Silent, refusing or out of reach, the device is forgotten all the same
+            Err(
+                e @ (CoordinatorError::Timeout
+                | CoordinatorError::ZdoStatus(_)
+                | CoordinatorError::CommandStatusFailure(_)),
+            ) => {
+                self.subscriptions.lock().await.unsubscribe(leave.id);
+                warn!(
+                    "{:#06x} didn't leave ({:?}), removing it anyway",
+                    network_address, e
+                );
+                (true, false)
+            }
+            Err(e) => {
+                self.subscriptions.lock().await.unsubscribe(leave.id);
+                return Err(e);
+            }
+        };
+        if options.rejoin && !forced {
+            return Ok(());
+        }
+        self.forget_link_key(ieee_address).await?;
+        // The leave indication already went out as an event, otherwise nobody reported it
+        if reported {
+            return Ok(());
+        }
+        dispatch(
+            &self.devices,
+            &self.events,
+            &self.on_zigbee_event,
+            ZigbeeEvent::DeviceLeave(Either::Left((Some(network_address), ieee_address))),
+        );
+        Ok(())
+    }
+
+    // Drops the link key the trust center keeps for the device. Devices that joined with the
+    // default global link key have none.
+    async fn forget_link_key(&self, ieee_address: [u8; 8]) -> Result<(), CoordinatorError> {
+        let response: SecDeviceRemoveResponse = self
+            .request_with_reply(&SecDeviceRemoveRequest {
+                ieee_address: CommandIeeeAddress { ieee_address },
+            })
+            .await?;
+        match ensure_success(response.try_into()?) {
+            Err(CoordinatorError::CommandStatusFailure(status)) => {
+                trace!("no link key to forget: {:?}", status);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn request_with_reply<
+        R: CommandRequest + DekuWriter,
+        Res: CommandResponse + for<'de> DekuReader<'de> + for<'de> DekuContainerRead<'de>,
+    >(
+        &self,
+        command: &R,
+    ) -> Result<Res, CoordinatorError> {
+        Ok(request_with_reply::<R, S, Res>(
+            &SUnpiPacket::from_command_owned(super::unpi::LenTypeInfo::OneByte, command)?,
+            self.serial.clone(),
+            self.subscriptions.clone(),
+            None,
+        )
+        .await?)
+    }
+
+    // Sends a ZDO request about a device and waits for the callback with its answer, which
+    // starts with the device address and a ZDO status
+    async fn zdo_request<R, Rsp>(
+        &self,
+        request: &R,
+        network_address: u16,
+    ) -> Result<Rsp, CoordinatorError>
+    where
+        R: CommandRequest + DekuWriter,
+        R::Response: for<'de> DekuReader<'de>
+            + for<'de> DekuContainerRead<'de>
+            + TryInto<CommandStatus, Error = NoCommandStatusError>,
+        Rsp: CommandRequest + for<'de> DekuReader<'de> + for<'de> DekuContainerRead<'de>,
+    {
+        let source = network_address.to_le_bytes();
+        self.zdo_exchange(request, move |payload| payload.starts_with(&source), 2)
+            .await
+    }
+
+    // Sends a ZDO request and waits for the callback whose payload `answers` it, asking again
+    // when none comes. A non-zero ZDO status at `status_index` of the callback is an error.
+    async fn zdo_exchange<R, Rsp>(
+        &self,
+        request: &R,
+        answers: impl Fn(&[u8]) -> bool + Clone + Send + Sync + 'static,
+        status_index: usize,
+    ) -> Result<Rsp, CoordinatorError>
+    where
+        R: CommandRequest + DekuWriter,
+        R::Response: for<'de> DekuReader<'de>
+            + for<'de> DekuContainerRead<'de>
+            + TryInto<CommandStatus, Error = NoCommandStatusError>,
+        Rsp: CommandRequest + for<'de> DekuReader<'de> + for<'de> DekuContainerRead<'de>,
+    {
+        let mut attempt = 1;
+        let packet = loop {
+            let answers = answers.clone();
+            let answer = subscribe_for_matching(
+                Rsp::id(),
+                MessageType::AREQ,
+                Subsystem::Zdo,
+                move |packet| answers(&packet.payload),
+                self.subscriptions.clone(),
+            )
+            .await;
+            let sent = self
+                .request_with_reply::<_, R::Response>(request)
+                .await
+                .and_then(|r| ensure_success(r.try_into()?));
+            if let Err(e) = sent {
+                self.subscriptions.lock().await.unsubscribe(answer.id);
+                return Err(e);
+            }
+            match answer
+                .wait(self.subscriptions.clone(), Some(self.device_timeout()))
+                .await
+            {
+                Ok(packet) => break packet,
+                Err(UnpiCommandError::Timeout) if attempt < DEVICE_REQUEST_ATTEMPTS => {
+                    warn!("no answer to {:?}, asking again", request);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        match packet.payload.get(status_index) {
+            Some(0) => Ok(packet.to_command_request()?),
+            Some(status) => Err(CoordinatorError::ZdoStatus(*status)),
+            None => Err(CoordinatorError::InvalidResponse),
+        }
+    }
+}
+
+// Turns the join of a device the policy refuses into a rejection and asks the device to leave.
+// Whatever such a device announces before it's gone is dropped.
+fn enforce_join_policy<S: SimpleSerial<SUnpiPacket> + Send + 'static>(
+    join_policy: &std::sync::Mutex<JoinPolicy>,
+    link: impl Fn() -> Option<DeviceLink<S>>,
+    event: ZigbeeEvent,
+) -> Option<ZigbeeEvent> {
+    let allows = |ieee_address: &[u8; 8]| join_policy.lock().unwrap().allows(ieee_address);
+    match event {
+        ZigbeeEvent::DeviceJoined {
+            network_address,
+            ieee_address,
+        } if !allows(&ieee_address) => {
+            warn!(
+                "{:#06x} is not allowed on the network, asking it to leave",
+                network_address
+            );
+            if let Some(link) = link() {
+                ask_to_leave(link, network_address, ieee_address);
+            }
+            Some(ZigbeeEvent::DeviceRejected {
+                network_address,
+                ieee_address,
+            })
+        }
+        ZigbeeEvent::DeviceAnnounce {
+            network_address,
+            ieee_address,
+        } if !allows(&ieee_address) => {
+            trace!("ignoring the announce of rejected {:#06x}", network_address);
+            None
+        }
+        event => Some(event),
+    }
+}
+
+// Removed from a thread of its own, the read thread that saw the join can't wait for answers
+fn ask_to_leave<S: SimpleSerial<SUnpiPacket> + Send + 'static>(
+    link: DeviceLink<S>,
+    network_address: u16,
+    ieee_address: [u8; 8],
+) {
+    std::thread::spawn(move || {
+        let removed =
+            block_on(link.remove_device(ieee_address, network_address, RemoveOptions::default()));
+        if let Err(e) = removed {
+            warn!("could not remove {:#06x}: {:?}", network_address, e);
+        }
+    });
+}
+
+// CRC-16/X-25 that follows an install code, appended least significant byte first
+fn install_code_crc(code: &[u8]) -> u16 {
+    !code.iter().fold(0xffff, |crc, byte| {
+        (0..8).fold(crc ^ u16::from(*byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+// Delivery failures that a fresh route may fix
+fn is_recoverable(status: &CommandStatus) -> bool {
+    matches!(
+        status,
+        CommandStatus::MacNoAck
+            | CommandStatus::MacChannelAccessFailure
+            | CommandStatus::MacTransactionExpired
+            | CommandStatus::NwkNoRoute
+            | CommandStatus::ApsNoAck
+    )
+}
+
+fn ensure_success(status: CommandStatus) -> Result<(), CoordinatorError> {
+    match status {
+        CommandStatus::Success => Ok(()),
+        status => Err(CoordinatorError::CommandStatusFailure(status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        coordinator::BindingDestination,
+        utils::sleep::delay,
+        zstack::{
+            nv_memory::entries::security::hashed_link_key,
+            simulator::{RemoteDevice, ZnpSimulator, SIMULATOR_IEEE_ADDRESS},
+        },
+    };
+    use futures::{executor::block_on, FutureExt};
+    use psila_data::cluster_library::{ClusterLibraryHeader, Direction, FrameControl, FrameType};
+    use std::collections::HashMap;
+
+    fn simulated() -> (CC253X<ZnpSimulator>, ZnpSimulator) {
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
+        let simulator = ZnpSimulator::new(subscriptions.clone());
+        let coordinator = block_on(CC253X::new(simulator.clone(), subscriptions)).unwrap();
+        (coordinator, simulator)
+    }
+
+    #[test]
+    fn test_af_data_request_and_incoming_message() {
+        let (mut coordinator, simulator) = simulated();
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
+        let events_tx = std::sync::Mutex::new(events_tx);
+        block_on(async {
+            coordinator
+                .set_on_event(Box::new(move |event| {
+                    events_tx.lock().unwrap().send(event).unwrap();
+                    Ok(())
+                }))
+                .await
+                .unwrap();
+            let register = RegisterRequest {
+                endpoint: 1,
+                app_prof_id: 0x0104,
+                app_device_id: 0x0005,
+                app_dev_ver: 0,
+                latency_req: 0,
+                app_in_cluster_list: vec![0x0000].into(),
+                app_out_cluster_list: vec![0x0006].into(),
+            };
+            coordinator.register_endpoint(&register).await.unwrap();
+            assert!(matches!(
+                coordinator.register_endpoint(&register).await,
+                Err(CoordinatorError::CommandStatusFailure(
+                    CommandStatus::ApsDuplicateEntry
+                ))
+            ));
+
+            let first = coordinator.next_transaction_id();
+            let second = coordinator.next_transaction_id();
+            assert_ne!(first, second);
+            let request = DataRequestRequest::new(
+                0x1234,
+                1,
+                1,
+                0x0006,
+                second,
+                0,
+                af::DEFAULT_RADIUS,
+                vec![0x01, 0x02, 0x01],
+            );
+            let confirm = coordinator.data_request(&request, None).await.unwrap();
+            assert_eq!(confirm.transaction_id, second);
+            assert_eq!(confirm.endpoint, 1);
+        });
+
+        simulator
+            .inject_command(&IncomingMsgRequest {
+                group_id: 0,
+                cluster_id: 0x0006,
+                source_address: 0x1234,
+                source_endpoint: 1,
+                destination_endpoint: 1,
+                was_broadcast: 0,
+                link_quality: 0x73,
+                security_use: 0,
+                timestamp: 0,
+                transaction_sequence_number: 7,
+                data: vec![0x18, 0x01, 0x0b].into(),
+            })
+            .unwrap();
+        let event = events_rx
+            .recv_timeout(std::time::Duration::from_secs(1))
+            .unwrap();
+        let ZigbeeEvent::IncomingMessage(message) = event else {
+            panic!("unexpected event {:?}", event);
+        };
+        assert_eq!(message.source_address, 0x1234);
+        assert_eq!(message.data, vec![0x18, 0x01, 0x0b]);
+    }
+
+    fn register_default_endpoint(coordinator: &CC253X<ZnpSimulator>) {
+        block_on(coordinator.register_endpoint(&RegisterRequest {
+            endpoint: 1,
+            app_prof_id: 0x0104,
+            app_device_id: 0x0005,
+            app_dev_ver: 0,
+            latency_req: 0,
+            app_in_cluster_list: vec![].into(),
+            app_out_cluster_list: vec![0x0006].into(),
+        }))
+        .unwrap();
+    }
+
+    fn toggle(transaction_sequence: u8) -> ZclFrame {
+        ZclFrame::new(
+            0x0006,
+            ClusterLibraryHeader {
+                control: FrameControl {
+                    frame_type: FrameType::Local,
+                    manufacturer_specific: false,
+                    direction: Direction::ToServer,
+                    disable_default_response: false,
+                },
+                manufacturer: None,
+                transaction_sequence,
+                command: 0x02,
+            },
+            vec![],
+        )
+    }
+
+    fn default_response(transaction_sequence: u8) -> IncomingMsgRequest {
+        IncomingMsgRequest {
+            group_id: 0,
+            cluster_id: 0x0006,
+            source_address: 0x1234,
+            source_endpoint: 1,
+            destination_endpoint: 1,
+            was_broadcast: 0,
+            link_quality: 0x73,
+            security_use: 0,
+            timestamp: 0,
+            transaction_sequence_number: 0,
+            // Global default response to the toggle, success
+            data: vec![0x18, transaction_sequence, 0x0b, 0x02, 0x00].into(),
+        }
+    }
+
+    fn data_requests(simulator: &ZnpSimulator) -> usize {
+        simulator.with(|s| {
+            s.received
+                .iter()
+                .filter(|p| {
+                    p.type_subsystem == (MessageType::SREQ, Subsystem::Af)
+                        && p.command == DataRequestRequest::id()
+                })
+                .count()
+        })
+    }
+
+    #[test]
+    fn test_send_zcl_frame_matches_reply_by_sequence_number() {
+        let (coordinator, simulator) = simulated();
+        register_default_endpoint(&coordinator);
+        let events = coordinator.subscribe_events();
+        let address = ieee802154::mac::Address::Short(
+            ieee802154::mac::PanId(0x1a62),
+            ieee802154::mac::ShortAddress(0x1234),
+        );
+        let frame = toggle(42);
+        let send = coordinator.send_zcl_frame(
+            &address,
+            0x1234,
+            1,
+            &frame,
+            std::time::Duration::from_secs(2),
+            false,
+            false,
+            None,
+        );
+        let device = async {
+            while data_requests(&simulator) == 0 {
+                delay(std::time::Duration::from_millis(5)).await;
+            }
+            // A reply to some other request comes first and must be skipped
+            simulator.inject_command(&default_response(41)).unwrap();
+            simulator.inject_command(&default_response(42)).unwrap();
+        };
+        let (reply, _) = block_on(async { futures::join!(send, device) });
+        let reply = reply.unwrap().unwrap();
+        assert_eq!(reply.header.transaction_sequence, 42);
+        assert_eq!(reply.header.command, 0x0b);
+        assert_eq!(reply.payload, vec![0x02, 0x00]);
+
+        // The reply went to the event subscribers as well
+        let delivered = block_on(events.take(2).collect::<Vec<_>>())
+            .into_iter()
+            .map(|event| match event {
+                ZigbeeEvent::IncomingMessage(message) => message.data[1],
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(delivered, vec![41, 42]);
+    }
+
+    #[test]
+    fn test_send_zcl_frame_matches_extended_reply() {
+        let (coordinator, simulator) = simulated();
+        register_default_endpoint(&coordinator);
+        let ieee_address = 0x0102_0304_0506_0708u64;
+        let address = ieee802154::mac::Address::Extended(
+            ieee802154::mac::PanId(0x1a62),
+            ieee802154::mac::ExtendedAddress(ieee_address),
+        );
+        let frame = toggle(42);
+        let send = coordinator.send_zcl_frame(
+            &address,
+            0x1234,
+            1,
+            &frame,
+            std::time::Duration::from_secs(2),
+            false,
+            false,
+            None,
+        );
+        let device = async {
+            while data_requests(&simulator) == 0 {
+                delay(std::time::Duration::from_millis(5)).await;
+            }
+            // The stack names the source by its IEEE address in the extended message
+            let IncomingMsgRequest {
+                group_id,
+                cluster_id,
+                source_endpoint,
+                destination_endpoint,
+                was_broadcast,
+                link_quality,
+                security_use,
+                timestamp,
+                transaction_sequence_number,
+                data,
+                ..
+            } = default_response(42);
+            simulator
+                .inject_command(&IncomingMsgExtRequest {
+                    group_id,
+                    cluster_id,
+                    source_address_mode: AddressMode::Addr64bit as u8,
+                    source_address: ieee_address.to_le_bytes(),
+                    source_endpoint,
+                    source_pan_id: 0x1a62,
+                    destination_endpoint,
+                    was_broadcast,
+                    link_quality,
+                    security_use,
+                    timestamp,
+                    transaction_sequence_number,
+                    data: data.items.into(),
+                })
+                .unwrap();
+        };
+        let (reply, _) = block_on(async { futures::join!(send, device) });
+        let reply = reply.unwrap().unwrap();
+        assert_eq!(reply.header.transaction_sequence, 42);
+        assert_eq!(reply.payload, vec![0x02, 0x00]);
+    }
+
+    #[test]
+    fn test_send_zcl_frame_recovery() {
+        let (coordinator, simulator) = simulated();
+        register_default_endpoint(&coordinator);
+        let address = ieee802154::mac::Address::Short(
+            ieee802154::mac::PanId(0x1a62),
+            ieee802154::mac::ShortAddress(0x1234),
+        );
+        let send = |disable_recovery| {
+            block_on(coordinator.send_zcl_frame(
+                &address,
+                0x1234,
+                1,
+                &toggle(1),
+                std::time::Duration::from_secs(1),
+                true,
+                disable_recovery,
+                None,
+            ))
+        };
+
+        // MAC_NO_ACK once, then delivered after a route discovery
+        simulator.with(|s| s.data_confirm_statuses.push_back(0xe9));
+        assert!(send(false).unwrap().is_none());
+        assert_eq!(data_requests(&simulator), 2);
+        assert!(simulator.with(|s| s.received.iter().any(|p| p.command
+            == ExitRouteDiscRequest::id()
+            && p.type_subsystem.1 == Subsystem::Zdo)));
+
+        simulator.with(|s| s.data_confirm_statuses.push_back(0xe9));
+        assert!(matches!(
+            send(true),
+            Err(CoordinatorError::CommandStatusFailure(
+                CommandStatus::MacNoAck
+            ))
+        ));
+        assert_eq!(data_requests(&simulator), 3);
+    }
+
+    #[test]
+    fn test_event_streams() {
+        let (coordinator, simulator) = simulated();
+        let logger = coordinator.subscribe_events();
+        let automation = coordinator.subscribe_events();
+        for transaction_sequence_number in 0..3 {
+            simulator
+                .inject_command(&IncomingMsgRequest {
+                    group_id: 0,
+                    cluster_id: 0x0006,
+                    source_address: 0x1234,
+                    source_endpoint: 1,
+                    destination_endpoint: 1,
+                    was_broadcast: 0,
+                    link_quality: 0x73,
+                    security_use: 0,
+                    timestamp: 0,
+                    transaction_sequence_number,
+                    data: vec![0x18, transaction_sequence_number, 0x0b].into(),
+                })
+                .unwrap();
+        }
+        let sequence_numbers = |events: Vec<ZigbeeEvent>| {
+            events
+                .into_iter()
+                .map(|event| match event {
+                    ZigbeeEvent::IncomingMessage(message) => message.transaction_sequence_number,
+                    event => panic!("unexpected event {:?}", event),
+                })
+                .collect::<Vec<_>>()
+        };
+        let (logged, automated) = block_on(async {
+            futures::join!(logger.take(3).collect(), automation.take(3).collect())
+        });
+        assert_eq!(sequence_numbers(logged), vec![0, 1, 2]);
+        assert_eq!(sequence_numbers(automated), vec![0, 1, 2]);
+
+        // A stream nobody reads only keeps the latest events
+        let idle = coordinator.subscribe_events();
+        for _ in 0..EVENT_STREAM_CAPACITY + 5 {
+            coordinator.events.send(ZigbeeEvent::Lagged(0));
+        }
+        let first = block_on(idle.take(1).collect::<Vec<_>>());
+        assert!(matches!(first[..], [ZigbeeEvent::Lagged(5)]));
+    }
+
+    #[test]
+    fn test_zdo_callbacks_become_events() {
+        let (coordinator, simulator) = simulated();
+        let events = coordinator.subscribe_events();
+        let device = [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8];
+        let ieee_address = CommandIeeeAddress {
+            ieee_address: device,
+        };
+        simulator
+            .inject_tc_device_index(0x1234, device, 0x0000)
+            .unwrap();
+        simulator
+            .inject_end_device_announce(0x1234, device, 0x8e)
+            .unwrap();
+        let address_response = NetworkAddressRspRequest {
+            status: 0,
+            ieee_address: ieee_address.clone(),
+            network_address: 0x5678,
+            start_index: 0,
+            associated_devices: vec![].into(),
+        };
+        simulator.inject_command(&address_response).unwrap();
+        // Failed lookups and rejoins tell nothing new
+        simulator
+            .inject_command(&IeeeAddressRspRequest {
+                status: 0x81,
+                ieee_address: ieee_address.clone(),
+                network_address: 0x5678,
+                start_index: 0,
+                associated_devices: vec![].into(),
+            })
+            .unwrap();
+        let leave = |rejoin| LeaveIndRequest {
+            source_address: 0x5678,
+            extended_address: ieee_address.clone(),
+            request: 0,
+            remove: 0,
+            rejoin,
+        };
+        simulator.inject_command(&leave(1)).unwrap();
+        simulator.inject_command(&leave(0)).unwrap();
+        simulator
+            .inject_command(&StateChangedIndRequest {
+                state: DeviceState::Coordinator as u8,
+            })
+            .unwrap();
+        simulator
+            .inject_command(&PermitJoinIndRequest { duration: 254 })
+            .unwrap();
+        simulator
+            .inject_command(&SourceRouteIndRequest {
+                destination_address: 0x5678,
+                relays: vec![0x0001, 0x0002].into(),
+            })
+            .unwrap();
+
+        let events: Vec<_> = block_on(events.take(7).collect());
+        assert_eq!(
+            events,
+            vec![
+                ZigbeeEvent::DeviceJoined {
+                    network_address: 0x1234,
+                    ieee_address: device,
+                },
+                ZigbeeEvent::DeviceAnnounce {
+                    network_address: 0x1234,
+                    ieee_address: device,
+                },
+                ZigbeeEvent::NetworkAddress {
+                    network_address: 0x5678,
+                    ieee_address: device,
+                },
+                ZigbeeEvent::DeviceLeave(Either::Left((Some(0x5678), device))),
+                ZigbeeEvent::NetworkState(DeviceState::Coordinator),
+                ZigbeeEvent::PermitJoin(PermitJoin::For(Duration::from_secs(254))),
+                ZigbeeEvent::SourceRoute {
+                    network_address: 0x5678,
+                    relays: vec![0x0001, 0x0002],
+                },
+            ]
+        );
+        // The device is gone from the registry once it left
+        assert!(coordinator.devices().devices().is_empty());
+    }
+
+    #[test]
+    fn test_interview() {
+        let (coordinator, simulator) = simulated();
+        coordinator.set_device_timeout(std::time::Duration::from_millis(200));
+        register_default_endpoint(&coordinator);
+        let ieee_address = [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8];
+        let light = Endpoint {
+            id: 1,
+            profile_id: 0x0104,
+            device_id: 0x0100,
+            input_clusters: vec![0x0000, 0x0006],
+            output_clusters: vec![0x0019],
+        };
+        let green_power = Endpoint {
+            id: 242,
+            profile_id: 0xa1e0,
+            device_id: 0x0061,
+            input_clusters: vec![],
+            output_clusters: vec![0x0021],
+        };
+        simulator.with(|s| {
+            s.remote_devices.insert(
+                0x1234,
+                RemoteDevice {
+                    ieee_address,
+                    logical_type: LogicalType::EndDevice,
+                    manufacturer_code: 0x117c,
+                    mac_capabilities: 0x80,
+                    endpoints: vec![light.clone(), green_power.clone()],
+                    attributes: HashMap::from([(
+                        (BASIC_CLUSTER, BASIC_MANUFACTURER_NAME),
+                        (0x42, b"\x04IKEA".to_vec()),
+                    )]),
+                    // Asleep when the node descriptor is first asked for
+                    missed_requests: 1,
+                    ..Default::default()
+                },
+            )
+        });
+        coordinator
+            .devices()
+            .update_address(ieee_address, 0x1234)
+            .unwrap();
+
+        let description = block_on(coordinator.interview(0x1234)).unwrap();
+        assert_eq!(
+            description,
+            DeviceDescription {
+                network_address: 0x1234,
+                logical_type: LogicalType::EndDevice,
+                manufacturer_code: 0x117c,
+                power_source: PowerSource::Battery,
+                endpoints: vec![light.clone(), green_power],
+                manufacturer_name: Some("IKEA".to_string()),
+                model_id: None,
+            }
+        );
+        let node_requests = simulator.with(|s| {
+            s.received
+                .iter()
+                .filter(|p| p.command == NodeDescriptorRequest::id())
+                .count()
+        });
+        assert_eq!(node_requests, 2);
+        let device = coordinator
+            .devices()
+            .by_ieee_address(&ieee_address)
+            .unwrap();
+        assert_eq!(device.endpoints[0], light);
+        assert_eq!(device.manufacturer_name.as_deref(), Some("IKEA"));
+
+        // Nobody there
+        assert!(matches!(
+            block_on(coordinator.interview(0x5678)),
+            Err(CoordinatorError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_address_requests() {
+        let (coordinator, simulator) = simulated();
+        coordinator.set_device_timeout(std::time::Duration::from_millis(200));
+        let ieee_address = [0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8];
+        simulator.with(|s| {
+            s.remote_devices.insert(
+                0x2000,
+                RemoteDevice {
+                    ieee_address,
+                    manufacturer_code: 0x1037,
+                    associated_devices: vec![0x3001, 0x3002, 0x3003],
+                    ..Default::default()
+                },
+            )
+        });
+        // Known from before the device picked a new address
+        coordinator
+            .devices()
+            .update_address(ieee_address, 0x1111)
+            .unwrap();
+        let events = coordinator.subscribe_events();
+
+        let response = block_on(coordinator.request_network_address(
+            ExtendedAddress(u64::from_le_bytes(ieee_address)),
+            AddressRequestType::Single,
+            0,
+        ))
+        .unwrap();
+        assert_eq!(response.network_address, 0x2000);
+        assert!(response.associated_devices.items.is_empty());
+        let device = coordinator
+            .devices()
+            .by_ieee_address(&ieee_address)
+            .unwrap();
+        assert_eq!(device.network_address, 0x2000);
+
+        let response =
+            block_on(coordinator.request_ieee_address(0x2000, AddressRequestType::Extended, 1))
+                .unwrap();
+        assert_eq!(response.ieee_address.ieee_address, ieee_address);
+        assert_eq!(response.start_index, 1);
+        assert_eq!(response.associated_devices.items, vec![0x3002, 0x3003]);
+
+        let found = ZigbeeEvent::NetworkAddress {
+            network_address: 0x2000,
+            ieee_address,
+        };
+        let received = block_on(events.take(2).collect::<Vec<_>>());
+        assert_eq!(received, vec![found.clone(), found]);
+
+        // Nobody has that address
+        assert!(matches!(
+            block_on(coordinator.request_network_address(
+                ExtendedAddress(0x0102030405060708),
+                AddressRequestType::Single,
+                0,
+            )),
+            Err(CoordinatorError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_scan_topology() {
+        let (coordinator, simulator) = simulated();
+        coordinator.set_device_timeout(std::time::Duration::from_millis(100));
+        let device = |network_address: u16, logical_type, associated_devices| RemoteDevice {
+            ieee_address: [
+                network_address as u8,
+                (network_address >> 8) as u8,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0x22,
+            ],
+            logical_type,
+            mac_capabilities: 0,
+            associated_devices,
+            link_quality: 0x80,
+            ..Default::default()
+        };
+        simulator.with(|s| {
+            // More children than one neighbor table page holds
+            s.associated_devices = vec![0x1000, 0x2000, 0x2001, 0x2002];
+            s.routes = vec![RoutingEntry {
+                destination_address: 0x1100,
+                status: 0,
+                next_hop: 0x1000,
+            }];
+            let mut router = device(0x1000, LogicalType::Router, vec![0x1100, 0x1200]);
+            router.routes = vec![RoutingEntry {
+                destination_address: 0x1100,
+                status: 0,
+                next_hop: 0x1100,
+            }];
+            s.remote_devices.insert(0x1000, router);
+            // Out of reach
+            let mut lost = device(0x1100, LogicalType::Router, vec![]);
+            lost.missed_requests = usize::MAX;
+            s.remote_devices.insert(0x1100, lost);
+            for end_device in [0x1200, 0x2000, 0x2001, 0x2002] {
+                s.remote_devices.insert(
+                    end_device,
+                    device(end_device, LogicalType::EndDevice, vec![]),
+                );
+            }
+        });
+
+        let topology = block_on(coordinator.scan_topology()).unwrap();
+        let node = |network_address| {
+            topology
+                .nodes
+                .iter()
+                .find(|node| node.network_address == network_address)
+                .unwrap()
+        };
+        assert_eq!(topology.nodes.len(), 7);
+        assert!(node(0x0000).scanned);
+        assert!(node(0x1000).scanned);
+        assert!(!node(0x1100).scanned);
+        assert_eq!(node(0x1100).depth, Some(2));
+        assert_eq!(node(0x1200).logical_type, Some(LogicalType::EndDevice));
+        assert_eq!(
+            node(0x2001).ieee_address,
+            Some([0x01, 0x20, 0, 0, 0, 0, 0, 0x22])
+        );
+        let links_from = |source| {
+            topology
+                .links
+                .iter()
+                .filter(|link| link.source == source)
+                .count()
+        };
+        assert_eq!(links_from(0x0000), 4);
+        assert_eq!(links_from(0x1000), 3);
+        assert_eq!(topology.routes.len(), 2);
+
+        // End devices are never asked for their tables
+        let asked = simulator.with(|s| {
+            s.received
+                .iter()
+                .filter(|p| p.command == ManagementLqiRequest::id())
+                .map(|p| u16::from_le_bytes([p.payload[0], p.payload[1]]))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(asked, vec![0x0000, 0x0000, 0x1000, 0x1100, 0x1100, 0x1100]);
+    }
+
+    #[test]
+    fn test_bindings() {
+        let (coordinator, simulator) = simulated();
+        let ieee_address = [0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8];
+        simulator.with(|s| {
+            s.remote_devices.insert(
+                0x4321,
+                RemoteDevice {
+                    ieee_address,
+                    ..Default::default()
+                },
+            )
+        });
+        let binding = |cluster_id, destination| BindingEntry {
+            source_address: ieee_address,
+            source_endpoint: 1,
+            cluster_id,
+            destination,
+        };
+        let to_coordinator = BindingDestination::Device {
+            ieee_address: SIMULATOR_IEEE_ADDRESS,
+            endpoint: 1,
+        };
+        // More than one page of the binding table
+        let bindings = vec![
+            binding(0x0006, to_coordinator.clone()),
+            binding(0x0008, to_coordinator.clone()),
+            binding(0x0300, to_coordinator),
+            binding(0x0006, BindingDestination::Group(0x0007)),
+        ];
+        block_on(async {
+            for b in &bindings {
+                coordinator.bind(0x4321, b).await.unwrap();
+            }
+        });
+        assert_eq!(
+            block_on(coordinator.binding_table(0x4321)).unwrap(),
+            bindings
+        );
+
+        block_on(coordinator.unbind(0x4321, &bindings[1])).unwrap();
+        let left = block_on(coordinator.binding_table(0x4321)).unwrap();
+        assert_eq!(left.len(), 3);
+        assert!(!left.contains(&bindings[1]));
+        assert!(matches!(
+            block_on(coordinator.unbind(0x4321, &bindings[1])),
+            Err(CoordinatorError::ZdoStatus(0x88))
+        ));
+    }
+
+    #[test]
+    fn test_groups() {
+        let (coordinator, simulator) = simulated();
+        register_default_endpoint(&coordinator);
+        simulator.with(|s| {
+            s.remote_devices.insert(
+                0x5555,
+                RemoteDevice {
+                    ieee_address: [0xd1, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8],
+                    ..Default::default()
+                },
+            )
+        });
+
+        block_on(async {
+            coordinator
+                .add_to_group(1, 0x0007, "Living room")
+                .await
+                .unwrap();
+            assert_eq!(coordinator.groups(1).await.unwrap(), vec![0x0007]);
+            coordinator.remove_from_group(1, 0x0007).await.unwrap();
+            assert!(coordinator.groups(1).await.unwrap().is_empty());
+            assert!(matches!(
+                coordinator.remove_from_group(1, 0x0007).await,
+                Err(CoordinatorError::CommandStatusFailure(
+                    CommandStatus::Failure
+                ))
+            ));
+
+            coordinator
+                .add_device_to_group(0x5555, 1, 0x0007, "Living room")
+                .await
+                .unwrap();
+            assert!(matches!(
+                coordinator
+                    .add_device_to_group(0x5555, 1, 0x0007, "Living room")
+                    .await,
+                Err(CoordinatorError::ZclStatus(0x8a))
+            ));
+            assert_eq!(
+                coordinator
+                    .view_device_group(0x5555, 1, 0x0007)
+                    .await
+                    .unwrap(),
+                "Living room"
+            );
+            assert_eq!(
+                coordinator.device_groups(0x5555, 1).await.unwrap(),
+                vec![0x0007]
+            );
+            coordinator
+                .remove_device_from_group(0x5555, 1, 0x0007)
+                .await
+                .unwrap();
+            assert!(matches!(
+                coordinator.view_device_group(0x5555, 1, 0x0007).await,
+                Err(CoordinatorError::ZclStatus(0x8b))
+            ));
+
+            coordinator
+                .send_zcl_frame_to_group(0x0007, &toggle(9), None)
+                .await
+                .unwrap();
+        });
+        let sent = simulator.with(|s| {
+            s.received
+                .iter()
+                .filter(|p| p.command == DataRequestExtRequest::id())
+                .filter_map(|p| p.to_command_request::<DataRequestExtRequest>().ok())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].destination_address_mode,
+            AddressMode::AddrGroup as u8
+        );
+        assert_eq!(sent[0].destination_address, [0x07, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(sent[0].data.items, toggle(9).to_bytes().unwrap());
+    }
+
+    #[test]
+    fn test_join_policy() {
+        let (coordinator, simulator) = simulated();
+        let mut events = Box::pin(coordinator.subscribe_events());
+        let neighbor = [0xd1, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8];
+        let stranger = [0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8];
+        simulator.with(|s| {
+            s.remote_devices.insert(
+                0x6666,
+                RemoteDevice {
+                    ieee_address: stranger,
+                    logical_type: LogicalType::EndDevice,
+                    mac_capabilities: 0x80,
+                    ..Default::default()
+                },
+            )
+        });
+        coordinator.set_join_policy(JoinPolicy::Allowlist(HashSet::from([neighbor])));
+
+        simulator
+            .inject_tc_device_index(0x6666, stranger, 0x0000)
+            .unwrap();
+        simulator
+            .inject_end_device_announce(0x6666, stranger, 0x80)
+            .unwrap();
+        // The announce is dropped, the next event is the device leaving as asked
+        let received = block_on(async { [events.next().await, events.next().await] });
+        assert_eq!(
+            received,
+            [
+                Some(ZigbeeEvent::DeviceRejected {
+                    network_address: 0x6666,
+                    ieee_address: stranger,
+                }),
+                Some(ZigbeeEvent::DeviceLeave(Either::Left((
+                    Some(0x6666),
+                    stranger
+                )))),
+            ]
+        );
+        let leave_requests = simulator.with(|s| {
+            s.received
+                .iter()
+                .filter(|p| p.command == ManagementLeaveRequest::id())
+                .filter_map(|p| p.to_command_request::<ManagementLeaveRequest>().ok())
+                .map(|r| (r.destination_address, r.device_address.ieee_address))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(leave_requests, vec![(0x6666, stranger)]);
+        assert!(coordinator.devices().by_ieee_address(&stranger).is_none());
+
+        simulator
+            .inject_tc_device_index(0x5555, neighbor, 0x0000)
+            .unwrap();
+        assert_eq!(
+            block_on(events.next()),
+            Some(ZigbeeEvent::DeviceJoined {
+                network_address: 0x5555,
+                ieee_address: neighbor,
+            })
+        );
+        assert!(coordinator.devices().by_ieee_address(&neighbor).is_some());
+    }
+
+    #[test]
+    fn test_join_policy_removal_waits_the_device_timeout() {
+        let (coordinator, simulator) = simulated();
+        let mut events = Box::pin(coordinator.subscribe_events());
+        let stranger = [0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8];
+        coordinator.set_join_policy(JoinPolicy::Allowlist(HashSet::new()));
+        // Set after the coordinator was built, the removal from the read thread must see it
+        coordinator.set_device_timeout(Duration::from_millis(100));
+
+        // Nothing answers at 0x6666, so the leave request times out
+        let started = std::time::Instant::now();
+        simulator
+            .inject_tc_device_index(0x6666, stranger, 0x0000)
+            .unwrap();
+        let received = block_on(async { [events.next().await, events.next().await] });
+        assert_eq!(
+            received[1],
+            Some(ZigbeeEvent::DeviceLeave(Either::Left((
+                Some(0x6666),
+                stranger
+            ))))
+        );
+        assert!(started.elapsed() < DEFAULT_DEVICE_TIMEOUT);
+    }
+
+    #[test]
+    fn test_install_codes() {
+        let (coordinator, simulator) = simulated();
+        let ieee_address = [0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8];
+        let install_code = [
+            0x83, 0xfe, 0xd3, 0x40, 0x7a, 0x93, 0x97, 0x23, 0xa5, 0xc6, 0x39, 0xb2, 0x69, 0x16,
+            0xd5, 0x05, 0xc3, 0xb5,
+        ];
+        assert_eq!(install_code_crc(&install_code[..16]), 0xb5c3);
+        block_on(coordinator.add_install_code(ieee_address, &install_code)).unwrap();
+        assert_eq!(
+            simulator.with(|s| s.install_codes.get(&ieee_address).cloned()),
+            Some(install_code.to_vec())
+        );
+
+        let mut corrupted = install_code;
+        corrupted[3] ^= 0x01;
+        assert!(matches!(
+            block_on(coordinator.add_install_code(ieee_address, &corrupted)),
+            Err(CoordinatorError::InvalidInstallCode)
+        ));
+        assert!(matches!(
+            block_on(coordinator.add_install_code(ieee_address, &install_code[2..])),
+            Err(CoordinatorError::InvalidInstallCode)
+        ));
+
+        let key = [0x66; 16];
+        block_on(coordinator.add_install_code_key(ieee_address, key)).unwrap();
+        assert_eq!(
+            simulator.with(|s| s.install_codes.get(&ieee_address).cloned()),
+            Some(key.to_vec())
+        );
+
+        let (coordinator, simulator) = simulated();
+        simulator.with(|s| s.version.product = ZnpVersion::ZStack12 as u8);
+        assert!(matches!(
+            block_on(coordinator.add_install_code_key(ieee_address, key)),
+            Err(CoordinatorError::UnsupportedFirmware)
+        ));
+    }
+
+    #[test]
+    fn test_permit_join_schedule() {
+        let schedule =
+            |seconds| permit_join_schedule(Duration::from_secs_f64(seconds)).collect::<Vec<_>>();
+        let at = |offset| Duration::from_secs(offset);
+        assert_eq!(schedule(60.0), vec![(at(0), 60)]);
+        assert_eq!(schedule(0.1), vec![(at(0), 1)]);
+        // Ten minutes take three requests, each sent 5 seconds before the last one runs out
+        assert_eq!(
+            schedule(600.0),
+            vec![(at(0), 254), (at(249), 254), (at(498), 102)]
+        );
+        assert_eq!(schedule(254.5), vec![(at(0), 254), (at(249), 6)]);
+    }
+
+    #[test]
+    fn test_join_window() {
+        let (coordinator, simulator) = simulated();
+        let mut events = Box::pin(coordinator.subscribe_events());
+        let permit_join = |permit, network_address| {
+            block_on(coordinator.permit_join(permit, network_address)).unwrap()
+        };
+
+        permit_join(PermitJoin::Forever, None);
+        assert_eq!(
+            block_on(events.next()),
+            Some(ZigbeeEvent::PermitJoin(PermitJoin::Forever))
+        );
+        // The stack's own indication only repeats it
+        simulator
+            .inject_command(&PermitJoinIndRequest { duration: 255 })
+            .unwrap();
+
+        // Reported closed once it ran out
+        let window = Duration::from_millis(1500);
+        permit_join(PermitJoin::For(window), Some(0x1234));
+        let received = block_on(async { [events.next().await, events.next().await] });
+        assert_eq!(
+            received,
+            [
+                Some(ZigbeeEvent::PermitJoin(PermitJoin::For(window))),
+                Some(ZigbeeEvent::PermitJoin(PermitJoin::Close)),
+            ]
+        );
+
+        // Closing takes over from the window that was open
+        permit_join(PermitJoin::For(Duration::from_secs(1)), None);
+        permit_join(PermitJoin::For(Duration::ZERO), None);
+        let received = block_on(async { [events.next().await, events.next().await] });
+        assert_eq!(
+            received,
+            [
+                Some(ZigbeeEvent::PermitJoin(PermitJoin::For(
+                    Duration::from_secs(1)
+                ))),
+                Some(ZigbeeEvent::PermitJoin(PermitJoin::Close)),
+            ]
+        );
+        block_on(delay(Duration::from_millis(1300)));
+        assert!(events.next().now_or_never().is_none());
+
+        let requests = simulator.with(|s| {
+            s.received
+                .iter()
+                .filter(|p| p.command == ManagementPermitJoinRequest::id())
+                .filter_map(|p| p.to_command_request::<ManagementPermitJoinRequest>().ok())
+                .map(|r| (r.address_mode, r.destination_address, r.duration))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            requests,
+            vec![
+                (15, 0xfffc, 255),
+                (2, 0x1234, 2),
+                (15, 0xfffc, 1),
+                (15, 0xfffc, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_device() {
+        let (coordinator, simulator) = simulated();
+        coordinator.set_device_timeout(Duration::from_millis(50));
+        let mut events = Box::pin(coordinator.subscribe_events());
+        let plug = [0x71, 0x72, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78];
+        let bulb = [0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88];
+        let gone = [0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98];
+        let sensor = [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8];
+        let device = |ieee_address, logical_type| RemoteDevice {
+            ieee_address,
+            logical_type,
+            ..Default::default()
+        };
+        simulator.with(|s| {
+            s.remote_devices
+                .insert(0x7001, device(plug, LogicalType::Router));
+            s.remote_devices
+                .insert(0x7002, device(bulb, LogicalType::Router));
+            s.remote_devices
+                .insert(0x7004, device(sensor, LogicalType::EndDevice));
+            for ieee_address in [plug, bulb, gone, sensor] {
+                s.install_codes.insert(ieee_address, vec![0x66; 16]);
+            }
+        });
+        for (network_address, ieee_address) in [
+            (0x7001, plug),
+            (0x7002, bulb),
+            (0x7003, gone),
+            (0x7004, sensor),
+        ] {
+            coordinator
+                .devices()
+                .update_address(ieee_address, network_address)
+                .unwrap();
+        }
+        let known = |ieee_address| {
+            (
+                coordinator
+                    .devices()
+                    .by_ieee_address(&ieee_address)
+                    .is_some(),
+                simulator.with(|s| s.install_codes.contains_key(&ieee_address)),
+            )
+        };
+
+        let options = RemoveOptions {
+            rejoin: false,
+            remove_children: true,
+        };
+        block_on(coordinator.remove_device(plug, 0x7001, options)).unwrap();
+        assert_eq!(
+            block_on(events.next()),
+            Some(ZigbeeEvent::DeviceLeave(Either::Left((Some(0x7001), plug))))
+        );
+        assert_eq!(known(plug), (false, false));
+        assert!(simulator.with(|s| !s.remote_devices.contains_key(&0x7001)));
+
+        // Coming back right away, so nothing is forgotten
+        let options = RemoveOptions {
+            rejoin: true,
+            remove_children: false,
+        };
+        block_on(coordinator.remove_device(bulb, 0x7002, options)).unwrap();
+        assert_eq!(known(bulb), (true, true));
+
+        // Nobody answers for a device that is gone already
+        block_on(coordinator.remove_device(gone, 0x7003, RemoveOptions::default())).unwrap();
+        assert_eq!(
+            block_on(events.next()),
+            Some(ZigbeeEvent::DeviceLeave(Either::Left((Some(0x7003), gone))))
+        );
+        assert_eq!(known(gone), (false, false));
+
+        // A device refusing to leave is dropped as well
+        block_on(coordinator.remove_device(sensor, 0x7004, RemoveOptions::default())).unwrap();
+        assert_eq!(
+            block_on(events.next()),
+            Some(ZigbeeEvent::DeviceLeave(Either::Left((
+                Some(0x7004),
+                sensor
+            ))))
+        );
+        assert_eq!(known(sensor), (false, false));
+
+        let leave_requests = simulator.with(|s| {
+            s.received
+                .iter()
+                .filter(|p| p.command == ManagementLeaveRequest::id())
+                .filter_map(|p| p.to_command_request::<ManagementLeaveRequest>().ok())
+                .map(|r| (r.destination_address, r.remove_children_rejoin))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            leave_requests,
+            vec![
+                (0x7001, 0x02),
+                (0x7002, 0x01),
+                (0x7003, 0x00),
+                (0x7003, 0x00),
+                (0x7003, 0x00),
+                (0x7004, 0x00)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_zcl_frame_resolves_ieee_address() {
+        let (coordinator, simulator) = simulated();
+        register_default_endpoint(&coordinator);
+        let ieee_address = [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8];
+        coordinator
+            .devices()
+            .update_address(ieee_address, 0x0034)
+            .unwrap();
+
+        // The caller's network address is stale, the registry knows better
+        let address = ieee802154::mac::Address::Extended(
+            ieee802154::mac::PanId(0x1a62),
+            ieee802154::mac::ExtendedAddress(u64::from_le_bytes(ieee_address)),
+        );
+        block_on(coordinator.send_zcl_frame(
+            &address,
+            0x1234,
+            1,
+            &toggle(1),
+            std::time::Duration::from_secs(1),
+            true,
+            true,
+            None,
+        ))
+        .unwrap();
+        let destination = simulator.with(|s| {
+            let request = s
+                .received
+                .iter()
+                .rfind(|p| p.command == DataRequestRequest::id())
+                .unwrap();
+            u16::from_le_bytes([request.payload[0], request.payload[1]])
+        });
+        assert_eq!(destination, 0x0034);
+    }
+
+    #[test]
+    fn test_form_network_only_when_config_differs() {
+        let (coordinator, simulator) = simulated();
+        let commissionings = || {
+            simulator.with(|s| {
+                s.received
+                    .iter()
+                    .filter(|p| p.command == BdbStartCommissioningRequest::id())
+                    .count()
+            })
+        };
+        let options = NetworkOptions {
+            pan_id: 0x1234,
+            extended_pan_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            channel_list: vec![15, 20],
+            ..Default::default()
+        };
+        block_on(async {
+            coordinator.form_network(&options).await.unwrap();
+            // formation and steering
+            assert_eq!(commissionings(), 2);
+            let nib: Nib = coordinator.nv_adapter.read().await.unwrap();
+            assert_eq!(nib.nwk_pan_id, 0x1234);
+            assert_eq!(nib.nwk_logical_channel, 15);
+            assert_eq!(
+                simulator.nv_item(NvItemId::PreCfgKey.into()).unwrap(),
+                options.network_key
+            );
+
+            // Same configuration, the network is only restored
+            coordinator.form_network(&options).await.unwrap();
+            assert_eq!(commissionings(), 2);
+
+            let options = NetworkOptions {
+                pan_id: 0x4321,
+                ..options
+            };
+            coordinator.form_network(&options).await.unwrap();
+            assert_eq!(commissionings(), 4);
+            let device_info = coordinator.device_info().await.unwrap();
+            assert_eq!(device_info.device_state, DeviceState::Coordinator as u8);
+        });
+    }
+
+    #[test]
+    fn test_form_network_refusals() {
+        let (coordinator, simulator) = simulated();
+        let options = NetworkOptions {
+            channel_list: vec![15, 32],
+            ..Default::default()
+        };
+        assert!(matches!(
+            block_on(coordinator.form_network(&options)),
+            Err(CoordinatorError::InvalidChannel)
+        ));
+
+        simulator.with(|s| s.version.product = ZnpVersion::ZStack12 as u8);
+        let startup_option = simulator.nv_item(NvItemId::StartupOption.into());
+        assert!(matches!(
+            block_on(coordinator.form_network(&NetworkOptions::default())),
+            Err(CoordinatorError::UnsupportedFirmware)
+        ));
+        // Refused before anything was wiped
+        assert_eq!(
+            simulator.nv_item(NvItemId::StartupOption.into()),
+            startup_option
+        );
+    }
+
+    #[test]
+    fn test_start_and_startup_over_simulator() {
+        let (coordinator, simulator) = simulated();
+        block_on(async {
+            coordinator.start().await.unwrap();
+            let version = coordinator.version().await.unwrap();
+            assert_eq!(version, simulator.with(|s| s.version.clone()));
+            coordinator.begin_startup().await.unwrap();
+            let device_info = coordinator.device_info().await.unwrap();
+            assert_eq!(device_info.device_state, DeviceState::Coordinator as u8);
+            coordinator
+                .permit_join(PermitJoin::For(Duration::from_secs(60)), None)
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_startup_reports_network_state() {
+        let (coordinator, _simulator) = simulated();
+        let mut events = Box::pin(coordinator.subscribe_events());
+        block_on(coordinator.begin_startup()).unwrap();
+        // Already there once startup returns
+        assert_eq!(
+            events.next().now_or_never(),
+            Some(Some(ZigbeeEvent::NetworkState(DeviceState::Coordinator)))
+        );
+    }
+
+    #[test]
+    fn test_restore_from_backup() {
+        let (coordinator, simulator) = simulated();
+        let seed = [0x5a; 16];
+        let hashed_device = [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8];
+        let hashed_key = hashed_link_key(&seed, &hashed_device, 3);
+        let json = serde_json::json!({
+            "metadata": {
+                "format": "zigpy/open-coordinator-backup",
+                "version": 1,
+                "source": "zigpy-znp@0.12.0",
+            },
+            "stack_specific": {"zstack": {"tclk_seed": "5a".repeat(16)}},
+            "coordinator_ieee": "00124b0001020304",
+            "pan_id": "1a62",
+            "extended_pan_id": "0102030405060708",
+            "nwk_update_id": 2,
+            "security_level": 5,
+            "channel": 15,
+            "channel_mask": [15],
+            "network_key": {"key": "11".repeat(16), "sequence_number": 1, "frame_counter": 1000},
+            "devices": [
+                {
+                    "nwk_address": "1234",
+                    "ieee_address": "a8a7a6a5a4a3a2a1",
+                    "is_child": true,
+                    "link_key": {
+                        "key": hashed_key.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+                        "rx_counter": 20,
+                        "tx_counter": 10,
+                    },
+                },
+                {
+                    "nwk_address": "5678",
+                    "ieee_address": "b8b7b6b5b4b3b2b1",
+                    "is_child": false,
+                    "link_key": {"key": "22".repeat(16), "rx_counter": 8, "tx_counter": 7},
+                },
+            ],
+        });
+        let backup = NetworkBackup::from_json(&json.to_string()).unwrap();
+
+        block_on(async {
+            coordinator.start().await.unwrap();
+            coordinator.restore(&backup).await.unwrap();
+            let restored = coordinator.backup().await.unwrap();
+            assert_eq!(restored.pan_id, 0x1a62);
+            assert_eq!(restored.extended_pan_id, backup.extended_pan_id);
+            assert_eq!(restored.channel, 15);
+            assert_eq!(restored.nwk_update_id, 2);
+            assert_eq!(restored.network_key.key, [0x11; 16]);
+            assert_eq!(restored.network_key.sequence_number, 1);
+            assert_eq!(restored.network_key.frame_counter, 3500);
+            assert_eq!(restored.devices.len(), 2);
+            for (restored, device) in restored.devices.iter().zip(&backup.devices) {
+                assert_eq!(restored.ieee_address, device.ieee_address);
+                assert_eq!(restored.nwk_address, device.nwk_address);
+                assert_eq!(restored.is_child, device.is_child);
+                let (restored, key) = (
+                    restored.link_key.as_ref().unwrap(),
+                    device.link_key.as_ref().unwrap(),
+                );
+                assert_eq!(restored.key, key.key);
+                assert_eq!(restored.tx_counter, key.tx_counter + 2500);
+            }
+        });
+        assert_eq!(
+            simulator.with(|s| s.device_state),
+            DeviceState::Coordinator as u8
+        );
+
+        // Z-Stack 1.2 can't hold a Z-Stack 3 network
+        let (coordinator, simulator) = simulated();
+        simulator.with(|s| s.version.product = 0);
+        block_on(async {
+            coordinator.start().await.unwrap();
+            assert!(matches!(
+                coordinator.restore(&backup).await,
+                Err(CoordinatorError::UnsupportedFirmware)
+            ));
+        });
+    }
+}
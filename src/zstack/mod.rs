@@ -1,4 +1,7 @@
+pub mod backup;
 #[cfg(feature = "cc2531x")]
 pub mod cc253x;
 pub mod nv_memory;
+#[cfg(any(test, feature = "simulator"))]
+pub mod simulator;
 pub mod unpi;
@@ -0,0 +1,39 @@
+use super::NvLayout;
+use deku::{DekuRead, DekuWrite};
+
+/// Bits of [`AddrMgrEntry::user`]
+pub const ADDR_MGR_USER_ASSOCIATE: u8 = 0x01;
+pub const ADDR_MGR_USER_SECURITY: u8 = 0x02;
+pub const ADDR_MGR_USER_BINDING: u8 = 0x04;
+
+/// Device known to the address manager. A single `AddrMgr` item holds the whole table on legacy
+/// firmwares, `ZcdNvExAddrmgr` has one entry per sub ID.
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+#[deku(ctx = "layout: NvLayout", ctx_default = "NvLayout::Packed")]
+pub struct AddrMgrEntry {
+    /// What the entry is used for, 0 when it's free
+    pub user: u8,
+    #[deku(endian = "little", pad_bytes_before = "layout.padding(1)")]
+    pub nwk_addr: u16,
+    pub ext_addr: [u8; 8],
+}
+
+impl AddrMgrEntry {
+    /// Free slot, as the stack initializes them
+    pub fn empty() -> Self {
+        AddrMgrEntry {
+            user: 0,
+            nwk_addr: 0xfffe,
+            ext_addr: [0xff; 8],
+        }
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.user != 0 && self.ext_addr != [0x00; 8] && self.ext_addr != [0xff; 8]
+    }
+
+    /// Directly associated to the coordinator
+    pub fn is_child(&self) -> bool {
+        self.user & ADDR_MGR_USER_ASSOCIATE != 0
+    }
+}
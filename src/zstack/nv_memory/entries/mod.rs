@@ -1 +1,134 @@
+use super::NvItemId;
+use crate::zstack::unpi::constants::sys::ZnpVersion;
+use deku::{
+    no_std_io::Cursor, reader::Reader, writer::Writer, DekuContainerRead, DekuContainerWrite,
+    DekuError, DekuRead, DekuReader, DekuWrite, DekuWriter,
+};
+
+pub mod addr_mgr;
 pub mod nib;
+pub mod security;
+
+/// Memory layout of the items that are raw C struct dumps, like the NIB
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NvLayout {
+    /// 8051 based CC2530/CC2531, no padding
+    Packed,
+    /// ARM based CC26x2 and CC2538, fields aligned to their size
+    Aligned,
+}
+
+impl NvLayout {
+    /// Layout used by the firmware family. CC2538 runs Z-Stack 3.0.x aligned, so prefer the
+    /// layout of the NIB when it can be read.
+    pub fn for_version(version: ZnpVersion) -> Self {
+        match version {
+            ZnpVersion::ZStack3x0 => NvLayout::Aligned,
+            ZnpVersion::ZStack12 | ZnpVersion::ZStack30x => NvLayout::Packed,
+        }
+    }
+
+    pub(crate) fn padding(self, bytes: usize) -> usize {
+        match self {
+            NvLayout::Packed => 0,
+            NvLayout::Aligned => bytes,
+        }
+    }
+}
+
+/// Decodes the entries of a table of C structs, as returned by
+/// [`NvMemoryAdapter::read_table`](super::nv_item::NvMemoryAdapter::read_table). Each NV entry
+/// may hold several structs, like the legacy `AddrMgr` item.
+pub fn parse_table<T>(entries: &[Vec<u8>], layout: NvLayout) -> Result<Vec<T>, DekuError>
+where
+    T: for<'a> DekuReader<'a, NvLayout>,
+{
+    let mut items = Vec::new();
+    for entry in entries {
+        let mut cursor = Cursor::new(entry);
+        let mut reader = Reader::new(&mut cursor);
+        while !reader.end() {
+            items.push(T::from_reader_with_ctx(&mut reader, layout)?);
+        }
+    }
+    Ok(items)
+}
+
+/// Value of an NV item with a known layout, read and written with
+/// [`NvMemoryAdapter::read`](super::nv_item::NvMemoryAdapter::read) and
+/// [`NvMemoryAdapter::write`](super::nv_item::NvMemoryAdapter::write)
+pub trait NvEntry: Sized {
+    const ID: NvItemId;
+
+    fn from_nv_bytes(data: &[u8]) -> Result<Self, DekuError>;
+    fn to_nv_bytes(&self) -> Result<Vec<u8>, DekuError>;
+}
+
+// Entries stored exactly as deku encodes them
+macro_rules! nv_entry {
+    ($name:ident, $id:expr) => {
+        impl NvEntry for $name {
+            const ID: NvItemId = $id;
+
+            fn from_nv_bytes(data: &[u8]) -> Result<Self, DekuError> {
+                Ok(Self::from_bytes((data, 0))?.1)
+            }
+
+            fn to_nv_bytes(&self) -> Result<Vec<u8>, DekuError> {
+                self.to_bytes()
+            }
+        }
+    };
+}
+
+/// IEEE address of the coordinator
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct ExtAddr(pub [u8; 8]);
+
+nv_entry!(ExtAddr, NvItemId::ExtAddr);
+
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct PanId(pub u16);
+
+nv_entry!(PanId, NvItemId::PanId);
+
+/// Least significant byte first
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct ExtendedPanId(pub [u8; 8]);
+
+nv_entry!(ExtendedPanId, NvItemId::ExtendedPanId);
+
+/// Bitmask of the channels the stack may use
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct ChanList(pub u32);
+
+nv_entry!(ChanList, NvItemId::ChanList);
+
+/// Network key configured before forming the network
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct PreCfgKey(pub [u8; 16]);
+
+nv_entry!(PreCfgKey, NvItemId::PreCfgKey);
+
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct NwkKey(pub [u8; 16]);
+
+nv_entry!(NwkKey, NvItemId::NwkKey);
+
+/// Network key in use and its sequence number
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct NwkActiveKeyInfo(pub nib::NwkKeyDescriptor);
+
+nv_entry!(NwkActiveKeyInfo, NvItemId::NwkActiveKeyInfo);
+
+/// Encodes one entry of a table of C structs
+pub fn encode_entry<T: DekuWriter<NvLayout>>(
+    entry: &T,
+    layout: NvLayout,
+) -> Result<Vec<u8>, DekuError> {
+    let mut cursor = Cursor::new(Vec::new());
+    let mut writer = Writer::new(&mut cursor);
+    entry.to_writer(&mut writer, layout)?;
+    writer.finalize()?;
+    Ok(cursor.into_inner())
+}
@@ -1,9 +1,21 @@
-use deku::DekuRead;
+use super::{encode_entry, NvEntry, NvLayout};
+use crate::{
+    coordinator::{CoordinatorError, ZIGBEE_CHANNELS},
+    zstack::nv_memory::NvItemId,
+};
+use deku::{no_std_io::Cursor, reader::Reader, DekuError, DekuRead, DekuReader, DekuWrite};
+
+pub const NIB_PACKED_LEN: usize = 110;
+pub const NIB_ALIGNED_LEN: usize = 116;
 
 //TODO: non_snake_case because of deku. How to remove/fix?
 #[allow(non_snake_case)]
-#[derive(Debug, DekuRead)]
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+#[deku(ctx = "layout: NvLayout", ctx_default = "NvLayout::Packed")]
 pub struct Nib {
+    /// Layout the NIB was read with, and will be written back with
+    #[deku(skip, default = "layout")]
+    pub layout: NvLayout,
     pub sequence_num: u8,
     pub passive_ack_timeout: u8,
     pub max_broadcast_retries: u8,
@@ -19,26 +31,31 @@ pub struct Nib {
     pub security_level: u8,
     pub sym_link: u8,
     pub capability_flags: u8,
+    #[deku(endian = "little", pad_bytes_before = "layout.padding(1)")]
     pub transaction_persistence_time: u16,
     pub nwk_protocol_version: u8,
     pub route_discovery_time: u8,
     pub route_expiry_time: u8,
+    #[deku(endian = "little", pad_bytes_before = "layout.padding(1)")]
     pub nwk_dev_address: u16,
     pub nwk_logical_channel: u8,
+    #[deku(endian = "little", pad_bytes_before = "layout.padding(1)")]
     pub nwk_coord_address: u16,
-    #[deku(endian = "little")]
     pub nwk_coord_ext_address: [u8; 8],
+    #[deku(endian = "little")]
     pub nwk_pan_id: u16,
     pub nwk_state: u8,
+    #[deku(endian = "little", pad_bytes_before = "layout.padding(1)")]
     pub channel_list: u32,
     pub beacon_order: u8,
     pub super_frame_order: u8,
     pub scan_duration: u8,
     pub batt_life_ext: u8,
+    #[deku(endian = "little")]
     pub allocated_router_addresses: u32,
+    #[deku(endian = "little")]
     pub allocated_end_device_addresses: u32,
     pub node_depth: u8,
-    #[deku(endian = "little")]
     pub extended_panid: [u8; 8],
     pub nwk_key_loaded: u8,
     pub spare1: NwkKeyDescriptor,
@@ -52,15 +69,160 @@ pub struct Nib {
     pub nwk_concentrator_discovery_time: u8,
     pub nwk_concentrator_radius: u8,
     pub nwk_all_fresh: u8,
+    #[deku(endian = "little", pad_bytes_before = "layout.padding(1)")]
     pub nwk_manager_addr: u16,
+    #[deku(endian = "little")]
     pub nwk_total_transmissions: u16,
+    // The aligned struct is padded to a multiple of 4 bytes
+    #[deku(pad_bytes_after = "layout.padding(1)")]
     pub nwk_update_id: u8,
 }
 
+impl Nib {
+    /// Length of the NV item holding a NIB in `layout`
+    pub fn item_len(layout: NvLayout) -> usize {
+        match layout {
+            NvLayout::Packed => NIB_PACKED_LEN,
+            NvLayout::Aligned => NIB_ALIGNED_LEN,
+        }
+    }
+
+    /// The NIB is the only item whose length gives the platform away
+    pub fn layout_for_len(len: usize) -> Option<NvLayout> {
+        match len {
+            NIB_PACKED_LEN => Some(NvLayout::Packed),
+            NIB_ALIGNED_LEN => Some(NvLayout::Aligned),
+            _ => None,
+        }
+    }
+
+    /// Decodes a NIB, the layout being told apart by the item length
+    pub fn from_nv_bytes(data: &[u8]) -> Result<Self, DekuError> {
+        let layout = Nib::layout_for_len(data.len()).ok_or_else(|| {
+            DekuError::Parse(format!("unexpected NIB length {}", data.len()).into())
+        })?;
+        let mut cursor = Cursor::new(data);
+        let mut reader = Reader::new(&mut cursor);
+        Nib::from_reader_with_ctx(&mut reader, layout)
+    }
+
+    /// Encodes the NIB in the layout it was read with
+    pub fn to_nv_bytes(&self) -> Result<Vec<u8>, DekuError> {
+        encode_entry(self, self.layout)
+    }
+
+    pub fn pan_id(&self) -> u16 {
+        self.nwk_pan_id
+    }
+
+    pub fn set_pan_id(&mut self, pan_id: u16) {
+        self.nwk_pan_id = pan_id;
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.nwk_logical_channel
+    }
+
+    /// Moves the network to `channel`, which becomes the only one in the channel list. Channels
+    /// outside [`ZIGBEE_CHANNELS`] are refused.
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), CoordinatorError> {
+        if !ZIGBEE_CHANNELS.contains(&channel) {
+            return Err(CoordinatorError::InvalidChannel);
+        }
+        self.nwk_logical_channel = channel;
+        self.channel_list = 1 << channel;
+        Ok(())
+    }
+
+    /// Least significant byte first
+    pub fn extended_pan_id(&self) -> [u8; 8] {
+        self.extended_panid
+    }
+
+    pub fn set_extended_pan_id(&mut self, extended_pan_id: [u8; 8]) {
+        self.extended_panid = extended_pan_id;
+    }
+
+    pub fn nwk_update_id(&self) -> u8 {
+        self.nwk_update_id
+    }
+
+    pub fn set_nwk_update_id(&mut self, nwk_update_id: u8) {
+        self.nwk_update_id = nwk_update_id;
+    }
+}
+
+impl NvEntry for Nib {
+    const ID: NvItemId = NvItemId::NIB;
+
+    fn from_nv_bytes(data: &[u8]) -> Result<Self, DekuError> {
+        Nib::from_nv_bytes(data)
+    }
+
+    fn to_nv_bytes(&self) -> Result<Vec<u8>, DekuError> {
+        Nib::to_nv_bytes(self)
+    }
+}
+
 //TODO: non_snake_case because of deku. How to remove/fix?
 #[allow(non_snake_case)]
-#[derive(Debug, DekuRead)]
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
 pub struct NwkKeyDescriptor {
     pub key_seq_num: u8,
     pub key: [u8; 16],
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pan id, channel, channel list, extended pan id, manager address and update id offsets
+    fn nib_bytes(len: usize, offsets: [usize; 6]) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        data[offsets[0]..offsets[0] + 2].copy_from_slice(&0x1a62u16.to_le_bytes());
+        data[offsets[1]] = 15;
+        data[offsets[2]..offsets[2] + 4].copy_from_slice(&(1u32 << 15).to_le_bytes());
+        data[offsets[3]..offsets[3] + 8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        data[offsets[4]..offsets[4] + 2].copy_from_slice(&0x0000u16.to_le_bytes());
+        data[offsets[5]] = 3;
+        data
+    }
+
+    #[test]
+    fn test_nib_layouts() {
+        for (layout, data) in [
+            (
+                NvLayout::Packed,
+                nib_bytes(NIB_PACKED_LEN, [33, 22, 36, 53, 105, 109]),
+            ),
+            (
+                NvLayout::Aligned,
+                nib_bytes(NIB_ALIGNED_LEN, [36, 24, 40, 57, 110, 114]),
+            ),
+        ] {
+            let mut nib = Nib::from_nv_bytes(&data).unwrap();
+            assert_eq!(nib.layout, layout);
+            assert_eq!(nib.pan_id(), 0x1a62);
+            assert_eq!(nib.channel(), 15);
+            assert_eq!(nib.channel_list, 1 << 15);
+            assert_eq!(nib.extended_pan_id(), [1, 2, 3, 4, 5, 6, 7, 8]);
+            assert_eq!(nib.nwk_update_id(), 3);
+            assert_eq!(nib.to_nv_bytes().unwrap(), data);
+
+            nib.set_channel(25).unwrap();
+            nib.set_nwk_update_id(4);
+            let patched = Nib::from_nv_bytes(&nib.to_nv_bytes().unwrap()).unwrap();
+            assert_eq!(patched, nib);
+            assert_eq!(patched.channel_list, 1 << 25);
+        }
+        assert!(Nib::from_nv_bytes(&[0; 100]).is_err());
+    }
+
+    #[test]
+    fn test_set_channel_outside_the_band() {
+        let mut nib = Nib::from_nv_bytes(&[0; NIB_PACKED_LEN]).unwrap();
+        assert!(nib.set_channel(32).is_err());
+        assert!(nib.set_channel(10).is_err());
+        assert_eq!(nib.channel(), 0);
+    }
+}
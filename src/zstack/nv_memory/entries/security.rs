@@ -0,0 +1,70 @@
+use super::NvLayout;
+use deku::{DekuRead, DekuWrite};
+
+/// Outgoing frame counter of the network key for one extended PAN ID, entry of
+/// `ExNwkSecMaterialTable`. The entry with an all `0xff` extended PAN ID is the generic one.
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+#[deku(ctx = "_layout: NvLayout", ctx_default = "NvLayout::Packed")]
+pub struct NwkSecMaterialDesc {
+    #[deku(endian = "little")]
+    pub frame_counter: u32,
+    pub extended_pan_id: [u8; 8],
+}
+
+/// `key_attributes` of a link key the device confirmed it uses
+pub const KEY_ATTRIBUTES_VERIFIED: u8 = 0x02;
+
+/// Device entry of the hashed trust center link key table, `ExTclkTable`. The key itself is
+/// derived from the TCLK seed, see [`hashed_link_key`].
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+#[deku(ctx = "layout: NvLayout", ctx_default = "NvLayout::Packed")]
+pub struct TclkDevEntry {
+    #[deku(endian = "little")]
+    pub tx_frame_counter: u32,
+    #[deku(endian = "little")]
+    pub rx_frame_counter: u32,
+    pub ext_addr: [u8; 8],
+    pub key_attributes: u8,
+    pub key_type: u8,
+    #[deku(pad_bytes_after = "layout.padding(1)")]
+    pub seed_shift_ic_index: u8,
+}
+
+/// Link key stored as is, entry of `ApsLinkKeyDataStart` or `ZcdNvExApsKeyDataTable`
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+#[deku(ctx = "_layout: NvLayout", ctx_default = "NvLayout::Packed")]
+pub struct ApsKeyDataEntry {
+    pub key: [u8; 16],
+    #[deku(endian = "little")]
+    pub tx_frame_counter: u32,
+    #[deku(endian = "little")]
+    pub rx_frame_counter: u32,
+}
+
+/// `authentication_state` of a device that got its own link key
+pub const AUTHENTICATED_CBCK: u8 = 0x01;
+
+/// Entry of `ApsLinkKeyTable`, which ties an address manager entry to its key in the APS key
+/// data table
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+#[deku(ctx = "layout: NvLayout", ctx_default = "NvLayout::Packed")]
+pub struct ApsLinkKeyTableEntry {
+    #[deku(endian = "little")]
+    pub addr_mgr_index: u16,
+    /// Legacy item ID of the key, counted from `ApsLinkKeyDataStart`
+    #[deku(endian = "little")]
+    pub link_key_nv_id: u16,
+    #[deku(pad_bytes_after = "layout.padding(1)")]
+    pub authentication_state: u8,
+}
+
+/// Unique link key Z-Stack 3 gives a device: the TCLK seed rotated by `shift` bytes, XORed with
+/// the device IEEE address (least significant byte first) twice
+pub fn hashed_link_key(seed: &[u8; 16], ieee_address: &[u8; 8], shift: u8) -> [u8; 16] {
+    let mut key = *seed;
+    key.rotate_left(shift as usize % seed.len());
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte ^= ieee_address[i % ieee_address.len()];
+    }
+    key
+}
@@ -1,3 +1,5 @@
+use crate::zstack::unpi::constants::sys::{ZnpVersion, NV_SYSTEM_ID_ZSTACK};
+
 pub mod entries;
 pub mod nv_item;
 
@@ -245,3 +247,134 @@ impl From<NvItemId> for u16 {
         }
     }
 }
+
+/// Location of an item in the NV memory of a given firmware
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NvAddress {
+    /// Addressed through the OSAL_NV_* commands
+    Legacy(u16),
+    /// Extended item of Z-Stack 3.x.0, addressed through the SYS_NV_* commands
+    Extended {
+        sys_id: u8,
+        item_id: u16,
+        sub_id: u16,
+    },
+}
+
+impl NvAddress {
+    /// Address of the `index`th entry of a table starting at this address. Legacy tables take
+    /// consecutive item IDs while extended ones use the sub ID.
+    pub fn entry(self, index: u16) -> Self {
+        match self {
+            NvAddress::Legacy(id) => NvAddress::Legacy(id + index),
+            NvAddress::Extended {
+                sys_id,
+                item_id,
+                sub_id,
+            } => NvAddress::Extended {
+                sys_id,
+                item_id,
+                sub_id: sub_id + index,
+            },
+        }
+    }
+}
+
+impl NvItemId {
+    /// Where the item lives on `version`. The tables that Z-Stack 3.x.0 moved to extended items
+    /// map to their legacy location on older firmwares, so the same item can be used everywhere.
+    pub fn address(self, version: ZnpVersion) -> NvAddress {
+        let extended = |item_id| NvAddress::Extended {
+            sys_id: NV_SYSTEM_ID_ZSTACK,
+            item_id,
+            sub_id: 0,
+        };
+        match (self, version) {
+            (
+                NvItemId::ExNwkSecMaterialTable
+                | NvItemId::ExTclkTable
+                | NvItemId::ZcdNvExApsKeyDataTable
+                | NvItemId::ZcdNvExAddrmgr,
+                ZnpVersion::ZStack3x0,
+            ) => extended(self.into()),
+            (NvItemId::ExNwkSecMaterialTable, _) => {
+                NvAddress::Legacy(NvItemId::LegacyNwkSecMaterialTableStart.into())
+            }
+            (NvItemId::ExTclkTable, ZnpVersion::ZStack12) => {
+                NvAddress::Legacy(NvItemId::LegacyTclkTableStart12.into())
+            }
+            (NvItemId::ExTclkTable, _) => NvAddress::Legacy(NvItemId::LegacyTclkTableStart.into()),
+            (NvItemId::ZcdNvExApsKeyDataTable, _) => {
+                NvAddress::Legacy(NvItemId::ApsLinkKeyDataStart.into())
+            }
+            (NvItemId::ZcdNvExAddrmgr, _) => NvAddress::Legacy(NvItemId::AddrMgr.into()),
+            _ => NvAddress::Legacy(self.into()),
+        }
+    }
+
+    /// Highest number of entries of a table, 1 for plain items
+    pub fn max_entries(self, version: ZnpVersion) -> u16 {
+        let legacy_range = |start: NvItemId, end: u16| end - u16::from(start) + 1;
+        match (self, version) {
+            // Extended tables end at the first missing sub ID
+            (
+                NvItemId::ExNwkSecMaterialTable
+                | NvItemId::ExTclkTable
+                | NvItemId::ZcdNvExApsKeyDataTable
+                | NvItemId::ZcdNvExAddrmgr,
+                ZnpVersion::ZStack3x0,
+            ) => u16::MAX,
+            (NvItemId::ExNwkSecMaterialTable, _) => {
+                legacy_range(NvItemId::LegacyNwkSecMaterialTableStart, 0x0080)
+            }
+            (NvItemId::ExTclkTable, ZnpVersion::ZStack12) => {
+                legacy_range(NvItemId::LegacyTclkTableStart12, 0x01ff)
+            }
+            (NvItemId::ExTclkTable, _) => legacy_range(NvItemId::LegacyTclkTableStart, 0x01ff),
+            (NvItemId::ZcdNvExApsKeyDataTable, _) => legacy_range(
+                NvItemId::ApsLinkKeyDataStart,
+                NvItemId::ApsLinkKeyDataEnd.into(),
+            ),
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_depends_on_version() {
+        assert_eq!(
+            NvItemId::ExTclkTable.address(ZnpVersion::ZStack3x0),
+            NvAddress::Extended {
+                sys_id: 1,
+                item_id: 4,
+                sub_id: 0
+            }
+        );
+        assert_eq!(
+            NvItemId::ExTclkTable.address(ZnpVersion::ZStack30x),
+            NvAddress::Legacy(0x0111)
+        );
+        assert_eq!(
+            NvItemId::ExTclkTable.address(ZnpVersion::ZStack12),
+            NvAddress::Legacy(0x0101)
+        );
+        assert_eq!(
+            NvItemId::NIB.address(ZnpVersion::ZStack3x0),
+            NvAddress::Legacy(0x0021)
+        );
+        assert_eq!(
+            NvItemId::ExTclkTable
+                .address(ZnpVersion::ZStack30x)
+                .entry(2),
+            NvAddress::Legacy(0x0113)
+        );
+        assert_eq!(
+            NvItemId::ExNwkSecMaterialTable.max_entries(ZnpVersion::ZStack30x),
+            12
+        );
+    }
+}
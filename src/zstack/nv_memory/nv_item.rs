@@ -1,26 +1,38 @@
-use super::NvItemId;
+use super::{entries::NvEntry, NvAddress, NvItemId};
 use crate::zstack::unpi::serial::{request, request_with_reply};
 use crate::zstack::unpi::subsystems::sys::{
-    OsalNvLengthResponse, OsalNvReadRequest, OsalNvReadResponse,
+    NvCreateRequest, NvCreateResponse, NvDeleteRequest, NvDeleteResponse, NvLengthRequest,
+    NvLengthResponse, NvReadRequest, NvReadResponse, NvWriteRequest, NvWriteResponse,
+    OsalNvDeleteRequest, OsalNvDeleteResponse, OsalNvItemInitRequest, OsalNvItemInitResponse,
+    OsalNvLengthResponse, OsalNvReadExtRequest, OsalNvReadExtResponse, OsalNvReadRequest,
+    OsalNvReadResponse, OsalNvWriteExtRequest, OsalNvWriteExtResponse, OsalNvWriteRequest,
+    OsalNvWriteResponse, VersionRequest, VersionResponse,
 };
 use crate::zstack::unpi::LenTypeInfo;
 use crate::{
     serial::SimpleSerial,
     subscription::SubscriptionService,
     zstack::unpi::{
+        buffer::Buffer,
         commands::{CommandRequest, CommandResponse},
+        constants::{sys::ZnpVersion, CommandStatus},
         serial::UnpiCommandError,
         subsystems::sys::OsalNvLengthRequest,
         SUnpiPacket,
     },
 };
-use deku::{DekuContainerRead, DekuReader, DekuWriter};
+use deku::{DekuContainerRead, DekuError, DekuReader, DekuWriter};
 use futures::lock::Mutex;
 use std::sync::Arc;
 
+// Bytes written per request (and read per SYS_NV_READ), leaving room for the request header in
+// one UNPI frame
+const MAX_NV_CHUNK_SIZE: usize = 244;
+
 pub struct NvMemoryAdapter<S: SimpleSerial<SUnpiPacket>> {
     serial: Arc<Mutex<S>>,
     subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+    version: std::sync::Mutex<Option<ZnpVersion>>,
 }
 
 impl<S: SimpleSerial<SUnpiPacket>> NvMemoryAdapter<S> {
@@ -31,6 +43,7 @@ impl<S: SimpleSerial<SUnpiPacket>> NvMemoryAdapter<S> {
         Ok(NvMemoryAdapter {
             serial,
             subscriptions,
+            version: std::sync::Mutex::new(None),
         })
     }
 
@@ -79,35 +92,398 @@ impl<S: SimpleSerial<SUnpiPacket>> NvMemoryAdapter<S> {
         .await?)
     }
 
-    pub async fn read_item<I: TryInto<NvItem>>(
+    /// Firmware family, asked to the device the first time it's needed
+    pub async fn znp_version(&self) -> Result<ZnpVersion, NvMemoryAdapterError> {
+        if let Some(version) = *self.version.lock().unwrap() {
+            return Ok(version);
+        }
+        let r: VersionResponse = self.request_with_reply(&VersionRequest {}, None).await?;
+        let version = ZnpVersion::from_product(r.product)
+            .ok_or(NvMemoryAdapterError::UnsupportedVersion(r.product))?;
+        self.set_znp_version(version);
+        Ok(version)
+    }
+
+    /// Skips the version detection, for callers that already asked the device
+    pub fn set_znp_version(&self, version: ZnpVersion) {
+        self.version.lock().unwrap().replace(version);
+    }
+
+    async fn address(&self, id: NvItemId) -> Result<NvAddress, NvMemoryAdapterError> {
+        Ok(id.address(self.znp_version().await?))
+    }
+
+    /// Reads the whole value of an item, in as many requests as its length requires
+    pub async fn read_raw(&self, id: NvItemId) -> Result<Vec<u8>, NvMemoryAdapterError> {
+        self.read_at(self.address(id).await?).await
+    }
+
+    /// Overwrites an existing item, `value` must have the item's length. Values longer than
+    /// one frame are written in several pieces.
+    pub async fn write_raw(&self, id: NvItemId, value: &[u8]) -> Result<(), NvMemoryAdapterError> {
+        self.write_at(self.address(id).await?, value).await
+    }
+
+    /// Length of an item, 0 if it doesn't exist
+    pub async fn item_length(&self, id: NvItemId) -> Result<usize, NvMemoryAdapterError> {
+        self.length_at(self.address(id).await?).await
+    }
+
+    /// Creates an item of `len` bytes starting with `initial_value`. Returns false, leaving the
+    /// item untouched, when it already existed.
+    pub async fn init_item(
+        &self,
+        id: NvItemId,
+        len: usize,
+        initial_value: &[u8],
+    ) -> Result<bool, NvMemoryAdapterError> {
+        self.init_at(self.address(id).await?, len, initial_value)
+            .await
+    }
+
+    /// Deletes an item, doing nothing if it doesn't exist
+    pub async fn delete_item(&self, id: NvItemId) -> Result<(), NvMemoryAdapterError> {
+        self.delete_at(self.address(id).await?).await
+    }
+
+    /// Reads every entry of a table such as `ExTclkTable`, stopping at the first missing one.
+    /// Plain items are returned as a table of one entry.
+    pub async fn read_table(&self, id: NvItemId) -> Result<Vec<Vec<u8>>, NvMemoryAdapterError> {
+        let version = self.znp_version().await?;
+        let address = id.address(version);
+        let mut entries = Vec::new();
+        for index in 0..id.max_entries(version) {
+            let entry = address.entry(index);
+            if self.length_at(entry).await? == 0 {
+                break;
+            }
+            entries.push(self.read_at(entry).await?);
+        }
+        Ok(entries)
+    }
+
+    /// Writes the `index`th entry of a table such as `ExTclkTable`, creating it if needed
+    pub async fn write_table_entry(
         &self,
         id: NvItemId,
-    ) -> Result<NvItem, NvMemoryAdapterError> {
-        let r: OsalNvLengthResponse = self
-            .request_with_reply(&OsalNvLengthRequest { id: id.into() }, None)
-            .await?;
-        let _len = r.length;
-        let r: OsalNvReadResponse = self
-            .request_with_reply(
-                &OsalNvReadRequest {
-                    id: id.into(),
-                    offset: 0,
+        index: u16,
+        value: &[u8],
+    ) -> Result<(), NvMemoryAdapterError> {
+        let address = self.address(id).await?.entry(index);
+        if self.init_at(address, value.len(), value).await? {
+            return Ok(());
+        }
+        self.write_at(address, value).await
+    }
+
+    async fn length_at(&self, address: NvAddress) -> Result<usize, NvMemoryAdapterError> {
+        match address {
+            NvAddress::Legacy(id) => {
+                let r: OsalNvLengthResponse = self
+                    .request_with_reply(&OsalNvLengthRequest { id }, None)
+                    .await?;
+                Ok(r.length as usize)
+            }
+            NvAddress::Extended {
+                sys_id,
+                item_id,
+                sub_id,
+            } => {
+                let r: NvLengthResponse = self
+                    .request_with_reply(
+                        &NvLengthRequest {
+                            sys_id,
+                            item_id,
+                            sub_id,
+                        },
+                        None,
+                    )
+                    .await?;
+                Ok(r.length as usize)
+            }
+        }
+    }
+
+    async fn read_at(&self, address: NvAddress) -> Result<Vec<u8>, NvMemoryAdapterError> {
+        let length = self.length_at(address).await?;
+        let mut value = Vec::with_capacity(length);
+        // A missing item still gets one read so the error comes from the device
+        while value.is_empty() || value.len() < length {
+            let chunk = self
+                .read_chunk(address, value.len(), length - value.len())
+                .await?;
+            if chunk.is_empty() {
+                break;
+            }
+            value.extend_from_slice(&chunk);
+        }
+        if value.len() != length {
+            return Err(NvMemoryAdapterError::InvalidData);
+        }
+        Ok(value)
+    }
+
+    // Legacy offsets that don't fit a byte need NV_READ_EXT or NV_WRITE_EXT, which Z-Stack 1.2
+    // lacks
+    async fn ensure_reachable(
+        &self,
+        address: NvAddress,
+        offset: usize,
+    ) -> Result<(), NvMemoryAdapterError> {
+        if matches!(address, NvAddress::Legacy(_))
+            && offset > u8::MAX as usize
+            && self.znp_version().await? == ZnpVersion::ZStack12
+        {
+            return Err(NvMemoryAdapterError::UnsupportedFirmware);
+        }
+        Ok(())
+    }
+
+    async fn read_chunk(
+        &self,
+        address: NvAddress,
+        offset: usize,
+        remaining: usize,
+    ) -> Result<Vec<u8>, NvMemoryAdapterError> {
+        self.ensure_reachable(address, offset).await?;
+        let (status, value) = match (address, u8::try_from(offset)) {
+            (NvAddress::Legacy(id), Ok(offset)) => {
+                let r: OsalNvReadResponse = self
+                    .request_with_reply(&OsalNvReadRequest { id, offset }, None)
+                    .await?;
+                (r.status, r.value)
+            }
+            (NvAddress::Legacy(id), Err(_)) => {
+                let r: OsalNvReadExtResponse = self
+                    .request_with_reply(
+                        &OsalNvReadExtRequest {
+                            id,
+                            offset: offset
+                                .try_into()
+                                .map_err(|_| NvMemoryAdapterError::InvalidData)?,
+                        },
+                        None,
+                    )
+                    .await?;
+                (r.status, r.value)
+            }
+            (
+                NvAddress::Extended {
+                    sys_id,
+                    item_id,
+                    sub_id,
                 },
-                None,
-            )
-            .await?;
-        println!("r: {:?}", r);
-        todo!()
+                _,
+            ) => {
+                let r: NvReadResponse = self
+                    .request_with_reply(
+                        &NvReadRequest {
+                            sys_id,
+                            item_id,
+                            sub_id,
+                            offset: offset
+                                .try_into()
+                                .map_err(|_| NvMemoryAdapterError::InvalidData)?,
+                            len: remaining.min(MAX_NV_CHUNK_SIZE) as u8,
+                        },
+                        None,
+                    )
+                    .await?;
+                (r.status, r.value)
+            }
+        };
+        ensure_success(status)?;
+        Ok(value.as_slice().to_vec())
     }
-}
 
-pub enum NvItem {}
+    async fn write_at(&self, address: NvAddress, value: &[u8]) -> Result<(), NvMemoryAdapterError> {
+        // Refused before the first piece goes out, rather than leaving the item half written
+        let last_offset = value.len().saturating_sub(1) / MAX_NV_CHUNK_SIZE * MAX_NV_CHUNK_SIZE;
+        self.ensure_reachable(address, last_offset).await?;
+        for (i, chunk) in value.chunks(MAX_NV_CHUNK_SIZE).enumerate() {
+            self.write_chunk(address, i * MAX_NV_CHUNK_SIZE, chunk)
+                .await?;
+        }
+        Ok(())
+    }
 
-impl TryFrom<()> for NvItem {
-    type Error = NvMemoryAdapterError;
+    async fn write_chunk(
+        &self,
+        address: NvAddress,
+        offset: usize,
+        chunk: &[u8],
+    ) -> Result<(), NvMemoryAdapterError> {
+        self.ensure_reachable(address, offset).await?;
+        let invalid = |_| NvMemoryAdapterError::InvalidData;
+        let status = match (address, u8::try_from(offset)) {
+            (NvAddress::Legacy(id), Ok(offset)) => {
+                let r: OsalNvWriteResponse = self
+                    .request_with_reply(
+                        &OsalNvWriteRequest {
+                            id,
+                            offset,
+                            len: chunk.len().try_into().map_err(invalid)?,
+                            value: Buffer::try_from(chunk)?,
+                        },
+                        None,
+                    )
+                    .await?;
+                r.status
+            }
+            (NvAddress::Legacy(id), Err(_)) => {
+                let r: OsalNvWriteExtResponse = self
+                    .request_with_reply(
+                        &OsalNvWriteExtRequest {
+                            id,
+                            offset: offset.try_into().map_err(invalid)?,
+                            len: chunk.len().try_into().map_err(invalid)?,
+                            value: Buffer::try_from(chunk)?,
+                        },
+                        None,
+                    )
+                    .await?;
+                r.status
+            }
+            (
+                NvAddress::Extended {
+                    sys_id,
+                    item_id,
+                    sub_id,
+                },
+                _,
+            ) => {
+                let r: NvWriteResponse = self
+                    .request_with_reply(
+                        &NvWriteRequest {
+                            sys_id,
+                            item_id,
+                            sub_id,
+                            offset: offset.try_into().map_err(invalid)?,
+                            len: chunk.len().try_into().map_err(invalid)?,
+                            value: Buffer::try_from(chunk)?,
+                        },
+                        None,
+                    )
+                    .await?;
+                r.status
+            }
+        };
+        ensure_success(status)
+    }
 
-    fn try_from(_: ()) -> Result<Self, Self::Error> {
-        todo!()
+    async fn init_at(
+        &self,
+        address: NvAddress,
+        len: usize,
+        initial_value: &[u8],
+    ) -> Result<bool, NvMemoryAdapterError> {
+        let invalid = |_| NvMemoryAdapterError::InvalidData;
+        match address {
+            NvAddress::Legacy(id) => {
+                let r: OsalNvItemInitResponse = self
+                    .request_with_reply(
+                        &OsalNvItemInitRequest {
+                            id,
+                            len: len.try_into().map_err(invalid)?,
+                            init_len: initial_value.len().try_into().map_err(invalid)?,
+                            init_value: Buffer::try_from(initial_value)?,
+                        },
+                        None,
+                    )
+                    .await?;
+                // NV_ITEM_UNINIT means the item was just created, success that it was there
+                match ensure_success(r.status) {
+                    Ok(()) => Ok(false),
+                    Err(NvMemoryAdapterError::CommandStatus(CommandStatus::NvItemInitialized)) => {
+                        Ok(true)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            NvAddress::Extended {
+                sys_id,
+                item_id,
+                sub_id,
+            } => {
+                if self.length_at(address).await? != 0 {
+                    return Ok(false);
+                }
+                let r: NvCreateResponse = self
+                    .request_with_reply(
+                        &NvCreateRequest {
+                            sys_id,
+                            item_id,
+                            sub_id,
+                            length: len.try_into().map_err(invalid)?,
+                        },
+                        None,
+                    )
+                    .await?;
+                ensure_success(r.status)?;
+                self.write_at(address, initial_value).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    async fn delete_at(&self, address: NvAddress) -> Result<(), NvMemoryAdapterError> {
+        let len = self.length_at(address).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        let status = match address {
+            NvAddress::Legacy(id) => {
+                let r: OsalNvDeleteResponse = self
+                    .request_with_reply(
+                        &OsalNvDeleteRequest {
+                            id,
+                            len: len
+                                .try_into()
+                                .map_err(|_| NvMemoryAdapterError::InvalidData)?,
+                        },
+                        None,
+                    )
+                    .await?;
+                r.status
+            }
+            NvAddress::Extended {
+                sys_id,
+                item_id,
+                sub_id,
+            } => {
+                let r: NvDeleteResponse = self
+                    .request_with_reply(
+                        &NvDeleteRequest {
+                            sys_id,
+                            item_id,
+                            sub_id,
+                        },
+                        None,
+                    )
+                    .await?;
+                r.status
+            }
+        };
+        ensure_success(status)
+    }
+
+    /// Reads and decodes an item with a known layout
+    pub async fn read<T: NvEntry>(&self) -> Result<T, NvMemoryAdapterError> {
+        let value = self.read_raw(T::ID).await?;
+        Ok(T::from_nv_bytes(&value)?)
+    }
+
+    /// Encodes and writes an item with a known layout
+    pub async fn write<T: NvEntry>(&self, value: &T) -> Result<(), NvMemoryAdapterError> {
+        self.write_raw(T::ID, &value.to_nv_bytes()?).await
+    }
+}
+
+fn ensure_success(status: u8) -> Result<(), NvMemoryAdapterError> {
+    match CommandStatus::try_from(status) {
+        Ok(CommandStatus::Success) => Ok(()),
+        Ok(status) => Err(NvMemoryAdapterError::CommandStatus(status)),
+        Err(_) => Err(NvMemoryAdapterError::InvalidData),
     }
 }
 
@@ -118,6 +494,16 @@ pub enum NvMemoryAdapterError {
     UnpiCommand(UnpiCommandError),
     Io(std::io::Error),
     MissingResponse,
+    CommandStatus(CommandStatus),
+    Deku(DekuError),
+    UnsupportedVersion(u8),
+    UnsupportedFirmware,
+}
+
+impl From<DekuError> for NvMemoryAdapterError {
+    fn from(e: DekuError) -> Self {
+        NvMemoryAdapterError::Deku(e)
+    }
 }
 
 impl From<UnpiCommandError> for NvMemoryAdapterError {
@@ -131,3 +517,151 @@ impl From<std::io::Error> for NvMemoryAdapterError {
         NvMemoryAdapterError::IoError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zstack::{
+        nv_memory::entries::{nib::Nib, ExtAddr, NwkKey, PanId},
+        simulator::ZnpSimulator,
+    };
+    use futures::executor::block_on;
+
+    fn adapter() -> (NvMemoryAdapter<ZnpSimulator>, ZnpSimulator) {
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
+        let simulator = ZnpSimulator::new(subscriptions.clone());
+        let adapter =
+            NvMemoryAdapter::new(Arc::new(Mutex::new(simulator.clone())), subscriptions).unwrap();
+        (adapter, simulator)
+    }
+
+    #[test]
+    fn test_typed_read_write() {
+        let (nv, simulator) = adapter();
+        simulator.set_nv_item(NvItemId::ExtAddr.into(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+        simulator.set_nv_item(NvItemId::NwkKey.into(), &[0xab; 16]);
+        block_on(async {
+            assert_eq!(
+                nv.read::<ExtAddr>().await.unwrap(),
+                ExtAddr([1, 2, 3, 4, 5, 6, 7, 8])
+            );
+            assert_eq!(nv.read::<NwkKey>().await.unwrap(), NwkKey([0xab; 16]));
+            nv.write(&PanId(0x1a62)).await.unwrap();
+            assert_eq!(nv.read::<PanId>().await.unwrap(), PanId(0x1a62));
+            // The NIB only exists once a network was formed
+            assert!(matches!(
+                nv.read::<Nib>().await,
+                Err(NvMemoryAdapterError::CommandStatus(
+                    CommandStatus::NvOperFailed
+                ))
+            ));
+        });
+        assert_eq!(
+            simulator.nv_item(NvItemId::PanId.into()).unwrap(),
+            vec![0x62, 0x1a]
+        );
+    }
+
+    #[test]
+    fn test_init_length_delete() {
+        let (nv, _simulator) = adapter();
+        let id = NvItemId::ZnpHasConfiguredZstack3;
+        block_on(async {
+            assert_eq!(nv.item_length(id).await.unwrap(), 0);
+            assert!(nv.init_item(id, 1, &[0x55]).await.unwrap());
+            assert!(!nv.init_item(id, 1, &[0x00]).await.unwrap());
+            assert_eq!(nv.item_length(id).await.unwrap(), 1);
+            assert_eq!(nv.read_raw(id).await.unwrap(), vec![0x55]);
+            assert!(matches!(
+                nv.write_raw(id, &[1, 2]).await,
+                Err(NvMemoryAdapterError::CommandStatus(
+                    CommandStatus::NvBadItemLen
+                ))
+            ));
+            nv.delete_item(id).await.unwrap();
+            assert_eq!(nv.item_length(id).await.unwrap(), 0);
+            nv.delete_item(id).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_chunked_read_write() {
+        let (nv, simulator) = adapter();
+        let id = NvItemId::ApsLinkKeyTable;
+        let table: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        simulator.set_nv_item(id.into(), &table);
+        block_on(async {
+            assert_eq!(nv.read_raw(id).await.unwrap(), table);
+            let updated: Vec<u8> = table.iter().map(|b| !b).collect();
+            nv.write_raw(id, &updated).await.unwrap();
+            assert_eq!(nv.read_raw(id).await.unwrap(), updated);
+        });
+        let commands = |command: u8| {
+            simulator.with(|s| s.received.iter().filter(|p| p.command == command).count())
+        };
+        // 248 bytes per read, the third one being past the reach of a one byte offset
+        assert_eq!(commands(OsalNvReadRequest::id()), 4);
+        assert_eq!(commands(OsalNvReadExtRequest::id()), 2);
+        // 244 bytes per write
+        assert_eq!(commands(OsalNvWriteRequest::id()), 2);
+        assert_eq!(commands(OsalNvWriteExtRequest::id()), 1);
+
+        // Z-Stack 1.2 has no extended commands, the end of the table is out of reach
+        nv.set_znp_version(ZnpVersion::ZStack12);
+        simulator.with(|s| s.received.clear());
+        block_on(async {
+            assert!(matches!(
+                nv.read_raw(id).await,
+                Err(NvMemoryAdapterError::UnsupportedFirmware)
+            ));
+            assert!(matches!(
+                nv.write_raw(id, &table).await,
+                Err(NvMemoryAdapterError::UnsupportedFirmware)
+            ));
+        });
+        assert_eq!(commands(OsalNvReadExtRequest::id()), 0);
+        assert_eq!(commands(OsalNvWriteRequest::id()), 0);
+        assert_eq!(commands(OsalNvWriteExtRequest::id()), 0);
+    }
+
+    #[test]
+    fn test_same_table_on_every_version() {
+        let entries = [[0x11u8; 19].to_vec(), [0x22u8; 19].to_vec()];
+
+        // CC2531 with Z-Stack 3.0.x, one legacy item per entry
+        let (nv, simulator) = adapter();
+        for (i, entry) in entries.iter().enumerate() {
+            simulator.set_nv_item(0x0111 + i as u16, entry);
+        }
+        let table = block_on(nv.read_table(NvItemId::ExTclkTable)).unwrap();
+        assert_eq!(table, entries);
+
+        // CC2652 with Z-Stack 3.x.0, one extended sub item per entry
+        let (nv, simulator) = adapter();
+        simulator.with(|s| {
+            s.version.product = ZnpVersion::ZStack3x0 as u8;
+            for (i, entry) in entries.iter().enumerate() {
+                s.nv_extended.insert((1, 4, i as u16), entry.clone());
+            }
+        });
+        block_on(async {
+            assert_eq!(nv.znp_version().await.unwrap(), ZnpVersion::ZStack3x0);
+            assert_eq!(nv.read_table(NvItemId::ExTclkTable).await.unwrap(), entries);
+            // Single items keep going through the OSAL commands
+            nv.write(&PanId(0x1a62)).await.unwrap();
+
+            let id = NvItemId::ZcdNvExAddrmgr;
+            assert!(nv.init_item(id, 300, &[0xaa; 4]).await.unwrap());
+            assert!(!nv.init_item(id, 300, &[]).await.unwrap());
+            let value: Vec<u8> = (0..300).map(|i| i as u8).collect();
+            nv.write_raw(id, &value).await.unwrap();
+            assert_eq!(nv.read_raw(id).await.unwrap(), value);
+            nv.delete_item(id).await.unwrap();
+            assert_eq!(nv.item_length(id).await.unwrap(), 0);
+        });
+        assert_eq!(
+            simulator.nv_item(NvItemId::PanId.into()).unwrap(),
+            vec![0x62, 0x1a]
+        );
+    }
+}
@@ -0,0 +1,1650 @@
+use super::{
+    nv_memory::{
+        entries::{addr_mgr::AddrMgrEntry, encode_entry, nib::Nib, NvLayout},
+        NvItemId,
+    },
+    unpi::{
+        buffer::Buffer,
+        commands::{CommandIeeeAddress, CommandRequest, CommandResponse, CountedList},
+        constants::{
+            app_cnf::{CommissioningMode, InstallCodeFormat},
+            sys::ZnpVersion,
+            zdo::{
+                AddressRequestType, DeviceState, LogicalType, Relationship, LEAVE_OPTION_REJOIN,
+                LEAVE_OPTION_REMOVE_CHILDREN, MAC_CAPABILITY_RX_ON_WHEN_IDLE,
+                STARTUP_OPTION_CLEAR_CONFIG, STARTUP_OPTION_CLEAR_STATE,
+            },
+            CommandStatus, BEACON_MAX_DEPTH,
+        },
+        subsystems::{
+            af::{
+                DataConfirmRequest, DataRequestExtRequest, DataRequestExtResponse,
+                DataRequestRequest, DataRequestResponse, RegisterRequest, RegisterResponse,
+            },
+            app_cnf::{
+                BdbAddInstallCodeRequest, BdbAddInstallCodeResponse,
+                BdbCommissioningNotificationRequest, BdbSetChannelRequest, BdbSetChannelResponse,
+                BdbStartCommissioningRequest, BdbStartCommissioningResponse,
+            },
+            sys::{
+                NvCreateRequest, NvCreateResponse, NvDeleteRequest, NvDeleteResponse,
+                NvLengthRequest, NvLengthResponse, NvReadRequest, NvReadResponse, NvWriteRequest,
+                NvWriteResponse, OsalNvDeleteRequest, OsalNvDeleteResponse, OsalNvItemInitRequest,
+                OsalNvItemInitResponse, OsalNvLengthRequest, OsalNvLengthResponse,
+                OsalNvReadExtRequest, OsalNvReadExtResponse, OsalNvReadRequest, OsalNvReadResponse,
+                OsalNvWriteExtRequest, OsalNvWriteExtResponse, OsalNvWriteRequest,
+                OsalNvWriteResponse, PingRequest, PingResponse, ResetIndRequest, ResetRequest,
+                StackTuneRequest, StackTuneResponse, VersionRequest, VersionResponse,
+            },
+            util::{GetDeviceInfoRequest, LedControlRequest, LedControlResponse},
+            zdo::{
+                ActiveEndpointsRequest, ActiveEndpointsResponse, ActiveEndpointsRspRequest,
+                BindRequest, BindResponse, BindRspRequest, EndDeviceAnnounceIndRequest,
+                ExitRouteDiscRequest, ExitRouteDiscResponse, ExtAddGroupRequest,
+                ExtAddGroupResponse, ExtFindAllGroupsEndpointRequest,
+                ExtFindAllGroupsEndpointResponse, ExtRemoveGroupRequest, ExtRemoveGroupResponse,
+                IeeeAddressRequest, IeeeAddressResponse, IeeeAddressRspRequest, LeaveIndRequest,
+                ManagementBindRequest, ManagementBindResponse, ManagementBindRspRequest,
+                ManagementLeaveRequest, ManagementLeaveResponse, ManagementLeaveRspRequest,
+                ManagementLqiRequest, ManagementLqiResponse, ManagementLqiRspRequest,
+                ManagementNetworkUpdateRequest, ManagementNetworkUpdateResponse,
+                ManagementPermitJoinRequest, ManagementPermitJoinResponse,
+                ManagementRoutingRequest, ManagementRoutingResponse, ManagementRoutingRspRequest,
+                NeighborLqi, NetworkAddressRequest, NetworkAddressResponse,
+                NetworkAddressRspRequest, NodeDescriptorRequest, NodeDescriptorResponse,
+                NodeDescriptorRspRequest, RoutingEntry, SecDeviceRemoveRequest,
+                SecDeviceRemoveResponse, SimpleDescriptorRequest, SimpleDescriptorResponse,
+                SimpleDescriptorRspRequest, StartupFromAppRequest, StartupFromAppResponse,
+                StateChangedIndRequest, TcDeviceIndexRequest, UnbindRequest, UnbindResponse,
+                UnbindRspRequest,
+            },
+        },
+        LenTypeInfo, MessageType, SUnpiPacket, Subsystem,
+    },
+};
+use crate::{
+    coordinator::BindingEntry,
+    devices::Endpoint,
+    serial::{SerialThreadError, SimpleSerial},
+    subscription::SubscriptionService,
+    utils::{trace, warn},
+};
+#[cfg(feature = "psila")]
+use crate::{
+    zcl::{
+        GROUPS_ADD, GROUPS_CLUSTER, GROUPS_GET_MEMBERSHIP, GROUPS_REMOVE, GROUPS_VIEW,
+        READ_ATTRIBUTES, READ_ATTRIBUTES_RESPONSE,
+    },
+    zstack::unpi::subsystems::af::IncomingMsgRequest,
+};
+use deku::{DekuContainerRead, DekuContainerWrite, DekuReader};
+use futures::{executor::block_on, lock::Mutex};
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    sync::{mpsc, Arc},
+};
+
+/// MT capabilities reported on ping: SYS, MAC, NWK, AF, ZDO, SAPI, UTIL, APP
+pub const SIMULATOR_CAPABILITIES: u16 = 0x0179;
+/// Largest value a single NV read reply carries: 250 bytes of payload minus status and length
+pub const SIMULATOR_NV_READ_MAX: usize = 248;
+/// Factory IEEE address, most significant byte first
+pub const SIMULATOR_IEEE_ADDRESS: [u8; 8] = [0x00, 0x12, 0x4b, 0x00, 0x01, 0x02, 0x03, 0x04];
+/// Size of the legacy address manager table
+pub const SIMULATOR_ADDR_MGR_ENTRIES: usize = 8;
+
+/// State of the emulated ZNP, shared between every clone of the simulator
+#[derive(Debug, Clone)]
+pub struct ZnpState {
+    pub version: VersionResponse,
+    pub ieee_address: [u8; 8],
+    pub network_address: u16,
+    pub device_state: u8,
+    pub nv: HashMap<u16, Vec<u8>>,
+    /// Extended items by system, item and sub ID, only reachable on Z-Stack 3.x.0
+    pub nv_extended: HashMap<(u8, u16, u16), Vec<u8>>,
+    /// Endpoints registered through AF_REGISTER
+    pub endpoints: Vec<u8>,
+    /// Statuses reported by the next AF data confirms, delivery succeeds once it's empty
+    pub data_confirm_statuses: VecDeque<u8>,
+    /// Every packet written by the host, in order
+    pub received: Vec<SUnpiPacket>,
+    /// Other devices of the network by network address
+    pub remote_devices: HashMap<u16, RemoteDevice>,
+    /// Network addresses of the devices that joined through the coordinator
+    pub associated_devices: Vec<u16>,
+    /// Routing table of the coordinator
+    pub routes: Vec<RoutingEntry>,
+    /// Groups the endpoints of the coordinator are in, by endpoint and group ID
+    pub groups: Vec<(u8, u16)>,
+    /// Install codes with their CRC, or the keys derived from them, by IEEE address
+    pub install_codes: HashMap<[u8; 8], Vec<u8>>,
+}
+
+/// Device of the emulated network, which answers ZDO descriptor requests and ZCL attribute reads
+#[derive(Debug, Clone)]
+pub struct RemoteDevice {
+    pub ieee_address: [u8; 8],
+    pub logical_type: LogicalType,
+    pub manufacturer_code: u16,
+    pub mac_capabilities: u8,
+    pub endpoints: Vec<Endpoint>,
+    /// Data type and encoded value by cluster and attribute ID
+    pub attributes: HashMap<(u16, u16), (u8, Vec<u8>)>,
+    /// Network addresses of the devices that joined through this one
+    pub associated_devices: Vec<u16>,
+    /// Quality its neighbors hear it with
+    pub link_quality: u8,
+    pub routes: Vec<RoutingEntry>,
+    pub bindings: Vec<BindingEntry>,
+    /// Groups the device is in, with their names
+    pub groups: Vec<(u16, String)>,
+    /// Requests the device sleeps through before answering one
+    pub missed_requests: usize,
+}
+
+// A mains powered router that answers every request, with nothing on it
+impl Default for RemoteDevice {
+    fn default() -> Self {
+        RemoteDevice {
+            ieee_address: [0; 8],
+            logical_type: LogicalType::Router,
+            manufacturer_code: 0,
+            mac_capabilities: 0x8e,
+            endpoints: vec![],
+            attributes: HashMap::new(),
+            associated_devices: vec![],
+            link_quality: 0xff,
+            routes: vec![],
+            bindings: vec![],
+            groups: vec![],
+            missed_requests: 0,
+        }
+    }
+}
+
+impl RemoteDevice {
+    // Whether the device is awake to answer a request
+    fn hears(&mut self) -> bool {
+        if self.missed_requests == 0 {
+            return true;
+        }
+        self.missed_requests -= 1;
+        false
+    }
+}
+
+impl Default for ZnpState {
+    fn default() -> Self {
+        Self {
+            // CC2531 running Z-Stack 3.0.x
+            version: VersionResponse {
+                transportrev: 2,
+                product: 2,
+                majorrel: 2,
+                minorrel: 7,
+                maintrel: 2,
+                revision: 20190425,
+            },
+            ieee_address: SIMULATOR_IEEE_ADDRESS,
+            network_address: 0x0000,
+            device_state: DeviceState::Hold as u8,
+            nv: default_nv(),
+            nv_extended: HashMap::new(),
+            endpoints: Vec::new(),
+            data_confirm_statuses: VecDeque::new(),
+            received: Vec::new(),
+            remote_devices: HashMap::new(),
+            associated_devices: Vec::new(),
+            routes: Vec::new(),
+            groups: Vec::new(),
+            install_codes: HashMap::new(),
+        }
+    }
+}
+
+enum DeviceMessage {
+    FromHost(SUnpiPacket),
+    Inject(SUnpiPacket),
+}
+
+/// In-process emulation of a Z-Stack ZNP, usable anywhere a serial transport is expected.
+///
+/// Replies are produced on a separate "device" thread and dispatched through the subscription
+/// service, just like packets read from a real serial port. Clones share the same device, so a
+/// test can hand one clone to the coordinator and keep another to inspect NV memory or inject
+/// callbacks.
+#[derive(Clone)]
+pub struct ZnpSimulator {
+    state: Arc<std::sync::Mutex<ZnpState>>,
+    to_device: mpsc::Sender<DeviceMessage>,
+}
+
+impl ZnpSimulator {
+    pub fn new(subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>) -> Self {
+        Self::with_state(ZnpState::default(), subscriptions)
+    }
+
+    pub fn with_state(
+        state: ZnpState,
+        subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+    ) -> Self {
+        let state = Arc::new(std::sync::Mutex::new(state));
+        let (to_device, from_host) = mpsc::channel();
+        let device_state = state.clone();
+        std::thread::spawn(move || {
+            // Ends once every simulator clone (and thus every sender) is dropped
+            while let Ok(message) = from_host.recv() {
+                let packets = match message {
+                    DeviceMessage::FromHost(packet) => {
+                        let mut state = device_state.lock().unwrap();
+                        state.received.push(packet.clone());
+                        handle(&mut state, &packet)
+                    }
+                    DeviceMessage::Inject(packet) => vec![packet],
+                };
+                for packet in packets {
+                    trace!("<<< {:?}", packet);
+                    let send = async { subscriptions.lock().await.notify(packet) };
+                    if let Err(e) = block_on(send) {
+                        warn!("simulator failed to dispatch packet: {:?}", e);
+                    }
+                }
+            }
+        });
+        Self { state, to_device }
+    }
+
+    /// Runs `f` with the device state locked, to inspect or tweak it
+    pub fn with<T>(&self, f: impl FnOnce(&mut ZnpState) -> T) -> T {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    pub fn nv_item(&self, id: u16) -> Option<Vec<u8>> {
+        self.with(|s| s.nv.get(&id).cloned())
+    }
+
+    pub fn set_nv_item(&self, id: u16, value: &[u8]) {
+        self.with(|s| s.nv.insert(id, value.to_vec()));
+    }
+
+    /// Sends a packet to the host as if the device had produced it
+    pub fn inject(&self, packet: SUnpiPacket) -> Result<(), SerialThreadError> {
+        self.to_device
+            .send(DeviceMessage::Inject(packet))
+            .map_err(|_| SerialThreadError::SerialChannel)
+    }
+
+    /// Injects a command as an AREQ callback
+    pub fn inject_command<R: CommandRequest + DekuContainerWrite>(
+        &self,
+        command: &R,
+    ) -> Result<(), SerialThreadError> {
+        self.inject(packet_from_command(command))
+    }
+
+    pub fn inject_tc_device_index(
+        &self,
+        network_address: u16,
+        ieee_address: [u8; 8],
+        parent_address: u16,
+    ) -> Result<(), SerialThreadError> {
+        self.inject_command(&TcDeviceIndexRequest {
+            network_address,
+            extended_address: CommandIeeeAddress { ieee_address },
+            parent_address,
+        })
+    }
+
+    pub fn inject_end_device_announce(
+        &self,
+        network_address: u16,
+        ieee_address: [u8; 8],
+        capabilities: u8,
+    ) -> Result<(), SerialThreadError> {
+        self.inject_command(&EndDeviceAnnounceIndRequest {
+            source_address: network_address,
+            network_address,
+            ieee_address: CommandIeeeAddress { ieee_address },
+            capabilities,
+        })
+    }
+}
+
+impl SimpleSerial<SUnpiPacket> for ZnpSimulator {
+    type Sender = mpsc::Sender<SUnpiPacket>;
+    type Receiver = mpsc::Receiver<SUnpiPacket>;
+
+    async fn write(&mut self, packet: &SUnpiPacket) -> Result<(), SerialThreadError> {
+        trace!(">>> {:?}", packet);
+        self.to_device
+            .send(DeviceMessage::FromHost(packet.clone()))
+            .map_err(|_| SerialThreadError::SerialChannel)
+    }
+}
+
+fn packet_from_bytes(
+    payload: &[u8],
+    message_type: MessageType,
+    subsystem: Subsystem,
+    command: u8,
+) -> SUnpiPacket {
+    SUnpiPacket::from_payload_owned(
+        (payload, LenTypeInfo::OneByte),
+        (message_type, subsystem),
+        command,
+    )
+    .expect("payload fits in a frame")
+}
+
+fn packet_from_command<R: CommandRequest + DekuContainerWrite>(command: &R) -> SUnpiPacket {
+    packet_from_bytes(
+        &command.to_bytes().expect("command serializes"),
+        R::message_type(),
+        R::subsystem(),
+        R::id(),
+    )
+}
+
+fn reply<R: CommandResponse + DekuContainerWrite>(response: &R) -> SUnpiPacket {
+    packet_from_bytes(
+        &response.to_bytes().expect("response serializes"),
+        R::message_type(),
+        R::subsystem(),
+        R::id(),
+    )
+}
+
+fn parse<'a, R: CommandRequest + DekuReader<'a> + DekuContainerRead<'a>>(
+    packet: &'a SUnpiPacket,
+) -> Option<R> {
+    packet
+        .to_command_request()
+        .inspect_err(|e| warn!("simulator could not parse {:?}: {:?}", packet, e))
+        .ok()
+}
+
+// Values the simulator answers with are no longer than the frame asking for them
+fn buffer(data: &[u8]) -> Buffer {
+    Buffer::try_from(data).expect("simulated values fit in a frame")
+}
+
+fn status(status: CommandStatus) -> u8 {
+    match status {
+        CommandStatus::Success => 0x00,
+        CommandStatus::InvalidParam => 0x02,
+        CommandStatus::NvItemInitialized => 0x09,
+        CommandStatus::NvOperFailed => 0x0a,
+        CommandStatus::NvBadItemLen => 0x0c,
+        CommandStatus::ApsDuplicateEntry => 0xb8,
+        _ => 0x01,
+    }
+}
+
+/// RPC error the ZNP answers with when it doesn't know a synchronous command
+fn rpc_error(packet: &SUnpiPacket) -> SUnpiPacket {
+    const INVALID_COMMAND_ID: u8 = 0x03;
+    let type_subsystem =
+        Into::<u8>::into(packet.type_subsystem.0) << 5 | Into::<u8>::into(packet.type_subsystem.1);
+    packet_from_bytes(
+        &[INVALID_COMMAND_ID, type_subsystem, packet.command],
+        MessageType::SRESP,
+        Subsystem::Res0,
+        0,
+    )
+}
+
+/// Produces the packets the device sends back for a packet written by the host
+fn handle(state: &mut ZnpState, packet: &SUnpiPacket) -> Vec<SUnpiPacket> {
+    let (message_type, subsystem) = packet.type_subsystem;
+    let command = packet.command;
+    let is = |id: u8, s: Subsystem| command == id && subsystem == s;
+
+    if message_type == MessageType::AREQ && is(ResetRequest::id(), ResetRequest::subsystem()) {
+        return vec![reset(state)];
+    }
+    if message_type != MessageType::SREQ {
+        // Other AREQs from the host don't get an answer
+        return vec![];
+    }
+
+    if is(PingRequest::id(), PingRequest::subsystem()) {
+        vec![reply(&PingResponse {
+            capabilities: SIMULATOR_CAPABILITIES,
+        })]
+    } else if is(VersionRequest::id(), VersionRequest::subsystem()) {
+        vec![reply(&state.version)]
+    } else if is(
+        GetDeviceInfoRequest::id(),
+        GetDeviceInfoRequest::subsystem(),
+    ) {
+        let mut payload = vec![0u8];
+        payload.extend_from_slice(&state.ieee_address);
+        payload.extend_from_slice(&state.network_address.to_le_bytes());
+        // device type bitmap: coordinator, router and end device capable
+        payload.push(0x07);
+        payload.push(state.device_state);
+        payload.push(state.associated_devices.len() as u8);
+        for device in &state.associated_devices {
+            payload.extend_from_slice(&device.to_le_bytes());
+        }
+        vec![packet_from_bytes(
+            &payload,
+            MessageType::SRESP,
+            Subsystem::Util,
+            GetDeviceInfoRequest::id(),
+        )]
+    } else if is(LedControlRequest::id(), LedControlRequest::subsystem()) {
+        vec![reply(&LedControlResponse { status: 0 })]
+    } else if is(StackTuneRequest::id(), StackTuneRequest::subsystem()) {
+        let Some(request) = parse::<StackTuneRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        vec![reply(&StackTuneResponse {
+            value: request.value as u8,
+        })]
+    } else if is(
+        StartupFromAppRequest::id(),
+        StartupFromAppRequest::subsystem(),
+    ) {
+        state.device_state = DeviceState::Coordinator as u8;
+        vec![
+            // 0: restored network state
+            reply(&StartupFromAppResponse { status: 0 }),
+            packet_from_command(&StateChangedIndRequest {
+                state: DeviceState::Coordinator as u8,
+            }),
+        ]
+    } else if is(
+        ManagementPermitJoinRequest::id(),
+        ManagementPermitJoinRequest::subsystem(),
+    ) {
+        vec![reply(&ManagementPermitJoinResponse { status: 0 })]
+    } else if is(
+        ManagementNetworkUpdateRequest::id(),
+        ManagementNetworkUpdateRequest::subsystem(),
+    ) {
+        vec![reply(&ManagementNetworkUpdateResponse { status: 0 })]
+    } else if is(
+        ExitRouteDiscRequest::id(),
+        ExitRouteDiscRequest::subsystem(),
+    ) {
+        vec![reply(&ExitRouteDiscResponse { status: 0 })]
+    } else if is(OsalNvLengthRequest::id(), OsalNvLengthRequest::subsystem()) {
+        let Some(request) = parse::<OsalNvLengthRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let length = state.nv.get(&request.id).map_or(0, |v| v.len() as u16);
+        vec![reply(&OsalNvLengthResponse { length })]
+    } else if is(OsalNvReadRequest::id(), OsalNvReadRequest::subsystem()) {
+        let Some(request) = parse::<OsalNvReadRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let (status, value) = nv_read(state, request.id, request.offset as usize);
+        vec![reply(&OsalNvReadResponse {
+            status,
+            len: value.len as u8,
+            value,
+        })]
+    } else if is(
+        OsalNvReadExtRequest::id(),
+        OsalNvReadExtRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<OsalNvReadExtRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let (status, value) = nv_read(state, request.id, request.offset as usize);
+        vec![reply(&OsalNvReadExtResponse {
+            status,
+            len: value.len as u8,
+            value,
+        })]
+    } else if is(OsalNvWriteRequest::id(), OsalNvWriteRequest::subsystem()) {
+        let Some(request) = parse::<OsalNvWriteRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let status = nv_write(
+            state,
+            request.id,
+            request.offset as usize,
+            request.value.as_slice(),
+        );
+        vec![reply(&OsalNvWriteResponse { status })]
+    } else if is(
+        OsalNvWriteExtRequest::id(),
+        OsalNvWriteExtRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<OsalNvWriteExtRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let status = nv_write(
+            state,
+            request.id,
+            request.offset as usize,
+            request.value.as_slice(),
+        );
+        vec![reply(&OsalNvWriteExtResponse { status })]
+    } else if is(
+        OsalNvItemInitRequest::id(),
+        OsalNvItemInitRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<OsalNvItemInitRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let status = match state.nv.entry(request.id) {
+            Entry::Occupied(_) => status(CommandStatus::Success),
+            // NV_ITEM_UNINIT, the item didn't exist
+            Entry::Vacant(entry) => {
+                let mut value = request.init_value.as_slice().to_vec();
+                value.resize(request.len as usize, 0);
+                entry.insert(value);
+                status(CommandStatus::NvItemInitialized)
+            }
+        };
+        vec![reply(&OsalNvItemInitResponse { status })]
+    } else if is(NvLengthRequest::id(), NvLengthRequest::subsystem()) && has_extended_nv(state) {
+        let Some(r) = parse::<NvLengthRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let length = state
+            .nv_extended
+            .get(&(r.sys_id, r.item_id, r.sub_id))
+            .map_or(0, |v| v.len() as u32);
+        vec![reply(&NvLengthResponse { length })]
+    } else if is(NvReadRequest::id(), NvReadRequest::subsystem()) && has_extended_nv(state) {
+        let Some(r) = parse::<NvReadRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let (status, value) = match state.nv_extended.get(&(r.sys_id, r.item_id, r.sub_id)) {
+            Some(value) if r.offset as usize + r.len as usize <= value.len() => {
+                let offset = r.offset as usize;
+                (
+                    status(CommandStatus::Success),
+                    buffer(&value[offset..offset + r.len as usize]),
+                )
+            }
+            Some(_) => (status(CommandStatus::NvBadItemLen), buffer(&[])),
+            None => (status(CommandStatus::NvOperFailed), buffer(&[])),
+        };
+        vec![reply(&NvReadResponse {
+            status,
+            len: value.len as u8,
+            value,
+        })]
+    } else if is(NvWriteRequest::id(), NvWriteRequest::subsystem()) && has_extended_nv(state) {
+        let Some(r) = parse::<NvWriteRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let data = r.value.as_slice();
+        let offset = r.offset as usize;
+        let status = match state.nv_extended.get_mut(&(r.sys_id, r.item_id, r.sub_id)) {
+            Some(value) if offset + data.len() <= value.len() => {
+                value[offset..offset + data.len()].copy_from_slice(data);
+                status(CommandStatus::Success)
+            }
+            Some(_) => status(CommandStatus::NvBadItemLen),
+            None => status(CommandStatus::NvOperFailed),
+        };
+        vec![reply(&NvWriteResponse { status })]
+    } else if is(NvCreateRequest::id(), NvCreateRequest::subsystem()) && has_extended_nv(state) {
+        let Some(r) = parse::<NvCreateRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        state
+            .nv_extended
+            .entry((r.sys_id, r.item_id, r.sub_id))
+            .or_insert_with(|| vec![0xff; r.length as usize]);
+        vec![reply(&NvCreateResponse {
+            status: status(CommandStatus::Success),
+        })]
+    } else if is(NvDeleteRequest::id(), NvDeleteRequest::subsystem()) && has_extended_nv(state) {
+        let Some(r) = parse::<NvDeleteRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let status = match state.nv_extended.remove(&(r.sys_id, r.item_id, r.sub_id)) {
+            Some(_) => status(CommandStatus::Success),
+            None => status(CommandStatus::NvOperFailed),
+        };
+        vec![reply(&NvDeleteResponse { status })]
+    } else if is(OsalNvDeleteRequest::id(), OsalNvDeleteRequest::subsystem()) {
+        let Some(request) = parse::<OsalNvDeleteRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let status = match state.nv.get(&request.id) {
+            Some(value) if value.len() == request.len as usize => {
+                state.nv.remove(&request.id);
+                status(CommandStatus::Success)
+            }
+            Some(_) => status(CommandStatus::NvBadItemLen),
+            None => status(CommandStatus::NvItemInitialized),
+        };
+        vec![reply(&OsalNvDeleteResponse { status })]
+    } else if is(
+        BdbSetChannelRequest::id(),
+        BdbSetChannelRequest::subsystem(),
+    ) {
+        vec![reply(&BdbSetChannelResponse {
+            status: status(CommandStatus::Success),
+        })]
+    } else if is(
+        BdbAddInstallCodeRequest::id(),
+        BdbAddInstallCodeRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<BdbAddInstallCodeRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let install_code = request.install_code.as_slice();
+        let expected_len = match request.install_code_format {
+            f if f == InstallCodeFormat::InstallCodeAndCrc as u8 => 18,
+            f if f == InstallCodeFormat::DerivedKey as u8 => 16,
+            _ => 0,
+        };
+        let result = if install_code.len() == expected_len {
+            state
+                .install_codes
+                .insert(request.ieee_address.ieee_address, install_code.to_vec());
+            CommandStatus::Success
+        } else {
+            CommandStatus::InvalidParam
+        };
+        vec![reply(&BdbAddInstallCodeResponse {
+            status: status(result),
+        })]
+    } else if is(
+        BdbStartCommissioningRequest::id(),
+        BdbStartCommissioningRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<BdbStartCommissioningRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let mut packets = vec![reply(&BdbStartCommissioningResponse {
+            status: status(CommandStatus::Success),
+        })];
+        let mut succeeded = true;
+        if request.mode == CommissioningMode::NetworkFormation as u8 {
+            succeeded = form_network(state);
+            if succeeded {
+                packets.extend([
+                    packet_from_command(&StateChangedIndRequest {
+                        state: DeviceState::CoordinatorStarting as u8,
+                    }),
+                    packet_from_command(&StateChangedIndRequest {
+                        state: DeviceState::Coordinator as u8,
+                    }),
+                ]);
+            }
+        }
+        packets.push(packet_from_command(&BdbCommissioningNotificationRequest {
+            status: status(if succeeded {
+                CommandStatus::Success
+            } else {
+                CommandStatus::Failure
+            }),
+            commissioning_mode: request.mode,
+            remaining_commissioning_modes: 0,
+        }));
+        packets
+    } else if is(RegisterRequest::id(), RegisterRequest::subsystem()) {
+        let Some(request) = parse::<RegisterRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let status = if state.endpoints.contains(&request.endpoint) {
+            status(CommandStatus::ApsDuplicateEntry)
+        } else {
+            state.endpoints.push(request.endpoint);
+            status(CommandStatus::Success)
+        };
+        vec![reply(&RegisterResponse { status })]
+    } else if is(DataRequestRequest::id(), DataRequestRequest::subsystem()) {
+        let Some(request) = parse::<DataRequestRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let (status, confirm_status) = data_request(state, request.source_endpoint);
+        let mut packets = vec![
+            reply(&DataRequestResponse { status }),
+            packet_from_command(&DataConfirmRequest {
+                status: confirm_status,
+                endpoint: request.source_endpoint,
+                transaction_id: request.transaction_id,
+            }),
+        ];
+        // Delivered, so the device may answer
+        if confirm_status == 0 {
+            packets.extend(zcl_answer(state, &request));
+        }
+        packets
+    } else if is(
+        DataRequestExtRequest::id(),
+        DataRequestExtRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<DataRequestExtRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let (status, confirm_status) = data_request(state, request.source_endpoint);
+        vec![
+            reply(&DataRequestExtResponse { status }),
+            packet_from_command(&DataConfirmRequest {
+                status: confirm_status,
+                endpoint: request.source_endpoint,
+                transaction_id: request.transaction_id,
+            }),
+        ]
+    } else if is(
+        NodeDescriptorRequest::id(),
+        NodeDescriptorRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<NodeDescriptorRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let answer = remote(state, request.destination_address).map(|device| {
+            packet_from_command(&NodeDescriptorRspRequest {
+                source_address: request.destination_address,
+                status: 0,
+                network_address_of_interest: request.network_address_of_interest,
+                logical_type_complex_user_descriptor: device.logical_type as u8,
+                aps_flags_frequency_band: 0x40,
+                mac_capabilities: device.mac_capabilities,
+                manufacturer_code: device.manufacturer_code,
+                max_buffer_size: 0x50,
+                max_in_transfer_size: 0x00a0,
+                server_mask: 0x2c00,
+                max_out_transfer_size: 0x00a0,
+                descriptor_capabilities: 0,
+            })
+        });
+        zdo_reply(NodeDescriptorResponse { status: 0 }, answer)
+    } else if is(
+        ActiveEndpointsRequest::id(),
+        ActiveEndpointsRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<ActiveEndpointsRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let answer = remote(state, request.destination_address).map(|device| {
+            packet_from_command(&ActiveEndpointsRspRequest {
+                source_address: request.destination_address,
+                status: 0,
+                network_address_of_interest: request.network_address_of_interest,
+                endpoints: device
+                    .endpoints
+                    .iter()
+                    .map(|e| e.id)
+                    .collect::<Vec<_>>()
+                    .into(),
+            })
+        });
+        zdo_reply(ActiveEndpointsResponse { status: 0 }, answer)
+    } else if is(
+        SimpleDescriptorRequest::id(),
+        SimpleDescriptorRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<SimpleDescriptorRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let answer = remote(state, request.destination_address).map(|device| {
+            match device.endpoints.iter().find(|e| e.id == request.endpoint) {
+                Some(endpoint) => packet_from_command(&SimpleDescriptorRspRequest {
+                    source_address: request.destination_address,
+                    status: 0,
+                    network_address_of_interest: request.network_address_of_interest,
+                    length: (8 + 2
+                        * (endpoint.input_clusters.len() + endpoint.output_clusters.len()))
+                        as u8,
+                    endpoint: endpoint.id,
+                    profile_id: endpoint.profile_id,
+                    device_id: endpoint.device_id,
+                    device_version: 0,
+                    input_clusters: endpoint.input_clusters.clone().into(),
+                    output_clusters: endpoint.output_clusters.clone().into(),
+                }),
+                // Only the address, status and address of interest come with a failure
+                None => {
+                    let mut payload = request.destination_address.to_le_bytes().to_vec();
+                    payload.push(ZDO_STATUS_NOT_ACTIVE);
+                    payload.extend(request.network_address_of_interest.to_le_bytes());
+                    packet_from_bytes(
+                        &payload,
+                        MessageType::AREQ,
+                        Subsystem::Zdo,
+                        SimpleDescriptorRspRequest::id(),
+                    )
+                }
+            }
+        });
+        zdo_reply(SimpleDescriptorResponse { status: 0 }, answer)
+    } else if is(
+        NetworkAddressRequest::id(),
+        NetworkAddressRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<NetworkAddressRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        // Broadcast, only the device with that IEEE address answers
+        let network_address = state
+            .remote_devices
+            .iter()
+            .find(|(_, device)| device.ieee_address == request.ieee_address.ieee_address)
+            .map(|(network_address, _)| *network_address);
+        let answer = network_address
+            .and_then(|network_address| Some((network_address, remote(state, network_address)?)))
+            .map(|(network_address, device)| {
+                packet_from_command(&NetworkAddressRspRequest {
+                    status: 0,
+                    ieee_address: CommandIeeeAddress {
+                        ieee_address: device.ieee_address,
+                    },
+                    network_address,
+                    start_index: request.start_index,
+                    associated_devices: associated_devices(
+                        device,
+                        request.request_type,
+                        request.start_index,
+                    ),
+                })
+            });
+        zdo_reply(NetworkAddressResponse { status: 0 }, answer)
+    } else if is(IeeeAddressRequest::id(), IeeeAddressRequest::subsystem()) {
+        let Some(request) = parse::<IeeeAddressRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let answer = remote(state, request.short_address).map(|device| {
+            packet_from_command(&IeeeAddressRspRequest {
+                status: 0,
+                ieee_address: CommandIeeeAddress {
+                    ieee_address: device.ieee_address,
+                },
+                network_address: request.short_address,
+                start_index: request.start_index,
+                associated_devices: associated_devices(
+                    device,
+                    request.request_type,
+                    request.start_index,
+                ),
+            })
+        });
+        zdo_reply(IeeeAddressResponse { status: 0 }, answer)
+    } else if is(
+        ManagementLqiRequest::id(),
+        ManagementLqiRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<ManagementLqiRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let address = request.destination_address;
+        let answer = management_status(state, address).map(|status| {
+            if status != 0 {
+                return zdo_failure(address, status, ManagementLqiRspRequest::id());
+            }
+            let table = neighbor_table(state, address);
+            packet_from_command(&ManagementLqiRspRequest {
+                source_address: address,
+                status,
+                neighbor_table_entries: table.len() as u8,
+                start_index: request.start_index,
+                neighbors: page(&table, request.start_index, MGMT_LQI_PAGE).into(),
+            })
+        });
+        zdo_reply(ManagementLqiResponse { status: 0 }, answer)
+    } else if is(
+        ManagementRoutingRequest::id(),
+        ManagementRoutingRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<ManagementRoutingRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let address = request.destination_address;
+        let answer = management_status(state, address).map(|status| {
+            if status != 0 {
+                return zdo_failure(address, status, ManagementRoutingRspRequest::id());
+            }
+            let table = match state.remote_devices.get(&address) {
+                Some(device) => device.routes.clone(),
+                None => state.routes.clone(),
+            };
+            packet_from_command(&ManagementRoutingRspRequest {
+                source_address: address,
+                status,
+                routing_table_entries: table.len() as u8,
+                start_index: request.start_index,
+                routes: page(&table, request.start_index, MGMT_RTG_PAGE).into(),
+            })
+        });
+        zdo_reply(ManagementRoutingResponse { status: 0 }, answer)
+    } else if is(BindRequest::id(), BindRequest::subsystem()) {
+        let Some(request) = parse::<BindRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let binding = request.binding();
+        let answer = remote(state, request.destination_address).map(|device| {
+            let status = match binding {
+                Some(binding) => {
+                    if !device.bindings.contains(&binding) {
+                        device.bindings.push(binding);
+                    }
+                    0
+                }
+                None => ZDO_STATUS_NOT_SUPPORTED,
+            };
+            packet_from_command(&BindRspRequest {
+                source_address: request.destination_address,
+                status,
+            })
+        });
+        zdo_reply(BindResponse { status: 0 }, answer)
+    } else if is(UnbindRequest::id(), UnbindRequest::subsystem()) {
+        let Some(request) = parse::<UnbindRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let binding = request.binding();
+        let answer = remote(state, request.destination_address).map(|device| {
+            let bindings = device.bindings.len();
+            device.bindings.retain(|b| Some(b) != binding.as_ref());
+            packet_from_command(&UnbindRspRequest {
+                source_address: request.destination_address,
+                status: if device.bindings.len() < bindings {
+                    0
+                } else {
+                    ZDO_STATUS_NO_ENTRY
+                },
+            })
+        });
+        zdo_reply(UnbindResponse { status: 0 }, answer)
+    } else if is(
+        ManagementBindRequest::id(),
+        ManagementBindRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<ManagementBindRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let answer = remote(state, request.destination_address).map(|device| {
+            packet_from_command(&ManagementBindRspRequest {
+                source_address: request.destination_address,
+                status: 0,
+                binding_table_entries: device.bindings.len() as u8,
+                start_index: request.start_index,
+                bindings: page(&device.bindings, request.start_index, MGMT_BIND_PAGE).into(),
+            })
+        });
+        zdo_reply(ManagementBindResponse { status: 0 }, answer)
+    } else if is(
+        SecDeviceRemoveRequest::id(),
+        SecDeviceRemoveRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<SecDeviceRemoveRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        // Install codes live in the key table the device is removed from
+        let result = match state
+            .install_codes
+            .remove(&request.ieee_address.ieee_address)
+        {
+            Some(_) => CommandStatus::Success,
+            None => CommandStatus::Failure,
+        };
+        vec![reply(&SecDeviceRemoveResponse {
+            status: status(result),
+        })]
+    } else if is(
+        ManagementLeaveRequest::id(),
+        ManagementLeaveRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<ManagementLeaveRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let source_address = request.destination_address;
+        let rejoin = request.remove_children_rejoin & LEAVE_OPTION_REJOIN != 0;
+        let mut replies = vec![reply(&ManagementLeaveResponse { status: 0 })];
+        let Some(device) = remote(state, source_address) else {
+            return replies;
+        };
+        if device.logical_type == LogicalType::EndDevice {
+            // Like many battery powered devices, it doesn't take orders to leave
+            replies.push(packet_from_command(&ManagementLeaveRspRequest {
+                source_address,
+                status: ZDO_STATUS_NOT_SUPPORTED,
+            }));
+        } else {
+            // The device confirms, then its parent reports it gone
+            if !rejoin {
+                state.remote_devices.remove(&source_address);
+                state.associated_devices.retain(|a| *a != source_address);
+            }
+            replies.push(packet_from_command(&ManagementLeaveRspRequest {
+                source_address,
+                status: 0,
+            }));
+            replies.push(packet_from_command(&LeaveIndRequest {
+                source_address,
+                extended_address: request.device_address,
+                request: 0,
+                remove: u8::from(
+                    request.remove_children_rejoin & LEAVE_OPTION_REMOVE_CHILDREN != 0,
+                ),
+                rejoin: u8::from(rejoin),
+            }));
+        }
+        replies
+    } else if is(ExtAddGroupRequest::id(), ExtAddGroupRequest::subsystem()) {
+        let Some(request) = parse::<ExtAddGroupRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let result = if !state.endpoints.contains(&request.endpoint) {
+            CommandStatus::InvalidParam
+        } else {
+            let group = (request.endpoint, request.group_id);
+            if !state.groups.contains(&group) {
+                state.groups.push(group);
+            }
+            CommandStatus::Success
+        };
+        vec![reply(&ExtAddGroupResponse {
+            status: status(result),
+        })]
+    } else if is(
+        ExtRemoveGroupRequest::id(),
+        ExtRemoveGroupRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<ExtRemoveGroupRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let groups = state.groups.len();
+        state
+            .groups
+            .retain(|group| *group != (request.endpoint, request.group_id));
+        let result = if state.groups.len() < groups {
+            CommandStatus::Success
+        } else {
+            CommandStatus::Failure
+        };
+        vec![reply(&ExtRemoveGroupResponse {
+            status: status(result),
+        })]
+    } else if is(
+        ExtFindAllGroupsEndpointRequest::id(),
+        ExtFindAllGroupsEndpointRequest::subsystem(),
+    ) {
+        let Some(request) = parse::<ExtFindAllGroupsEndpointRequest>(packet) else {
+            return vec![rpc_error(packet)];
+        };
+        let groups = state
+            .groups
+            .iter()
+            .filter(|(endpoint, _)| *endpoint == request.endpoint)
+            .map(|(_, group_id)| *group_id)
+            .collect::<Vec<_>>();
+        vec![reply(&ExtFindAllGroupsEndpointResponse {
+            groups: groups.into(),
+        })]
+    } else {
+        warn!("simulator doesn't know how to answer {:?}", packet);
+        vec![rpc_error(packet)]
+    }
+}
+
+/// ZDO status of a request about an endpoint the device doesn't have
+const ZDO_STATUS_NOT_ACTIVE: u8 = 0x83;
+/// ZDO status of a management request the device doesn't serve
+const ZDO_STATUS_NOT_SUPPORTED: u8 = 0x84;
+/// ZDO status of an unbind request for a binding the device doesn't have
+const ZDO_STATUS_NO_ENTRY: u8 = 0x88;
+// Table entries that fit in one management response
+const MGMT_LQI_PAGE: usize = 3;
+const MGMT_RTG_PAGE: usize = 5;
+const MGMT_BIND_PAGE: usize = 3;
+
+// Status a node answers a management request with, none when it doesn't hear it. End devices
+// have no tables to share.
+fn management_status(state: &mut ZnpState, network_address: u16) -> Option<u8> {
+    if network_address == state.network_address {
+        return Some(0);
+    }
+    let device = remote(state, network_address)?;
+    Some(match device.logical_type {
+        LogicalType::EndDevice => ZDO_STATUS_NOT_SUPPORTED,
+        _ => 0,
+    })
+}
+
+// Failed ZDO answer, which only has the address of the device and the status
+fn zdo_failure(network_address: u16, status: u8, command: u8) -> SUnpiPacket {
+    let mut payload = network_address.to_le_bytes().to_vec();
+    payload.push(status);
+    packet_from_bytes(&payload, MessageType::AREQ, Subsystem::Zdo, command)
+}
+
+fn page<T: Clone>(table: &[T], start_index: u8, size: usize) -> Vec<T> {
+    table
+        .iter()
+        .skip(start_index.into())
+        .take(size)
+        .cloned()
+        .collect()
+}
+
+// Node that `network_address` joined through
+fn parent(state: &ZnpState, network_address: u16) -> Option<u16> {
+    if state.associated_devices.contains(&network_address) {
+        return Some(state.network_address);
+    }
+    state
+        .remote_devices
+        .iter()
+        .find(|(_, device)| device.associated_devices.contains(&network_address))
+        .map(|(address, _)| *address)
+}
+
+// A node only knows its parent and children in the emulated network
+fn neighbor_table(state: &ZnpState, network_address: u16) -> Vec<NeighborLqi> {
+    let children = match state.remote_devices.get(&network_address) {
+        Some(device) => &device.associated_devices,
+        None => &state.associated_devices,
+    };
+    let parent = parent(state, network_address).map(|parent| (parent, Relationship::Parent));
+    parent
+        .into_iter()
+        .chain(children.iter().map(|child| (*child, Relationship::Child)))
+        .filter_map(|(address, relationship)| neighbor(state, address, relationship))
+        .collect()
+}
+
+fn neighbor(
+    state: &ZnpState,
+    network_address: u16,
+    relationship: Relationship,
+) -> Option<NeighborLqi> {
+    let (ieee_address, logical_type, rx_on_when_idle, lqi) =
+        if network_address == state.network_address {
+            (state.ieee_address, LogicalType::Coordinator, true, 0xff)
+        } else {
+            let device = state.remote_devices.get(&network_address)?;
+            (
+                device.ieee_address,
+                device.logical_type,
+                device.mac_capabilities & MAC_CAPABILITY_RX_ON_WHEN_IDLE != 0,
+                device.link_quality,
+            )
+        };
+    let mut depth = 0;
+    let mut ancestor = network_address;
+    while let Some(address) = parent(state, ancestor) {
+        depth += 1;
+        ancestor = address;
+        if depth == BEACON_MAX_DEPTH {
+            break;
+        }
+    }
+    Some(NeighborLqi {
+        extended_pan_id: state
+            .nv
+            .get(&NvItemId::ExtendedPanId.into())
+            .and_then(|value| value.as_slice().try_into().ok())
+            .unwrap_or_default(),
+        ieee_address,
+        network_address,
+        device_type_rx_on_when_idle_relationship: logical_type as u8
+            | (rx_on_when_idle as u8) << 2
+            | (relationship as u8) << 4,
+        permit_joining: 0,
+        depth,
+        lqi,
+    })
+}
+
+// The remote device at `network_address`, if it exists and is awake
+fn remote(state: &mut ZnpState, network_address: u16) -> Option<&mut RemoteDevice> {
+    let device = state.remote_devices.get_mut(&network_address)?;
+    device.hears().then_some(device)
+}
+
+// Children listed in an address response, only extended requests ask for them
+fn associated_devices(
+    device: &RemoteDevice,
+    request_type: u8,
+    start_index: u8,
+) -> CountedList<u8, u16> {
+    if request_type != AddressRequestType::Extended as u8 {
+        return Vec::new().into();
+    }
+    device
+        .associated_devices
+        .iter()
+        .skip(start_index.into())
+        .copied()
+        .collect::<Vec<_>>()
+        .into()
+}
+
+// The stack accepts a ZDO request right away, the device answers later if at all
+fn zdo_reply<R: CommandResponse + DekuContainerWrite>(
+    response: R,
+    answer: Option<SUnpiPacket>,
+) -> Vec<SUnpiPacket> {
+    std::iter::once(reply(&response)).chain(answer).collect()
+}
+
+// Answer of a remote device to a ZCL read attributes or Groups cluster command sent to it
+#[cfg(feature = "psila")]
+fn zcl_answer(state: &mut ZnpState, request: &DataRequestRequest) -> Option<SUnpiPacket> {
+    let device = remote(state, request.destination_address)?;
+    // No manufacturer code: frame control, sequence number, command
+    let [control, sequence, command, payload @ ..] = request.data.items.as_slice() else {
+        return None;
+    };
+    let data = match control & 0x07 {
+        0x00 => read_attributes_answer(device, request.cluster_id, *sequence, *command, payload)?,
+        0x01 if request.cluster_id == GROUPS_CLUSTER => {
+            groups_answer(device, *sequence, *command, payload)?
+        }
+        _ => return None,
+    };
+    Some(packet_from_command(&IncomingMsgRequest {
+        group_id: 0,
+        cluster_id: request.cluster_id,
+        source_address: request.destination_address,
+        source_endpoint: request.destination_endpoint,
+        destination_endpoint: request.source_endpoint,
+        was_broadcast: 0,
+        link_quality: 0xff,
+        security_use: 0,
+        timestamp: 0,
+        transaction_sequence_number: 0,
+        data: data.into(),
+    }))
+}
+
+// Without the ZCL support of the psila feature the remote devices leave frames unanswered
+#[cfg(not(feature = "psila"))]
+fn zcl_answer(_state: &mut ZnpState, _request: &DataRequestRequest) -> Option<SUnpiPacket> {
+    None
+}
+
+#[cfg(feature = "psila")]
+fn read_attributes_answer(
+    device: &RemoteDevice,
+    cluster_id: u16,
+    sequence: u8,
+    command: u8,
+    ids: &[u8],
+) -> Option<Vec<u8>> {
+    const UNSUPPORTED_ATTRIBUTE: u8 = 0x86;
+    if command != READ_ATTRIBUTES {
+        return None;
+    }
+    let mut data = vec![0x18, sequence, READ_ATTRIBUTES_RESPONSE];
+    for id in ids.chunks_exact(2) {
+        data.extend_from_slice(id);
+        let id = u16::from_le_bytes([id[0], id[1]]);
+        match device.attributes.get(&(cluster_id, id)) {
+            Some((data_type, value)) => {
+                data.push(0);
+                data.push(*data_type);
+                data.extend_from_slice(value);
+            }
+            None => data.push(UNSUPPORTED_ATTRIBUTE),
+        }
+    }
+    Some(data)
+}
+
+#[cfg(feature = "psila")]
+fn groups_answer(
+    device: &mut RemoteDevice,
+    sequence: u8,
+    command: u8,
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    const GROUP_CAPACITY: usize = 16;
+    const INSUFFICIENT_SPACE: u8 = 0x89;
+    const DUPLICATE_EXISTS: u8 = 0x8a;
+    const NOT_FOUND: u8 = 0x8b;
+    // Cluster specific, to the client, no default response
+    let mut data = vec![0x19, sequence, command];
+    let group_id = payload
+        .get(..2)
+        .map(|id| u16::from_le_bytes([id[0], id[1]]));
+    let position = group_id.and_then(|id| device.groups.iter().position(|(g, _)| *g == id));
+    match command {
+        GROUPS_ADD => {
+            let group_id = group_id?;
+            let name = payload.get(3..3 + *payload.get(2)? as usize)?;
+            data.push(if position.is_some() {
+                DUPLICATE_EXISTS
+            } else if device.groups.len() == GROUP_CAPACITY {
+                INSUFFICIENT_SPACE
+            } else {
+                let name = String::from_utf8_lossy(name).into_owned();
+                device.groups.push((group_id, name));
+                0
+            });
+            data.extend(group_id.to_le_bytes());
+        }
+        GROUPS_VIEW => {
+            data.push(if position.is_some() { 0 } else { NOT_FOUND });
+            data.extend(group_id?.to_le_bytes());
+            if let Some(position) = position {
+                let name = device.groups[position].1.as_bytes();
+                data.push(name.len() as u8);
+                data.extend_from_slice(name);
+            }
+        }
+        GROUPS_GET_MEMBERSHIP => {
+            let [count, ids @ ..] = payload else {
+                return None;
+            };
+            let asked: Vec<u16> = ids
+                .chunks_exact(2)
+                .take((*count).into())
+                .map(|id| u16::from_le_bytes([id[0], id[1]]))
+                .collect();
+            let groups: Vec<u16> = device
+                .groups
+                .iter()
+                .map(|(group_id, _)| *group_id)
+                .filter(|group_id| asked.is_empty() || asked.contains(group_id))
+                .collect();
+            data.push((GROUP_CAPACITY - device.groups.len()) as u8);
+            data.push(groups.len() as u8);
+            data.extend(groups.iter().flat_map(|group_id| group_id.to_le_bytes()));
+        }
+        GROUPS_REMOVE => {
+            match position {
+                Some(position) => {
+                    device.groups.remove(position);
+                    data.push(0);
+                }
+                None => data.push(NOT_FOUND),
+            }
+            data.extend(group_id?.to_le_bytes());
+        }
+        _ => return None,
+    }
+    Some(data)
+}
+
+// Applies the startup options and reboots, the network (if any) comes back on ZDO startup
+fn reset(state: &mut ZnpState) -> SUnpiPacket {
+    let startup_option = state
+        .nv
+        .get(&NvItemId::StartupOption.into())
+        .and_then(|v| v.first().copied())
+        .unwrap_or(0);
+    if startup_option & STARTUP_OPTION_CLEAR_CONFIG != 0 {
+        state.nv.extend(default_nv());
+    }
+    if startup_option & STARTUP_OPTION_CLEAR_STATE != 0 {
+        state.nv.remove(&NvItemId::NIB.into());
+    }
+    state.nv.insert(NvItemId::StartupOption.into(), vec![0x00]);
+    state.device_state = DeviceState::Hold as u8;
+    packet_from_command(&ResetIndRequest {
+        // power up
+        reason: 0,
+        transport_rev: state.version.transportrev,
+        product_id: state.version.product,
+        major_rel: state.version.majorrel,
+        minor_rel: state.version.minorrel,
+        hw_rev: state.version.maintrel,
+    })
+}
+
+// Forms the network the NV items describe, on the lowest allowed channel. Fails when no channel
+// of the list is in the Zigbee band.
+fn form_network(state: &mut ZnpState) -> bool {
+    let item = |id: NvItemId| state.nv.get(&id.into()).cloned().unwrap_or_default();
+    let pan_id = item(NvItemId::PanId);
+    let extended_pan_id = item(NvItemId::ExtendedPanId);
+    let channel_list = item(NvItemId::ChanList);
+    let key_info = [[0].as_slice(), &item(NvItemId::PreCfgKey)].concat();
+    let channel_mask = u32::from_le_bytes(channel_list[..4].try_into().unwrap());
+    let channel = channel_mask.trailing_zeros() as u8;
+
+    // Only the fields describing the network are filled in, in the layout of the emulated chip
+    let layout = ZnpVersion::from_product(state.version.product)
+        .map_or(NvLayout::Packed, NvLayout::for_version);
+    let mut nib = Nib::from_nv_bytes(&vec![0u8; Nib::item_len(layout)]).unwrap();
+    nib.nwk_dev_address = state.network_address;
+    if let Err(e) = nib.set_channel(channel) {
+        warn!(
+            "simulator can't form a network on channel {}: {:?}",
+            channel, e
+        );
+        return false;
+    }
+    nib.channel_list = channel_mask;
+    nib.set_pan_id(u16::from_le_bytes([pan_id[0], pan_id[1]]));
+    nib.set_extended_pan_id(extended_pan_id.try_into().unwrap());
+    let nib = nib.to_nv_bytes().unwrap();
+    state.nv.insert(NvItemId::NIB.into(), nib);
+
+    // Security material and an empty device table, like the stack sets up on formation
+    state
+        .nv
+        .insert(NvItemId::NwkActiveKeyInfo.into(), key_info.clone());
+    state.nv.insert(NvItemId::NwkAlternKeyInfo.into(), key_info);
+    state
+        .nv
+        .entry(NvItemId::TclkSeed.into())
+        .or_insert_with(|| vec![0x5a; 16]);
+    if layout == NvLayout::Packed {
+        let empty = encode_entry(&AddrMgrEntry::empty(), layout).unwrap();
+        state.nv.insert(
+            NvItemId::AddrMgr.into(),
+            empty.repeat(SIMULATOR_ADDR_MGR_ENTRIES),
+        );
+    }
+    state.device_state = DeviceState::Coordinator as u8;
+    true
+}
+
+// Items a factory-new Z-Stack holds, restored by a configuration reset
+fn default_nv() -> HashMap<u16, Vec<u8>> {
+    HashMap::from([
+        (NvItemId::StartupOption.into(), vec![0x00]),
+        (
+            NvItemId::LogicalType.into(),
+            vec![LogicalType::Coordinator as u8],
+        ),
+        (NvItemId::PanId.into(), vec![0xff, 0xff]),
+        (NvItemId::ExtendedPanId.into(), vec![0xff; 8]),
+        (
+            NvItemId::ChanList.into(),
+            0x0000_0800u32.to_le_bytes().to_vec(),
+        ),
+        (NvItemId::PreCfgKey.into(), vec![0x00; 16]),
+        (NvItemId::PrecfgkeysEnable.into(), vec![0x00]),
+        // Stored least significant byte first, survives configuration resets
+        (
+            NvItemId::ExtAddr.into(),
+            SIMULATOR_IEEE_ADDRESS.iter().rev().copied().collect(),
+        ),
+    ])
+}
+
+// Returns the status of the request itself and the one its confirm reports
+fn data_request(state: &mut ZnpState, source_endpoint: u8) -> (u8, u8) {
+    if !state.endpoints.contains(&source_endpoint) {
+        let status = status(CommandStatus::InvalidParam);
+        return (status, status);
+    }
+    let confirm_status = state
+        .data_confirm_statuses
+        .pop_front()
+        .unwrap_or(status(CommandStatus::Success));
+    (status(CommandStatus::Success), confirm_status)
+}
+
+fn nv_read(state: &ZnpState, id: u16, offset: usize) -> (u8, Buffer) {
+    match state.nv.get(&id) {
+        Some(value) if offset <= value.len() => {
+            let end = value.len().min(offset + SIMULATOR_NV_READ_MAX);
+            (status(CommandStatus::Success), buffer(&value[offset..end]))
+        }
+        Some(_) => (status(CommandStatus::NvBadItemLen), buffer(&[])),
+        None => (status(CommandStatus::NvOperFailed), buffer(&[])),
+    }
+}
+
+// The SYS_NV_* commands only exist on Z-Stack 3.x.0
+fn has_extended_nv(state: &ZnpState) -> bool {
+    state.version.product == ZnpVersion::ZStack3x0 as u8
+}
+
+fn nv_write(state: &mut ZnpState, id: u16, offset: usize, data: &[u8]) -> u8 {
+    match state.nv.get_mut(&id) {
+        Some(value) if offset + data.len() <= value.len() => {
+            value[offset..offset + data.len()].copy_from_slice(data);
+            status(CommandStatus::Success)
+        }
+        Some(_) => status(CommandStatus::NvBadItemLen),
+        None => status(CommandStatus::NvOperFailed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        subscription::{Event, Predicate, Subscription},
+        zstack::unpi::{
+            serial::{request_with_reply, wait_for},
+            subsystems::util::GetDeviceInfoResponse,
+        },
+    };
+    use deku::DekuWriter;
+
+    type Subscriptions = Arc<Mutex<SubscriptionService<SUnpiPacket>>>;
+
+    fn setup() -> (Arc<Mutex<ZnpSimulator>>, ZnpSimulator, Subscriptions) {
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
+        let simulator = ZnpSimulator::new(subscriptions.clone());
+        (
+            Arc::new(Mutex::new(simulator.clone())),
+            simulator,
+            subscriptions,
+        )
+    }
+
+    fn ask<R: CommandRequest + DekuWriter, Res>(
+        serial: &Arc<Mutex<ZnpSimulator>>,
+        subscriptions: &Subscriptions,
+        command: &R,
+    ) -> Res
+    where
+        Res: CommandResponse + for<'de> DekuReader<'de> + for<'de> DekuContainerRead<'de>,
+    {
+        let packet = SUnpiPacket::from_command_owned(LenTypeInfo::OneByte, command).unwrap();
+        block_on(request_with_reply::<R, _, Res>(
+            &packet,
+            serial.clone(),
+            subscriptions.clone(),
+            Some(std::time::Duration::from_secs(1)),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_simulator_ping_version_device_info() {
+        let (serial, simulator, subscriptions) = setup();
+        let ping: PingResponse = ask(&serial, &subscriptions, &PingRequest {});
+        assert_eq!(ping.capabilities, SIMULATOR_CAPABILITIES);
+        let version: VersionResponse = ask(&serial, &subscriptions, &VersionRequest {});
+        assert_eq!(version, simulator.with(|s| s.version.clone()));
+        let info: GetDeviceInfoResponse = ask(&serial, &subscriptions, &GetDeviceInfoRequest {});
+        assert_eq!(info.ieee_addr, simulator.with(|s| s.ieee_address));
+        assert_eq!(info.device_state, DeviceState::Hold as u8);
+        assert_eq!(simulator.with(|s| s.received.len()), 3);
+    }
+
+    #[test]
+    fn test_simulator_nv_read_write() {
+        let (serial, simulator, subscriptions) = setup();
+        simulator.set_nv_item(0x0083, &[0x34, 0x12]);
+        let length: OsalNvLengthResponse =
+            ask(&serial, &subscriptions, &OsalNvLengthRequest { id: 0x0083 });
+        assert_eq!(length.length, 2);
+        let write: OsalNvWriteResponse = ask(
+            &serial,
+            &subscriptions,
+            &OsalNvWriteRequest {
+                id: 0x0083,
+                offset: 1,
+                len: 1,
+                value: buffer(&[0xab]),
+            },
+        );
+        assert_eq!(write.status, 0);
+        let read: OsalNvReadResponse = ask(
+            &serial,
+            &subscriptions,
+            &OsalNvReadRequest {
+                id: 0x0083,
+                offset: 0,
+            },
+        );
+        assert_eq!(read.status, 0);
+        assert_eq!(read.value.as_slice(), &[0x34, 0xab]);
+        let missing: OsalNvReadResponse = ask(
+            &serial,
+            &subscriptions,
+            &OsalNvReadRequest {
+                id: 0x0f00,
+                offset: 0,
+            },
+        );
+        assert_eq!(missing.status, status(CommandStatus::NvOperFailed));
+    }
+
+    #[test]
+    fn test_simulator_startup_changes_state() {
+        let (serial, simulator, subscriptions) = setup();
+        let state_changed = wait_for(
+            StateChangedIndRequest::id(),
+            MessageType::AREQ,
+            Subsystem::Zdo,
+            subscriptions.clone(),
+            Some(std::time::Duration::from_secs(1)),
+        );
+        let startup = async {
+            let packet = SUnpiPacket::from_command_owned(
+                LenTypeInfo::OneByte,
+                &StartupFromAppRequest {
+                    start_delay: 100,
+                    status: 0,
+                },
+            )
+            .unwrap();
+            serial.lock().await.write(&packet).await.unwrap();
+        };
+        let (packet, _) = block_on(futures::future::join(state_changed, startup));
+        let indication: StateChangedIndRequest = packet.unwrap().to_command_request().unwrap();
+        assert_eq!(indication.state, DeviceState::Coordinator as u8);
+        assert_eq!(
+            simulator.with(|s| s.device_state),
+            DeviceState::Coordinator as u8
+        );
+    }
+
+    #[test]
+    fn test_simulator_inject_callback() {
+        let (_serial, simulator, subscriptions) = setup();
+        let (tx, rx) = std::sync::mpsc::channel();
+        block_on(subscriptions.lock()).subscribe(Subscription::Event(
+            Predicate(Box::new(|packet: &SUnpiPacket| {
+                packet.type_subsystem == (MessageType::AREQ, Subsystem::Zdo)
+                    && packet.command == TcDeviceIndexRequest::id()
+            })),
+            Event(Box::new(move |packet: &SUnpiPacket| {
+                let _ = tx.send(packet.to_command_request::<TcDeviceIndexRequest>().unwrap());
+            })),
+        ));
+        simulator
+            .inject_tc_device_index(0x1234, [1, 2, 3, 4, 5, 6, 7, 8], 0x0000)
+            .unwrap();
+        let device = rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(device.network_address, 0x1234);
+        assert_eq!(
+            device.extended_address.ieee_address,
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+    }
+}
@@ -27,11 +27,61 @@ impl<'a> DekuReader<'a, ()> for Buffer {
     where
         Self: Sized,
     {
+        // The buffer is always the last field, so it takes whatever is left up to its capacity
         let mut output = [0u8; 255];
-        reader.read_bytes(output.len(), &mut output)?;
+        let mut len = 0;
+        while len < output.len() && !reader.end() {
+            reader.read_bytes(1, &mut output[len..len + 1])?;
+            len += 1;
+        }
         Ok(Buffer {
             buffer: output,
-            len: output.len(),
+            len,
         })
     }
 }
+
+impl TryFrom<&[u8]> for Buffer {
+    type Error = DekuError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let mut buffer = [0u8; 255];
+        let Some(target) = buffer.get_mut(..data.len()) else {
+            return Err(DekuError::InvalidParam(
+                format!("{} bytes don't fit in a buffer of 255", data.len()).into(),
+            ));
+        };
+        target.copy_from_slice(data);
+        Ok(Buffer {
+            buffer,
+            len: data.len(),
+        })
+    }
+}
+
+impl Buffer {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deku::no_std_io;
+
+    #[test]
+    fn test_buffer_reads_until_end() {
+        let data = [1u8, 2, 3];
+        let mut cursor = no_std_io::Cursor::new(&data);
+        let mut reader = Reader::new(&mut cursor);
+        let buffer = Buffer::from_reader_with_ctx(&mut reader, ()).unwrap();
+        assert_eq!(buffer.as_slice(), &data);
+    }
+
+    #[test]
+    fn test_buffer_rejects_oversized_data() {
+        assert_eq!(Buffer::try_from(&[7u8; 255][..]).unwrap().len, 255);
+        assert!(Buffer::try_from(&[7u8; 256][..]).is_err());
+    }
+}
@@ -1,5 +1,11 @@
 use super::{MessageType, Subsystem};
-use deku::{DekuRead, DekuWrite};
+use deku::{
+    reader::Reader, writer::Writer, DekuError, DekuRead, DekuReader, DekuWrite, DekuWriter,
+};
+use std::{
+    io::{Read, Seek, Write},
+    marker::PhantomData,
+};
 
 pub const MAX_COMMAND_SIZE: usize = 15;
 
@@ -50,6 +56,67 @@ impl<T: Copy + Default> Default for List<T> {
     }
 }
 
+/// Variable length list preceded by its item count, the count being encoded as `L` (u8 or u16)
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CountedList<L, T> {
+    pub items: Vec<T>,
+    count: PhantomData<L>,
+}
+
+impl<L, T> CountedList<L, T> {
+    pub fn new(items: Vec<T>) -> Self {
+        CountedList {
+            items,
+            count: PhantomData,
+        }
+    }
+}
+
+impl<L, T> From<Vec<T>> for CountedList<L, T> {
+    fn from(items: Vec<T>) -> Self {
+        Self::new(items)
+    }
+}
+
+impl<'a, L, T> DekuReader<'a, ()> for CountedList<L, T>
+where
+    L: DekuReader<'a, ()> + TryInto<usize>,
+    T: DekuReader<'a, ()>,
+{
+    fn from_reader_with_ctx<R: Read + Seek>(
+        reader: &mut Reader<R>,
+        _ctx: (),
+    ) -> Result<Self, DekuError> {
+        let count = L::from_reader_with_ctx(reader, ())?
+            .try_into()
+            .map_err(|_| DekuError::Parse("invalid list count".into()))?;
+        let items = (0..count)
+            .map(|_| T::from_reader_with_ctx(reader, ()))
+            .collect::<Result<Vec<T>, DekuError>>()?;
+        Ok(Self::new(items))
+    }
+}
+
+impl<L, T> DekuWriter<()> for CountedList<L, T>
+where
+    L: DekuWriter<()> + TryFrom<usize>,
+    T: DekuWriter<()>,
+{
+    fn to_writer<W: Write + Seek>(
+        &self,
+        writer: &mut Writer<W>,
+        _ctx: (),
+    ) -> Result<(), DekuError> {
+        let count = L::try_from(self.items.len())
+            .map_err(|_| DekuError::InvalidParam("list too long".into()))?;
+        count.to_writer(writer, ())?;
+        for item in &self.items {
+            item.to_writer(writer, ())?;
+        }
+        Ok(())
+    }
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
 pub struct CommandIeeeAddress {
@@ -27,6 +27,186 @@ pub mod af {
     pub const DEFAULT_RADIUS: u8 = super::DEF_NWK_RADIUS;
 }
 
+pub mod zdo {
+    use serde::Serialize;
+
+    /// Device state reported by ZDO_STATE_CHANGE_IND and UTIL_GET_DEVICE_INFO
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum DeviceState {
+        Hold = 0,
+        Init = 1,
+        NwkDiscovering = 2,
+        NwkJoining = 3,
+        NwkRejoining = 4,
+        EndDeviceUnauthenticated = 5,
+        EndDevice = 6,
+        Router = 7,
+        CoordinatorStarting = 8,
+        Coordinator = 9,
+        NwkOrphan = 10,
+    }
+
+    impl DeviceState {
+        pub fn from_state(state: u8) -> Option<Self> {
+            match state {
+                0 => Some(DeviceState::Hold),
+                1 => Some(DeviceState::Init),
+                2 => Some(DeviceState::NwkDiscovering),
+                3 => Some(DeviceState::NwkJoining),
+                4 => Some(DeviceState::NwkRejoining),
+                5 => Some(DeviceState::EndDeviceUnauthenticated),
+                6 => Some(DeviceState::EndDevice),
+                7 => Some(DeviceState::Router),
+                8 => Some(DeviceState::CoordinatorStarting),
+                9 => Some(DeviceState::Coordinator),
+                10 => Some(DeviceState::NwkOrphan),
+                _ => None,
+            }
+        }
+    }
+
+    /// Status returned by ZDO_STARTUP_FROM_APP
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum StartupStatus {
+        RestoredNetworkState = 0,
+        NewNetworkState = 1,
+        LeaveAndNotStarted = 2,
+    }
+
+    /// Value of the `LogicalType` NV item
+    #[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+    pub enum LogicalType {
+        Coordinator = 0,
+        Router = 1,
+        EndDevice = 2,
+    }
+
+    impl LogicalType {
+        pub fn from_type(logical_type: u8) -> Option<Self> {
+            match logical_type {
+                0 => Some(LogicalType::Coordinator),
+                1 => Some(LogicalType::Router),
+                2 => Some(LogicalType::EndDevice),
+                _ => None,
+            }
+        }
+    }
+
+    /// What ZDO_NWK_ADDR_REQ and ZDO_IEEE_ADDR_REQ ask for
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum AddressRequestType {
+        /// Only the address of the device
+        Single = 0,
+        /// The address of the device and the devices that joined through it
+        Extended = 1,
+    }
+
+    /// How a neighbor listed by ZDO_MGMT_LQI_RSP relates to the device that lists it
+    #[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+    pub enum Relationship {
+        Parent = 0,
+        Child = 1,
+        Sibling = 2,
+        /// None of the above
+        Other = 3,
+        PreviousChild = 4,
+    }
+
+    impl Relationship {
+        pub fn from_relationship(relationship: u8) -> Option<Self> {
+            match relationship {
+                0 => Some(Relationship::Parent),
+                1 => Some(Relationship::Child),
+                2 => Some(Relationship::Sibling),
+                3 => Some(Relationship::Other),
+                4 => Some(Relationship::PreviousChild),
+                _ => None,
+            }
+        }
+    }
+
+    /// State of a route listed by ZDO_MGMT_RTG_RSP
+    #[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+    pub enum RouteStatus {
+        Active = 0,
+        DiscoveryUnderway = 1,
+        DiscoveryFailed = 2,
+        Inactive = 3,
+        ValidationUnderway = 4,
+    }
+
+    impl RouteStatus {
+        pub fn from_status(status: u8) -> Option<Self> {
+            match status {
+                0 => Some(RouteStatus::Active),
+                1 => Some(RouteStatus::DiscoveryUnderway),
+                2 => Some(RouteStatus::DiscoveryFailed),
+                3 => Some(RouteStatus::Inactive),
+                4 => Some(RouteStatus::ValidationUnderway),
+                _ => None,
+            }
+        }
+    }
+
+    /// Bit of the MAC capabilities of a device that runs on mains power
+    pub const MAC_CAPABILITY_MAINS_POWERED: u8 = 0x04;
+    /// Bit of the MAC capabilities of a device that keeps its receiver on when idle
+    pub const MAC_CAPABILITY_RX_ON_WHEN_IDLE: u8 = 0x08;
+
+    /// Bits of the `StartupOption` NV item, applied on the next reset
+    pub const STARTUP_OPTION_CLEAR_CONFIG: u8 = 0x01;
+    pub const STARTUP_OPTION_CLEAR_STATE: u8 = 0x02;
+
+    /// Bits of the `remove_children_rejoin` byte of ZDO_MGMT_LEAVE_REQ
+    pub const LEAVE_OPTION_REJOIN: u8 = 0x01;
+    pub const LEAVE_OPTION_REMOVE_CHILDREN: u8 = 0x02;
+}
+
+pub mod app_cnf {
+    /// Modes accepted by APP_CNF_BDB_START_COMMISSIONING
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum CommissioningMode {
+        Initialization = 0x00,
+        TouchLink = 0x01,
+        NetworkSteering = 0x02,
+        NetworkFormation = 0x04,
+        FindingAndBinding = 0x08,
+    }
+
+    /// What APP_CNF_BDB_ADD_INSTALLCODE is given for a device
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum InstallCodeFormat {
+        /// The install code followed by its CRC, the stack derives the link key
+        InstallCodeAndCrc = 0x01,
+        /// The link key already derived from the install code
+        DerivedKey = 0x02,
+    }
+}
+
+pub mod sys {
+    /// Firmware family, reported in the `product` field of SYS_VERSION
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum ZnpVersion {
+        ZStack12 = 0,
+        ZStack3x0 = 1,
+        ZStack30x = 2,
+    }
+
+    impl ZnpVersion {
+        pub fn from_product(product: u8) -> Option<Self> {
+            match product {
+                0 => Some(ZnpVersion::ZStack12),
+                1 => Some(ZnpVersion::ZStack3x0),
+                2 => Some(ZnpVersion::ZStack30x),
+                _ => None,
+            }
+        }
+    }
+
+    /// System ID of the Z-Stack items in the SYS_NV_* extended item commands
+    pub const NV_SYSTEM_ID_ZSTACK: u8 = 1;
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum CommandStatus {
     Success,
@@ -0,0 +1,180 @@
+use super::{LenTypeInfo, SUnpiPacket, UnpiPacket, START_OF_FRAME};
+use crate::{
+    serial::simple_serial_port::{FromSerial, SerialDecoder},
+    utils::warn,
+};
+
+/// Incremental UNPI frame decoder.
+///
+/// Serial reads don't respect frame boundaries: a frame can be split across several reads and a
+/// single read can carry more than one frame. The decoder keeps the bytes between reads, emits
+/// every complete frame in order and resynchronizes on the next start of frame whenever it finds
+/// garbage or a frame that fails to parse (wrong FCS, invalid type/subsystem and so on).
+#[derive(Debug, Clone)]
+pub struct UnpiDecoder {
+    buffer: Vec<u8>,
+    len_type_info: LenTypeInfo,
+}
+
+impl UnpiDecoder {
+    pub fn new(len_type_info: LenTypeInfo) -> Self {
+        Self {
+            buffer: Vec::new(),
+            len_type_info,
+        }
+    }
+
+    /// Number of bytes waiting for the rest of their frame
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Appends bytes read from the transport
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame, or None if more bytes are needed
+    pub fn next_packet(&mut self) -> Option<SUnpiPacket> {
+        loop {
+            let Some(start_of_frame_position) =
+                self.buffer.iter().position(|&x| x == START_OF_FRAME)
+            else {
+                if !self.buffer.is_empty() {
+                    warn!(
+                        "discarding {} bytes without start of frame",
+                        self.buffer.len()
+                    );
+                    self.buffer.clear();
+                }
+                return None;
+            };
+            if start_of_frame_position > 0 {
+                warn!(
+                    "discarding {} bytes before start of frame",
+                    start_of_frame_position
+                );
+                self.buffer.drain(..start_of_frame_position);
+            }
+
+            // SOF(1) + Length(1 or 2) + Type/Sub(1) + Cmd(1) + Payload(N) + FCS(1)
+            let len_size = self.len_type_info.byte_size();
+            if self.buffer.len() < 1 + len_size {
+                return None;
+            }
+            let payload_len = match self.len_type_info {
+                LenTypeInfo::OneByte => self.buffer[1] as usize,
+                LenTypeInfo::TwoByte => {
+                    u16::from_le_bytes([self.buffer[1], self.buffer[2]]) as usize
+                }
+            };
+            let frame_len = 1 + len_size + 2 + payload_len + 1;
+            if self.buffer.len() < frame_len {
+                return None;
+            }
+
+            match UnpiPacket::try_from((&self.buffer[..frame_len], self.len_type_info)) {
+                Ok(packet) => {
+                    let packet = packet.to_owned();
+                    self.buffer.drain(..frame_len);
+                    return Some(packet);
+                }
+                Err(e) => {
+                    // Not a real frame, skip this start of frame and look for the next one
+                    warn!("discarding malformed frame: {:?}", e);
+                    self.buffer.drain(..1);
+                }
+            }
+        }
+    }
+}
+
+impl Default for UnpiDecoder {
+    fn default() -> Self {
+        Self::new(LenTypeInfo::OneByte)
+    }
+}
+
+impl SerialDecoder<SUnpiPacket> for UnpiDecoder {
+    fn push(&mut self, data: &[u8]) {
+        UnpiDecoder::push(self, data)
+    }
+
+    fn next_packet(&mut self) -> Option<SUnpiPacket> {
+        UnpiDecoder::next_packet(self)
+    }
+}
+
+impl FromSerial for SUnpiPacket {
+    type Decoder = UnpiDecoder;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zstack::unpi::{MessageType, Subsystem};
+
+    const PING: [u8; 5] = [0xfe, 0x00, 0x21, 0x01, 0x20];
+    const PAYLOAD: [u8; 7] = [0xfe, 0x02, 0x25, 0x37, 0x55, 0xdd, 0x98];
+
+    fn drain(decoder: &mut UnpiDecoder) -> Vec<SUnpiPacket> {
+        std::iter::from_fn(|| decoder.next_packet()).collect()
+    }
+
+    #[test]
+    fn test_decoder_single_frame() {
+        let mut decoder = UnpiDecoder::default();
+        decoder.push(&PING);
+        let packets = drain(&mut decoder);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0].type_subsystem,
+            (MessageType::SREQ, Subsystem::Sys)
+        );
+        assert_eq!(packets[0].command, 1);
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn test_decoder_split_frame() {
+        let mut decoder = UnpiDecoder::default();
+        for chunk in PAYLOAD.chunks(2) {
+            assert!(decoder.next_packet().is_none());
+            decoder.push(chunk);
+        }
+        let packets = drain(&mut decoder);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].payload, vec![0x55, 0xdd]);
+    }
+
+    #[test]
+    fn test_decoder_coalesced_frames() {
+        let mut decoder = UnpiDecoder::default();
+        let mut data = PING.to_vec();
+        data.extend_from_slice(&PAYLOAD);
+        data.extend_from_slice(&PAYLOAD[..3]);
+        decoder.push(&data);
+        let packets = drain(&mut decoder);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].command, 0x01);
+        assert_eq!(packets[1].command, 0x37);
+        assert_eq!(decoder.pending(), 3);
+        decoder.push(&PAYLOAD[3..]);
+        assert_eq!(drain(&mut decoder).len(), 1);
+    }
+
+    #[test]
+    fn test_decoder_resync_after_garbage_and_bad_fcs() {
+        let mut decoder = UnpiDecoder::default();
+        let mut bad_fcs = PAYLOAD;
+        bad_fcs[6] = 0x00;
+        let mut data = vec![0x01, 0x02];
+        data.extend_from_slice(&bad_fcs);
+        data.extend_from_slice(&PING);
+        decoder.push(&data);
+        let packets = drain(&mut decoder);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].command, 0x01);
+        assert_eq!(decoder.pending(), 0);
+    }
+}
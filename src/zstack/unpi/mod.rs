@@ -9,6 +9,7 @@ use std::future::Future;
 pub mod buffer;
 pub mod commands;
 pub mod constants;
+pub mod decoder;
 pub mod serial;
 pub mod subsystems;
 
@@ -46,7 +47,7 @@ impl ToSerial for SUnpiPacket {
     }
 }
 
-impl<'a> ToSerial for &'a [u8] {
+impl ToSerial for &[u8] {
     fn to_serial<W: std::io::Write + ?Sized>(&self, serial: &mut W) -> Result<(), std::io::Error> {
         serial.write_all(self)?;
         Ok(())
@@ -194,7 +195,7 @@ pub enum Subsystem {
     RcnClient,
     Boot,
     Ziptest,
-    Debug,
+    AppCnf,
     Peripherals,
     Nfc,
     PbNwkMgr,
@@ -231,7 +232,7 @@ impl From<Subsystem> for u8 {
             Subsystem::RcnClient => 12,
             Subsystem::Boot => 13,
             Subsystem::Ziptest => 14,
-            Subsystem::Debug => 15,
+            Subsystem::AppCnf => 15,
             Subsystem::Peripherals => 16,
             Subsystem::Nfc => 17,
             Subsystem::PbNwkMgr => 18,
@@ -515,7 +516,7 @@ impl TryFrom<u8> for Subsystem {
             12 => Ok(Subsystem::RcnClient),
             13 => Ok(Subsystem::Boot),
             14 => Ok(Subsystem::Ziptest),
-            15 => Ok(Subsystem::Debug),
+            15 => Ok(Subsystem::AppCnf),
             16 => Ok(Subsystem::Peripherals),
             17 => Ok(Subsystem::Nfc),
             18 => Ok(Subsystem::PbNwkMgr),
@@ -574,8 +575,8 @@ mod tests {
     pub fn test_unpi_payload_to_from_bytes() {
         let data = [0xfe, 0x02, 0x25, 0x37, 0x55, 0xdd, 0x98];
         let packet = UnpiPacket::try_from((&data[..], LenTypeInfo::OneByte)).unwrap();
-        let mut output: &mut [u8] = &mut [0u8; MAX_FRAME_SIZE];
-        let len = packet.to_bytes(&mut output).unwrap();
+        let output: &mut [u8] = &mut [0u8; MAX_FRAME_SIZE];
+        let len = packet.to_bytes(output).unwrap();
         assert_eq!(&output[0..len], &data[..]);
     }
 
@@ -588,7 +589,7 @@ mod tests {
         )
         .unwrap();
         let output = &mut [0u8; MAX_FRAME_SIZE];
-        let len = packet.to_bytes(&mut output.as_mut()).unwrap();
+        let len = packet.to_bytes(output.as_mut()).unwrap();
         assert_eq!(&output[0..len], &[0xFE, 0x02, 0x25, 0x37, 0x55, 0xdd, 0x98]);
     }
 
@@ -616,8 +617,8 @@ mod tests {
     pub fn test_unpi_double_len_to_from_bytes() {
         let data = [0xFEu8, 0x04, 0x00, 0x25, 0x04, 0x01, 0x02, 0x03, 0x04, 0x21];
         let packet = UnpiPacket::try_from((&data[..], LenTypeInfo::TwoByte)).unwrap();
-        let mut output: &mut [u8] = &mut [0u8; MAX_FRAME_SIZE];
-        let len = packet.to_bytes(&mut output).unwrap();
+        let output: &mut [u8] = &mut [0u8; MAX_FRAME_SIZE];
+        let len = packet.to_bytes(output).unwrap();
         assert_eq!(&output[0..len], &data[..])
     }
 
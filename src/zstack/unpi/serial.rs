@@ -3,8 +3,8 @@ use crate::zstack::unpi::commands::CommandResponse;
 use crate::{
     coordinator::CoordinatorError,
     serial::{simple_serial_port::ToSerial, SimpleSerial},
-    subscription::{Action, Predicate, Subscription, SubscriptionService},
-    utils::map::MapError,
+    subscription::{Action, Predicate, Subscription, SubscriptionId, SubscriptionService},
+    utils::{map::MapError, sleep, warn},
     zstack::unpi::MAX_PAYLOAD_SIZE,
 };
 use crate::{serial::SerialThreadError, utils::info};
@@ -17,6 +17,9 @@ use futures::{
 use serialport::SerialPort;
 use std::sync::Arc;
 
+/// Timeout used when waiting for a reply without an explicit timeout
+pub const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(6);
+
 // reusable request function
 pub async fn request<R: CommandRequest + DekuWriter, S: SimpleSerial<SUnpiPacket>>(
     packet: &SUnpiPacket,
@@ -36,18 +39,26 @@ pub async fn request_with_reply<
     subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
     timeout: Option<std::time::Duration>,
 ) -> Result<Res, UnpiCommandError> {
-    let wait = wait_for(
+    // Subscribe before writing so a fast reply can't slip through before we're listening
+    let pending = subscribe_for(
         R::Response::id(),
         MessageType::SRESP,
         R::Response::subsystem(),
         subscriptions.clone(),
-        timeout,
-    );
+    )
+    .await;
     let send = async {
         let mut s = serial.lock().await;
         s.write(packet).await.map_err(UnpiCommandError::Serial)
     };
-    futures::try_join!(send, wait).map(|(_, packet)| packet.to_command_response())?
+    if let Err(e) = send.await {
+        subscriptions.lock().await.unsubscribe(pending.id);
+        return Err(e);
+    }
+    pending
+        .wait(subscriptions, timeout)
+        .await?
+        .to_command_response()
 }
 
 // reusable wait_for function
@@ -56,25 +67,85 @@ pub async fn wait_for(
     message_type: MessageType,
     subsystem: Subsystem,
     subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
-    _timeout: Option<std::time::Duration>,
+    timeout: Option<std::time::Duration>,
 ) -> Result<SUnpiPacket, UnpiCommandError> {
-    // let command = get_command_by_name(&subsystem, name)
-    //     .ok_or(UnpiCommandError::NoCommandWithName(name.to_string()))?;
+    subscribe_for(command_id, message_type, subsystem, subscriptions.clone())
+        .await
+        .wait(subscriptions, timeout)
+        .await
+}
+
+/// Registers a single shot subscription for the next packet matching the command, without waiting for it
+pub async fn subscribe_for(
+    command_id: u8,
+    message_type: MessageType,
+    subsystem: Subsystem,
+    subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+) -> PendingPacket {
+    subscribe_for_matching(command_id, message_type, subsystem, |_| true, subscriptions).await
+}
+
+/// Like [`subscribe_for`], but the packet must also satisfy `matches` (a transaction id, a state...)
+pub async fn subscribe_for_matching(
+    command_id: u8,
+    message_type: MessageType,
+    subsystem: Subsystem,
+    matches: impl Fn(&SUnpiPacket) -> bool + Send + Sync + 'static,
+    subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+) -> PendingPacket {
+    subscribe_for_any_matching(
+        message_type,
+        subsystem,
+        move |packet| packet.command == command_id && matches(packet),
+        subscriptions,
+    )
+    .await
+}
+
+/// Like [`subscribe_for_matching`], for replies that may come as any command of the subsystem
+pub async fn subscribe_for_any_matching(
+    message_type: MessageType,
+    subsystem: Subsystem,
+    matches: impl Fn(&SUnpiPacket) -> bool + Send + Sync + 'static,
+    subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+) -> PendingPacket {
     let (tx, rx): (Sender<SUnpiPacket>, Receiver<SUnpiPacket>) = oneshot::channel();
-    {
-        let mut s = subscriptions.lock().await;
-        let subscription = Subscription::SingleShot(
-            Predicate(Box::new(move |packet: &SUnpiPacket| {
-                packet.type_subsystem == (message_type, subsystem) && packet.command == command_id
-            })),
-            Action(Box::new(move |packet: &SUnpiPacket| {
-                let _ = tx.send(packet.clone());
-            })),
-        );
-        s.subscribe(subscription);
-    }
+    let subscription = Subscription::SingleShot(
+        Predicate(Box::new(move |packet: &SUnpiPacket| {
+            packet.type_subsystem == (message_type, subsystem) && matches(packet)
+        })),
+        Action(Box::new(move |packet: &SUnpiPacket| {
+            let _ = tx.send(packet.clone());
+        })),
+    );
+    let id = subscriptions.lock().await.subscribe(subscription);
+    PendingPacket { id, rx }
+}
+
+/// A packet we subscribed to but haven't received yet
+pub struct PendingPacket {
+    pub id: SubscriptionId,
+    rx: Receiver<SUnpiPacket>,
+}
 
-    rx.await.map_err(|_| UnpiCommandError::SubscriptionError)
+impl PendingPacket {
+    /// Waits for the packet, removing the subscription if it doesn't arrive in time.
+    /// Uses [`DEFAULT_TIMEOUT`] when no timeout is given.
+    pub async fn wait(
+        self,
+        subscriptions: Arc<Mutex<SubscriptionService<SUnpiPacket>>>,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<SUnpiPacket, UnpiCommandError> {
+        let timeout = timeout.unwrap_or(DEFAULT_TIMEOUT);
+        match sleep::timeout(timeout, self.rx).await {
+            Ok(packet) => packet.map_err(|_| UnpiCommandError::SubscriptionError),
+            Err(_) => {
+                warn!("timed out after {:?} waiting for reply", timeout);
+                subscriptions.lock().await.unsubscribe(self.id);
+                Err(UnpiCommandError::Timeout)
+            }
+        }
+    }
 }
 
 impl<T: Clone> UnpiPacket<T>
@@ -140,6 +211,7 @@ pub enum UnpiCommandError {
     InvalidResponse,
     Bincode,
     Deku(deku::DekuError),
+    Timeout,
 }
 
 impl From<std::io::Error> for UnpiCommandError {
@@ -165,3 +237,41 @@ impl From<deku::DekuError> for UnpiCommandError {
         UnpiCommandError::Deku(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zstack::unpi::{
+        subsystems::sys::{PingRequest, PingResponse},
+        LenTypeInfo,
+    };
+    use futures::executor::block_on;
+
+    // Swallows everything, like a dongle that stopped answering
+    struct SilentSerial;
+
+    impl SimpleSerial<SUnpiPacket> for SilentSerial {
+        type Sender = ();
+        type Receiver = ();
+
+        async fn write(&mut self, _packet: &SUnpiPacket) -> Result<(), SerialThreadError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_request_with_reply_timeout_removes_subscription() {
+        let serial = Arc::new(Mutex::new(SilentSerial));
+        let subscriptions = Arc::new(Mutex::new(SubscriptionService::new()));
+        let packet =
+            SUnpiPacket::from_command_owned(LenTypeInfo::OneByte, &PingRequest {}).unwrap();
+        let r = block_on(request_with_reply::<PingRequest, _, PingResponse>(
+            &packet,
+            serial,
+            subscriptions.clone(),
+            Some(std::time::Duration::from_millis(10)),
+        ));
+        assert!(matches!(r, Err(UnpiCommandError::Timeout)));
+        assert!(block_on(subscriptions.lock()).is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{
+    command,
+    coordinator::AddressMode,
+    zstack::unpi::{commands::CountedList, MessageType, Subsystem},
+};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+command! {
+    0,
+    Subsystem::Af,
+    MessageType::SREQ,
+    struct RegisterRequest {
+        endpoint: u8,
+        app_prof_id: u16,
+        app_device_id: u16,
+        app_dev_ver: u8,
+        latency_req: u8,
+        app_in_cluster_list: CountedList<u8, u16>,
+        app_out_cluster_list: CountedList<u8, u16>
+    },
+    struct RegisterResponse {
+        status: u8
+    },
+}
+
+command! {
+    1,
+    Subsystem::Af,
+    MessageType::SREQ,
+    struct DataRequestRequest {
+        destination_address: u16,
+        destination_endpoint: u8,
+        source_endpoint: u8,
+        cluster_id: u16,
+        transaction_id: u8,
+        options: u8,
+        radius: u8,
+        data: CountedList<u8, u8>
+    },
+    struct DataRequestResponse {
+        status: u8
+    },
+}
+
+command! {
+    2,
+    Subsystem::Af,
+    MessageType::SREQ,
+    struct DataRequestExtRequest {
+        destination_address_mode: u8,
+        destination_address: [u8; 8],
+        destination_endpoint: u8,
+        destination_pan_id: u16,
+        source_endpoint: u8,
+        cluster_id: u16,
+        transaction_id: u8,
+        options: u8,
+        radius: u8,
+        data: CountedList<u16, u8>
+    },
+    struct DataRequestExtResponse {
+        status: u8
+    },
+}
+
+command! {
+    128,
+    Subsystem::Af,
+    MessageType::AREQ,
+    struct DataConfirmRequest {
+        status: u8,
+        endpoint: u8,
+        transaction_id: u8
+    },
+    struct DataConfirmResponse {
+
+    },
+}
+
+command! {
+    129,
+    Subsystem::Af,
+    MessageType::AREQ,
+    struct IncomingMsgRequest {
+        group_id: u16,
+        cluster_id: u16,
+        source_address: u16,
+        source_endpoint: u8,
+        destination_endpoint: u8,
+        was_broadcast: u8,
+        link_quality: u8,
+        security_use: u8,
+        timestamp: u32,
+        transaction_sequence_number: u8,
+        // Newer firmwares append the MAC source address and radius, those are ignored
+        data: CountedList<u8, u8>
+    },
+    struct IncomingMsgResponse {
+
+    },
+}
+
+command! {
+    130,
+    Subsystem::Af,
+    MessageType::AREQ,
+    struct IncomingMsgExtRequest {
+        group_id: u16,
+        cluster_id: u16,
+        source_address_mode: u8,
+        source_address: [u8; 8],
+        source_endpoint: u8,
+        source_pan_id: u16,
+        destination_endpoint: u8,
+        was_broadcast: u8,
+        link_quality: u8,
+        security_use: u8,
+        timestamp: u32,
+        transaction_sequence_number: u8,
+        data: CountedList<u16, u8>
+    },
+    struct IncomingMsgExtResponse {
+
+    },
+}
+
+impl DataRequestRequest {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        destination_address: u16,
+        destination_endpoint: u8,
+        source_endpoint: u8,
+        cluster_id: u16,
+        transaction_id: u8,
+        options: u8,
+        radius: u8,
+        data: Vec<u8>,
+    ) -> Self {
+        DataRequestRequest {
+            destination_address,
+            destination_endpoint,
+            source_endpoint,
+            cluster_id,
+            transaction_id,
+            options,
+            radius,
+            data: data.into(),
+        }
+    }
+}
+
+/// Hands out the transaction IDs used to match a data request with its AF_DATA_CONFIRM.
+/// IDs wrap around, so only 256 requests can be in flight at the same time.
+#[derive(Debug, Default)]
+pub struct TransactionIdGenerator {
+    next: AtomicU8,
+}
+
+impl TransactionIdGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next_id(&self) -> u8 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Application message received by one of our endpoints, from either AF_INCOMING_MSG or
+/// AF_INCOMING_MSG_EXT
+#[derive(Debug, PartialEq, Clone)]
+pub struct IncomingMessage {
+    pub group_id: u16,
+    pub cluster_id: u16,
+    pub source_address: u16,
+    /// Only known when the message came through AF_INCOMING_MSG_EXT with a 64 bit address
+    pub source_ieee_address: Option<[u8; 8]>,
+    pub source_endpoint: u8,
+    pub destination_endpoint: u8,
+    pub was_broadcast: bool,
+    pub link_quality: u8,
+    pub security_use: bool,
+    pub timestamp: u32,
+    pub transaction_sequence_number: u8,
+    pub data: Vec<u8>,
+}
+
+impl From<IncomingMsgRequest> for IncomingMessage {
+    fn from(m: IncomingMsgRequest) -> Self {
+        IncomingMessage {
+            group_id: m.group_id,
+            cluster_id: m.cluster_id,
+            source_address: m.source_address,
+            source_ieee_address: None,
+            source_endpoint: m.source_endpoint,
+            destination_endpoint: m.destination_endpoint,
+            was_broadcast: m.was_broadcast != 0,
+            link_quality: m.link_quality,
+            security_use: m.security_use != 0,
+            timestamp: m.timestamp,
+            transaction_sequence_number: m.transaction_sequence_number,
+            data: m.data.items,
+        }
+    }
+}
+
+impl From<IncomingMsgExtRequest> for IncomingMessage {
+    fn from(m: IncomingMsgExtRequest) -> Self {
+        let (source_address, source_ieee_address) =
+            if m.source_address_mode == AddressMode::Addr64bit as u8 {
+                (0xfffe, Some(m.source_address))
+            } else {
+                (
+                    u16::from_le_bytes([m.source_address[0], m.source_address[1]]),
+                    None,
+                )
+            };
+        IncomingMessage {
+            group_id: m.group_id,
+            cluster_id: m.cluster_id,
+            source_address,
+            source_ieee_address,
+            source_endpoint: m.source_endpoint,
+            destination_endpoint: m.destination_endpoint,
+            was_broadcast: m.was_broadcast != 0,
+            link_quality: m.link_quality,
+            security_use: m.security_use != 0,
+            timestamp: m.timestamp,
+            transaction_sequence_number: m.transaction_sequence_number,
+            data: m.data.items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deku::{DekuContainerRead, DekuContainerWrite};
+
+    #[test]
+    fn test_register_serialization() {
+        let request = RegisterRequest {
+            endpoint: 1,
+            app_prof_id: 0x0104,
+            app_device_id: 0x0005,
+            app_dev_ver: 0,
+            latency_req: 0,
+            app_in_cluster_list: vec![0x0000, 0x0006].into(),
+            app_out_cluster_list: vec![0x0019].into(),
+        };
+        let bytes = request.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            vec![1, 0x04, 0x01, 0x05, 0x00, 0, 0, 2, 0x00, 0x00, 0x06, 0x00, 1, 0x19, 0x00]
+        );
+        let (_, parsed) = RegisterRequest::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(parsed, request);
+    }
+
+    #[test]
+    fn test_incoming_msg_with_trailing_bytes() {
+        let data = [
+            0x00, 0x00, 0x06, 0x00, 0x34, 0x12, 0x01, 0x01, 0x00, 0x73, 0x00, 0x10, 0x20, 0x30,
+            0x40, 0x05, 0x03, 0x18, 0x05, 0x0b, 0x34, 0x12, 0x1d,
+        ];
+        let (_, request) = IncomingMsgRequest::from_bytes((&data, 0)).unwrap();
+        let message = IncomingMessage::from(request);
+        assert_eq!(message.cluster_id, 0x0006);
+        assert_eq!(message.source_address, 0x1234);
+        assert_eq!(message.link_quality, 0x73);
+        assert_eq!(message.timestamp, 0x40302010);
+        assert_eq!(message.transaction_sequence_number, 5);
+        assert_eq!(message.data, vec![0x18, 0x05, 0x0b]);
+    }
+}
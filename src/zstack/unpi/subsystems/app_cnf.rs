@@ -0,0 +1,57 @@
+use crate::{
+    command,
+    zstack::unpi::{buffer::Buffer, commands::CommandIeeeAddress, MessageType, Subsystem},
+};
+
+command! {
+    4,
+    Subsystem::AppCnf,
+    MessageType::SREQ,
+    struct BdbAddInstallCodeRequest {
+        install_code_format: u8,
+        ieee_address: CommandIeeeAddress,
+        install_code: Buffer
+    },
+    struct BdbAddInstallCodeResponse {
+        status: u8
+    },
+}
+
+command! {
+    5,
+    Subsystem::AppCnf,
+    MessageType::SREQ,
+    struct BdbStartCommissioningRequest {
+        mode: u8
+    },
+    struct BdbStartCommissioningResponse {
+        status: u8
+    },
+}
+
+command! {
+    8,
+    Subsystem::AppCnf,
+    MessageType::SREQ,
+    struct BdbSetChannelRequest {
+        is_primary: u8,
+        channel: u32
+    },
+    struct BdbSetChannelResponse {
+        status: u8
+    },
+}
+
+command! {
+    128,
+    Subsystem::AppCnf,
+    MessageType::AREQ,
+    struct BdbCommissioningNotificationRequest {
+        status: u8,
+        commissioning_mode: u8,
+        remaining_commissioning_modes: u8
+    },
+    struct BdbCommissioningNotificationResponse {
+
+    },
+}
@@ -1,3 +1,5 @@
+pub mod af;
+pub mod app_cnf;
 pub mod sys;
 pub mod util;
 pub mod zdo;
@@ -55,6 +55,19 @@ command! {
     },
 }
 
+command! {
+    18,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct OsalNvDeleteRequest {
+        id: u16,
+        len: u16
+    },
+    struct OsalNvDeleteResponse {
+        status: u8
+    },
+}
+
 command! {
     19,
     Subsystem::Sys,
@@ -67,13 +80,28 @@ command! {
     },
 }
 
+command! {
+    7,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct OsalNvItemInitRequest {
+        id: u16,
+        len: u16,
+        init_len: u8,
+        init_value: Buffer
+    },
+    struct OsalNvItemInitResponse {
+        status: u8
+    },
+}
+
 command! {
     8,
     Subsystem::Sys,
     MessageType::SREQ,
     struct OsalNvReadRequest {
         id: u16,
-        offset: u16
+        offset: u8
     },
     struct OsalNvReadResponse {
         status: u8,
@@ -98,16 +126,155 @@ command! {
 }
 
 command! {
-    29,
+    9,
     Subsystem::Sys,
     MessageType::SREQ,
     struct OsalNvWriteRequest {
+        id: u16,
+        offset: u8,
+        len: u8,
+        value: Buffer
+    },
+    struct OsalNvWriteResponse {
+        status: u8
+    },
+}
+
+command! {
+    29,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct OsalNvWriteExtRequest {
         id: u16,
         offset: u16,
         len: u16,
         value: Buffer
     },
-    struct OsalNvWriteResponse {
+    struct OsalNvWriteExtResponse {
+        status: u8
+    },
+}
+
+// Extended NV items of Z-Stack 3.x.0, addressed by system, item and sub ID
+
+command! {
+    48,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct NvCreateRequest {
+        sys_id: u8,
+        item_id: u16,
+        sub_id: u16,
+        length: u32
+    },
+    struct NvCreateResponse {
+        status: u8
+    },
+}
+
+command! {
+    49,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct NvDeleteRequest {
+        sys_id: u8,
+        item_id: u16,
+        sub_id: u16
+    },
+    struct NvDeleteResponse {
+        status: u8
+    },
+}
+
+command! {
+    50,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct NvLengthRequest {
+        sys_id: u8,
+        item_id: u16,
+        sub_id: u16
+    },
+    struct NvLengthResponse {
+        length: u32
+    },
+}
+
+command! {
+    51,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct NvReadRequest {
+        sys_id: u8,
+        item_id: u16,
+        sub_id: u16,
+        offset: u16,
+        len: u8
+    },
+    struct NvReadResponse {
+        status: u8,
+        len: u8,
+        value: Buffer
+    },
+}
+
+command! {
+    52,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct NvWriteRequest {
+        sys_id: u8,
+        item_id: u16,
+        sub_id: u16,
+        offset: u16,
+        len: u8,
+        value: Buffer
+    },
+    struct NvWriteResponse {
+        status: u8
+    },
+}
+
+command! {
+    53,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct NvUpdateRequest {
+        sys_id: u8,
+        item_id: u16,
+        sub_id: u16,
+        len: u8,
+        value: Buffer
+    },
+    struct NvUpdateResponse {
+        status: u8
+    },
+}
+
+command! {
+    54,
+    Subsystem::Sys,
+    MessageType::SREQ,
+    struct NvCompactRequest {
+        threshold: u16
+    },
+    struct NvCompactResponse {
         status: u8
     },
 }
+
+command! {
+    128,
+    Subsystem::Sys,
+    MessageType::AREQ,
+    struct ResetIndRequest {
+        reason: u8,
+        transport_rev: u8,
+        product_id: u8,
+        major_rel: u8,
+        minor_rel: u8,
+        hw_rev: u8
+    },
+    struct ResetIndResponse {
+    },
+}
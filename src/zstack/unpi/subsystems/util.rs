@@ -34,10 +34,7 @@ impl<'a> DekuReader<'a, ()> for GetDeviceInfoResponse {
         let device_type = u8::from_reader_with_ctx(reader, BitSize::of::<u8>())?;
         let device_state = u8::from_reader_with_ctx(reader, BitSize::of::<u8>())?;
         let num_assoc_devices = u8::from_reader_with_ctx(reader, BitSize::of::<u8>())?;
-        let mut assoc_devices_list = List {
-            list: [0u16; 255],
-            len: num_assoc_devices as usize,
-        };
+        let mut assoc_devices_list = List::new();
         for _ in 0..(num_assoc_devices as usize) {
             let item = u16::from_reader_with_ctx(reader, BitSize::of::<u16>())?;
             assoc_devices_list.push(item);
@@ -130,4 +127,19 @@ mod tests {
         assert_eq!(device_response.short_addr, 0);
         assert_eq!(device_response.device_type, 7);
     }
+
+    #[test]
+    fn test_get_device_info_associated_devices() {
+        let data = [
+            0, 175, 60, 67, 1, 0, 75, 18, 0, 0, 0, 7, 9, 2, 0x34, 0x12, 0x78, 0x56,
+        ];
+        let mut cursor = no_std_io::Cursor::new(&data);
+        let (_, device_response) = GetDeviceInfoResponse::from_reader((&mut cursor, 0)).unwrap();
+        assert_eq!(device_response.num_assoc_devices, 2);
+        assert_eq!(device_response.assoc_devices_list.len, 2);
+        assert_eq!(
+            device_response.assoc_devices_list.list[..2],
+            [0x1234, 0x5678]
+        );
+    }
 }
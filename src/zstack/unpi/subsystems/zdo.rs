@@ -1,7 +1,47 @@
 use crate::{
     command,
-    zstack::unpi::{commands::CommandIeeeAddress, MessageType, Subsystem},
+    coordinator::{AddressMode, BindingDestination, BindingEntry},
+    zstack::unpi::{
+        commands::{CommandIeeeAddress, CountedList},
+        MessageType, Subsystem,
+    },
 };
+use deku::{DekuRead, DekuWrite};
+
+/// Entry of a neighbor table, as listed by ZDO_MGMT_LQI_RSP
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct NeighborLqi {
+    pub extended_pan_id: [u8; 8],
+    pub ieee_address: [u8; 8],
+    #[deku(endian = "little")]
+    pub network_address: u16,
+    /// Device type in bits 0-1, receiver on when idle in bits 2-3, relationship in bits 4-6
+    pub device_type_rx_on_when_idle_relationship: u8,
+    pub permit_joining: u8,
+    pub depth: u8,
+    pub lqi: u8,
+}
+
+impl NeighborLqi {
+    pub fn device_type(&self) -> u8 {
+        self.device_type_rx_on_when_idle_relationship & 0x03
+    }
+
+    pub fn relationship(&self) -> u8 {
+        (self.device_type_rx_on_when_idle_relationship >> 4) & 0x07
+    }
+}
+
+/// Entry of a routing table, as listed by ZDO_MGMT_RTG_RSP
+#[derive(Debug, PartialEq, Clone, DekuRead, DekuWrite)]
+pub struct RoutingEntry {
+    #[deku(endian = "little")]
+    pub destination_address: u16,
+    /// Route status in bits 0-2, the other bits are flags
+    pub status: u8,
+    #[deku(endian = "little")]
+    pub next_hop: u16,
+}
 
 command! {
     54,
@@ -87,3 +127,549 @@ command! {
 
     },
 }
+
+command! {
+    193,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct EndDeviceAnnounceIndRequest {
+        source_address: u16,
+        network_address: u16,
+        ieee_address: CommandIeeeAddress,
+        capabilities: u8
+    },
+    struct EndDeviceAnnounceIndResponse {
+
+    },
+}
+
+command! {
+    0,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct NetworkAddressRequest {
+        ieee_address: CommandIeeeAddress,
+        request_type: u8,
+        start_index: u8
+    },
+    struct NetworkAddressResponse {
+        status: u8
+    },
+}
+
+command! {
+    1,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct IeeeAddressRequest {
+        short_address: u16,
+        request_type: u8,
+        start_index: u8
+    },
+    struct IeeeAddressResponse {
+        status: u8
+    },
+}
+
+command! {
+    128,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct NetworkAddressRspRequest {
+        status: u8,
+        ieee_address: CommandIeeeAddress,
+        network_address: u16,
+        start_index: u8,
+        associated_devices: CountedList<u8, u16>
+    },
+    struct NetworkAddressRspResponse {
+
+    },
+}
+
+command! {
+    129,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct IeeeAddressRspRequest {
+        status: u8,
+        ieee_address: CommandIeeeAddress,
+        network_address: u16,
+        start_index: u8,
+        associated_devices: CountedList<u8, u16>
+    },
+    struct IeeeAddressRspResponse {
+
+    },
+}
+
+command! {
+    196,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct SourceRouteIndRequest {
+        destination_address: u16,
+        relays: CountedList<u8, u16>
+    },
+    struct SourceRouteIndResponse {
+
+    },
+}
+
+command! {
+    201,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct LeaveIndRequest {
+        source_address: u16,
+        extended_address: CommandIeeeAddress,
+        request: u8,
+        remove: u8,
+        rejoin: u8
+    },
+    struct LeaveIndResponse {
+
+    },
+}
+
+command! {
+    203,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct PermitJoinIndRequest {
+        duration: u8
+    },
+    struct PermitJoinIndResponse {
+
+    },
+}
+
+command! {
+    2,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct NodeDescriptorRequest {
+        destination_address: u16,
+        network_address_of_interest: u16
+    },
+    struct NodeDescriptorResponse {
+        status: u8
+    },
+}
+
+command! {
+    4,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct SimpleDescriptorRequest {
+        destination_address: u16,
+        network_address_of_interest: u16,
+        endpoint: u8
+    },
+    struct SimpleDescriptorResponse {
+        status: u8
+    },
+}
+
+command! {
+    5,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct ActiveEndpointsRequest {
+        destination_address: u16,
+        network_address_of_interest: u16
+    },
+    struct ActiveEndpointsResponse {
+        status: u8
+    },
+}
+
+command! {
+    130,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct NodeDescriptorRspRequest {
+        source_address: u16,
+        status: u8,
+        network_address_of_interest: u16,
+        logical_type_complex_user_descriptor: u8,
+        aps_flags_frequency_band: u8,
+        mac_capabilities: u8,
+        manufacturer_code: u16,
+        max_buffer_size: u8,
+        max_in_transfer_size: u16,
+        server_mask: u16,
+        max_out_transfer_size: u16,
+        descriptor_capabilities: u8
+    },
+    struct NodeDescriptorRspResponse {
+
+    },
+}
+
+command! {
+    132,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct SimpleDescriptorRspRequest {
+        source_address: u16,
+        status: u8,
+        network_address_of_interest: u16,
+        length: u8,
+        endpoint: u8,
+        profile_id: u16,
+        device_id: u16,
+        device_version: u8,
+        input_clusters: CountedList<u8, u16>,
+        output_clusters: CountedList<u8, u16>
+    },
+    struct SimpleDescriptorRspResponse {
+
+    },
+}
+
+command! {
+    133,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct ActiveEndpointsRspRequest {
+        source_address: u16,
+        status: u8,
+        network_address_of_interest: u16,
+        endpoints: CountedList<u8, u8>
+    },
+    struct ActiveEndpointsRspResponse {
+
+    },
+}
+
+command! {
+    49,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct ManagementLqiRequest {
+        destination_address: u16,
+        start_index: u8
+    },
+    struct ManagementLqiResponse {
+        status: u8
+    },
+}
+
+command! {
+    50,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct ManagementRoutingRequest {
+        destination_address: u16,
+        start_index: u8
+    },
+    struct ManagementRoutingResponse {
+        status: u8
+    },
+}
+
+command! {
+    177,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct ManagementLqiRspRequest {
+        source_address: u16,
+        status: u8,
+        neighbor_table_entries: u8,
+        start_index: u8,
+        neighbors: CountedList<u8, NeighborLqi>
+    },
+    struct ManagementLqiRspResponse {
+
+    },
+}
+
+command! {
+    178,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct ManagementRoutingRspRequest {
+        source_address: u16,
+        status: u8,
+        routing_table_entries: u8,
+        start_index: u8,
+        routes: CountedList<u8, RoutingEntry>
+    },
+    struct ManagementRoutingRspResponse {
+
+    },
+}
+
+command! {
+    33,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct BindRequest {
+        destination_address: u16,
+        source_address: CommandIeeeAddress,
+        source_endpoint: u8,
+        cluster_id: u16,
+        target_address_mode: u8,
+        target_address: [u8; 8],
+        target_endpoint: u8
+    },
+    struct BindResponse {
+        status: u8
+    },
+}
+
+command! {
+    34,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct UnbindRequest {
+        destination_address: u16,
+        source_address: CommandIeeeAddress,
+        source_endpoint: u8,
+        cluster_id: u16,
+        target_address_mode: u8,
+        target_address: [u8; 8],
+        target_endpoint: u8
+    },
+    struct UnbindResponse {
+        status: u8
+    },
+}
+
+command! {
+    51,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct ManagementBindRequest {
+        destination_address: u16,
+        start_index: u8
+    },
+    struct ManagementBindResponse {
+        status: u8
+    },
+}
+
+command! {
+    161,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct BindRspRequest {
+        source_address: u16,
+        status: u8
+    },
+    struct BindRspResponse {
+
+    },
+}
+
+command! {
+    162,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct UnbindRspRequest {
+        source_address: u16,
+        status: u8
+    },
+    struct UnbindRspResponse {
+
+    },
+}
+
+command! {
+    179,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct ManagementBindRspRequest {
+        source_address: u16,
+        status: u8,
+        binding_table_entries: u8,
+        start_index: u8,
+        bindings: CountedList<u8, BindingEntry>
+    },
+    struct ManagementBindRspResponse {
+
+    },
+}
+
+command! {
+    52,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct ManagementLeaveRequest {
+        destination_address: u16,
+        device_address: CommandIeeeAddress,
+        remove_children_rejoin: u8
+    },
+    struct ManagementLeaveResponse {
+        status: u8
+    },
+}
+
+command! {
+    180,
+    Subsystem::Zdo,
+    MessageType::AREQ,
+    struct ManagementLeaveRspRequest {
+        source_address: u16,
+        status: u8
+    },
+    struct ManagementLeaveRspResponse {
+
+    },
+}
+
+command! {
+    68,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct SecDeviceRemoveRequest {
+        ieee_address: CommandIeeeAddress
+    },
+    struct SecDeviceRemoveResponse {
+        status: u8
+    },
+}
+
+command! {
+    71,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct ExtRemoveGroupRequest {
+        endpoint: u8,
+        group_id: u16
+    },
+    struct ExtRemoveGroupResponse {
+        status: u8
+    },
+}
+
+command! {
+    73,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct ExtFindAllGroupsEndpointRequest {
+        endpoint: u8
+    },
+    struct ExtFindAllGroupsEndpointResponse {
+        groups: CountedList<u8, u16>
+    },
+}
+
+command! {
+    75,
+    Subsystem::Zdo,
+    MessageType::SREQ,
+    struct ExtAddGroupRequest {
+        endpoint: u8,
+        group_id: u16,
+        name: CountedList<u8, u8>
+    },
+    struct ExtAddGroupResponse {
+        status: u8
+    },
+}
+
+// Unlike in binding tables, a bind or unbind request always has room for an IEEE address and
+// an endpoint, a group only fills the first two bytes
+fn target(destination: &BindingDestination) -> (u8, [u8; 8], u8) {
+    match destination {
+        BindingDestination::Device {
+            ieee_address,
+            endpoint,
+        } => (AddressMode::Addr64bit as u8, *ieee_address, *endpoint),
+        BindingDestination::Group(group) => {
+            let mut address = [0u8; 8];
+            address[..2].copy_from_slice(&group.to_le_bytes());
+            (AddressMode::AddrGroup as u8, address, 0)
+        }
+    }
+}
+
+fn binding(
+    source_address: &CommandIeeeAddress,
+    source_endpoint: u8,
+    cluster_id: u16,
+    (mode, address, endpoint): (u8, [u8; 8], u8),
+) -> Option<BindingEntry> {
+    let destination = match AddressMode::from_mode(mode)? {
+        AddressMode::Addr64bit => BindingDestination::Device {
+            ieee_address: address,
+            endpoint,
+        },
+        AddressMode::AddrGroup => {
+            BindingDestination::Group(u16::from_le_bytes([address[0], address[1]]))
+        }
+        _ => return None,
+    };
+    Some(BindingEntry {
+        source_address: source_address.ieee_address,
+        source_endpoint,
+        cluster_id,
+        destination,
+    })
+}
+
+impl BindRequest {
+    /// Request to the device at `destination_address` to add `binding` to its table
+    pub fn new(destination_address: u16, binding: &BindingEntry) -> Self {
+        let (target_address_mode, target_address, target_endpoint) = target(&binding.destination);
+        Self {
+            destination_address,
+            source_address: CommandIeeeAddress {
+                ieee_address: binding.source_address,
+            },
+            source_endpoint: binding.source_endpoint,
+            cluster_id: binding.cluster_id,
+            target_address_mode,
+            target_address,
+            target_endpoint,
+        }
+    }
+
+    /// The binding asked for, none when the destination mode isn't one a binding can have
+    pub fn binding(&self) -> Option<BindingEntry> {
+        binding(
+            &self.source_address,
+            self.source_endpoint,
+            self.cluster_id,
+            (
+                self.target_address_mode,
+                self.target_address,
+                self.target_endpoint,
+            ),
+        )
+    }
+}
+
+impl UnbindRequest {
+    /// Request to the device at `destination_address` to remove `binding` from its table
+    pub fn new(destination_address: u16, binding: &BindingEntry) -> Self {
+        let (target_address_mode, target_address, target_endpoint) = target(&binding.destination);
+        Self {
+            destination_address,
+            source_address: CommandIeeeAddress {
+                ieee_address: binding.source_address,
+            },
+            source_endpoint: binding.source_endpoint,
+            cluster_id: binding.cluster_id,
+            target_address_mode,
+            target_address,
+            target_endpoint,
+        }
+    }
+
+    /// The binding to remove, none when the destination mode isn't one a binding can have
+    pub fn binding(&self) -> Option<BindingEntry> {
+        binding(
+            &self.source_address,
+            self.source_endpoint,
+            self.cluster_id,
+            (
+                self.target_address_mode,
+                self.target_address,
+                self.target_endpoint,
+            ),
+        )
+    }
+}